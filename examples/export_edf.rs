@@ -0,0 +1,80 @@
+/// Export recorded session directories to EDF+ for EEGLAB, MNE and EDFbrowser. The app does
+/// this itself as each session ends; use this for older sessions or to write the file elsewhere.
+///
+///     export_edf SESSION_DIR... [--output FILE]
+///
+/// Each session is written to session.edf in its directory unless --output is given.
+use meme_machine_database::edf::{export_session, EDF_FILE};
+use std::env;
+use std::path::PathBuf;
+use std::process;
+
+const USAGE: &str = "Usage:
+    export_edf SESSION_DIR... [--output FILE]
+
+SESSION_DIR  a session directory in the log directory, for example log/2020-05-02_10-00-00_session_17
+--output     where to write the EDF+ file of a single session, by default session.edf in its directory";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    dirs: Vec<PathBuf>,
+    output: Option<PathBuf>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            match arg.as_ref() {
+                "--output" => {
+                    let output = args.next().ok_or("--output needs a value")?;
+                    options.output = Some(PathBuf::from(output));
+                }
+                unknown if unknown.starts_with("--") => {
+                    return Err(format!("Unknown argument '{}'\n\n{}", unknown, USAGE))
+                }
+                dir => options.dirs.push(PathBuf::from(dir)),
+            }
+        }
+
+        match (options.dirs.len(), &options.output) {
+            (0, _) => Err(format!("Which session directory?\n\n{}", USAGE)),
+            (n, Some(_)) if n > 1 => Err("--output needs a single session directory".to_string()),
+            _ => Ok(options),
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    if args.iter().any(|arg| arg == "help" || arg == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let options = Options::parse(args)?;
+
+    for dir in &options.dirs {
+        let output = match &options.output {
+            Some(output) => output.clone(),
+            None => dir.join(EDF_FILE),
+        };
+        let export =
+            export_session(dir, &output).map_err(|e| format!("Can not export {:?}: {}", dir, e))?;
+        println!(
+            "{:?}: {} seconds, {} signals and {} annotations",
+            output, export.records, export.signals, export.annotations
+        );
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,418 @@
+/// Pretend to be a Muse headset streamed through MindMonitor. Synthetic OSC messages are sent to
+/// the meme machine so the whole pipeline can be exercised on a machine with no headset or phone.
+///
+/// Each scenario runs for a number of seconds, in the order given:
+///     muse_simulator relaxed_alpha_rise:60 blink_storm:10 poor_contact_tp10
+///     muse_simulator --target 192.168.1.20:34254 --script demo.txt
+///
+/// A script file has one "scenario seconds" pair per line. Lines starting with # are ignored.
+use nannou_osc::{Message, Packet, Type};
+use std::env;
+use std::f32::consts::PI;
+use std::fs;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_TARGET: &str = "127.0.0.1:34254"; // Where the meme machine listens for MindMonitor
+const DEFAULT_SCENARIO_SECONDS: f32 = 30.0;
+
+const EEG_RATE: f32 = 256.0; // Samples per second, as the Muse 2016 headset
+const MOTION_RATE: f32 = 52.0; // Accelerometer and gyro samples per second
+const ELEMENTS_RATE: f32 = 10.0; // Band powers, horseshoe and forehead contact per second
+const BATTERY_RATE: f32 = 0.1; // Battery reports per second
+const TICK: Duration = Duration::from_millis(4);
+
+const EEG_BASELINE: f32 = 800.0; // Raw EEG is reported 0.0 - 1682.815 microVolts, centered near here
+const HORSESHOE_GOOD: f32 = 1.0;
+const HORSESHOE_MEDIUM: f32 = 2.0;
+const HORSESHOE_BAD: f32 = 4.0;
+
+const AF7: usize = 1;
+const AF8: usize = 2;
+const TP10: usize = 3;
+
+const SCENARIO_NAMES: [&str; 6] = [
+    "baseline",
+    "relaxed_alpha_rise",
+    "frontal_asymmetry_swing",
+    "poor_contact_tp10",
+    "blink_storm",
+    "battery_draining",
+];
+
+/// One simulated condition of the person wearing the headset
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Scenario {
+    Baseline,
+    RelaxedAlphaRise,
+    FrontalAsymmetrySwing,
+    PoorContactTp10,
+    BlinkStorm,
+    BatteryDraining,
+}
+
+impl Scenario {
+    fn parse(name: &str) -> Option<Scenario> {
+        match name {
+            "baseline" => Some(Scenario::Baseline),
+            "relaxed_alpha_rise" => Some(Scenario::RelaxedAlphaRise),
+            "frontal_asymmetry_swing" => Some(Scenario::FrontalAsymmetrySwing),
+            "poor_contact_tp10" => Some(Scenario::PoorContactTp10),
+            "blink_storm" => Some(Scenario::BlinkStorm),
+            "battery_draining" => Some(Scenario::BatteryDraining),
+            _ => None,
+        }
+    }
+
+    /// Headset state at 'elapsed' seconds into a scenario which lasts 'duration' seconds
+    fn headset_state(&self, elapsed: f32, duration: f32) -> HeadsetState {
+        let progress = (elapsed / duration).max(0.0).min(1.0);
+        let mut state = HeadsetState::default();
+
+        match self {
+            Scenario::Baseline => {}
+            Scenario::RelaxedAlphaRise => {
+                // Eyes closing and the mind calming: alpha climbs, beta falls
+                for chan in 0..4 {
+                    state.alpha[chan] = state.alpha[chan] + 0.8 * progress;
+                    state.beta[chan] = state.beta[chan] - 0.3 * progress;
+                }
+            }
+            Scenario::FrontalAsymmetrySwing => {
+                // Left and right frontal alpha swap back and forth every 20 seconds
+                let swing = 0.5 * (2.0 * PI * elapsed / 20.0).sin();
+                state.alpha[AF7] = state.alpha[AF7] + swing;
+                state.alpha[AF8] = state.alpha[AF8] - swing;
+            }
+            Scenario::PoorContactTp10 => {
+                state.horseshoe[TP10] = match progress {
+                    p if p < 0.2 => HORSESHOE_MEDIUM,
+                    _ => HORSESHOE_BAD,
+                };
+                state.eeg_noise[TP10] = 400.0;
+            }
+            Scenario::BlinkStorm => {
+                state.blinks_per_second = 3.0;
+            }
+            Scenario::BatteryDraining => {
+                state.battery_percent = 100.0 * (1.0 - progress);
+            }
+        }
+
+        state
+    }
+}
+
+/// The values the simulated headset is currently reporting
+#[derive(Clone, Copy, Debug)]
+struct HeadsetState {
+    alpha: [f32; 4], // Absolute band powers, log10 Bels as MindMonitor reports them
+    beta: [f32; 4],
+    gamma: [f32; 4],
+    delta: [f32; 4],
+    theta: [f32; 4],
+    horseshoe: [f32; 4],
+    eeg_noise: [f32; 4], // microVolts of random noise on each raw channel
+    blinks_per_second: f32,
+    battery_percent: f32,
+}
+
+impl Default for HeadsetState {
+    fn default() -> Self {
+        Self {
+            alpha: [0.8, 0.6, 0.6, 0.8],
+            beta: [0.4, 0.3, 0.3, 0.4],
+            gamma: [0.1, 0.0, 0.0, 0.1],
+            delta: [1.0, 0.9, 0.9, 1.0],
+            theta: [0.7, 0.6, 0.6, 0.7],
+            horseshoe: [HORSESHOE_GOOD; 4],
+            eeg_noise: [10.0; 4],
+            blinks_per_second: 0.2,
+            battery_percent: 85.0,
+        }
+    }
+}
+
+/// Repeatable pseudo-random numbers, so scenarios are the same every run
+struct XorShift {
+    state: u32,
+}
+
+impl XorShift {
+    /// Next value in the range 0.0..1.0
+    fn next(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+
+        (self.state >> 8) as f32 / (1u32 << 24) as f32
+    }
+
+    /// Next value in the range -1.0..1.0
+    fn next_signed(&mut self) -> f32 {
+        self.next() * 2.0 - 1.0
+    }
+}
+
+/// Scheduling of one OSC stream at a fixed rate
+struct Stream {
+    interval: f32,
+    next_time: f32,
+}
+
+impl Stream {
+    fn new(rate: f32) -> Self {
+        Self {
+            interval: 1.0 / rate,
+            next_time: 0.0,
+        }
+    }
+
+    /// Number of messages due by 'now' seconds
+    fn due(&mut self, now: f32) -> usize {
+        let mut count = 0;
+        while self.next_time <= now {
+            self.next_time = self.next_time + self.interval;
+            count = count + 1;
+        }
+
+        count
+    }
+}
+
+fn message(addr: &str, args: Vec<Type>) -> Packet {
+    Packet::Message(Message {
+        addr: addr.to_string(),
+        args: Some(args),
+    })
+}
+
+fn floats(values: &[f32]) -> Vec<Type> {
+    values.iter().map(|v| Type::Float(*v)).collect()
+}
+
+/// Raw EEG for one sample, a mix of sine waves whose strength follows the band powers
+fn eeg_sample(state: &HeadsetState, t: f32, blinking: bool, random: &mut XorShift) -> [f32; 4] {
+    let mut sample = [EEG_BASELINE; 4];
+
+    for chan in 0..4 {
+        let wave =
+            |bels: f32, hz: f32| 10.0 * 10.0_f32.powf(bels).sqrt() * (2.0 * PI * hz * t).sin();
+        sample[chan] = sample[chan]
+            + wave(state.delta[chan], 2.0)
+            + wave(state.theta[chan], 6.0)
+            + wave(state.alpha[chan], 10.0)
+            + wave(state.beta[chan], 20.0)
+            + wave(state.gamma[chan], 38.0)
+            + state.eeg_noise[chan] * random.next_signed();
+    }
+
+    if blinking {
+        // Eye blinks show as a large slow deflection on the frontal electrodes
+        sample[AF7] = sample[AF7] + 300.0;
+        sample[AF8] = sample[AF8] + 300.0;
+    }
+
+    sample
+}
+
+struct Simulator {
+    sender: nannou_osc::Sender<nannou_osc::Connected>,
+    random: XorShift,
+    eeg: Stream,
+    motion: Stream,
+    elements: Stream,
+    battery: Stream,
+    blink_until: f32, // Time at which the current blink ends
+    sent_count: u64,
+}
+
+impl Simulator {
+    fn new(target: &str) -> Self {
+        let sender = nannou_osc::sender()
+            .expect("Can not create OSC sender")
+            .connect(target)
+            .expect("Can not connect OSC sender to target");
+
+        Self {
+            sender,
+            random: XorShift { state: 0x1234_5678 },
+            eeg: Stream::new(EEG_RATE),
+            motion: Stream::new(MOTION_RATE),
+            elements: Stream::new(ELEMENTS_RATE),
+            battery: Stream::new(BATTERY_RATE),
+            blink_until: 0.0,
+            sent_count: 0,
+        }
+    }
+
+    fn send(&mut self, packet: Packet) {
+        match self.sender.send(packet) {
+            Ok(_) => self.sent_count = self.sent_count + 1,
+            Err(e) => eprintln!("Can not send OSC packet: {}", e),
+        }
+    }
+
+    fn run_scenario(&mut self, scenario: Scenario, duration: f32, session_start: Instant) {
+        println!("{:?} for {} seconds", scenario, duration);
+        let scenario_start = session_start.elapsed().as_secs_f32();
+
+        loop {
+            let now = session_start.elapsed().as_secs_f32();
+            let elapsed = now - scenario_start;
+            if elapsed >= duration {
+                break;
+            }
+            let state = scenario.headset_state(elapsed, duration);
+
+            for _ in 0..self.eeg.due(now) {
+                let t = self.eeg.next_time - self.eeg.interval;
+                let blinking = t < self.blink_until;
+                let sample = eeg_sample(&state, t, blinking, &mut self.random);
+                self.send(message("/muse/eeg", floats(&sample)));
+            }
+
+            for _ in 0..self.motion.due(now) {
+                let jitter = 0.01 * self.random.next_signed();
+                self.send(message("/muse/acc", floats(&[jitter, 0.0, 1.0])));
+                self.send(message("/muse/gyro", floats(&[jitter, -jitter, 0.0])));
+            }
+
+            for _ in 0..self.elements.due(now) {
+                self.send_elements(&state, now);
+            }
+
+            for _ in 0..self.battery.due(now) {
+                let charge = (state.battery_percent * 100.0) as i32;
+                let millivolts = 3300 + (9 * state.battery_percent as i32);
+                self.send(message(
+                    "/muse/batt",
+                    vec![
+                        Type::Int(charge),
+                        Type::Int(millivolts),
+                        Type::Int(millivolts),
+                        Type::Int(32),
+                    ],
+                ));
+            }
+
+            thread::sleep(TICK);
+        }
+    }
+
+    /// Band powers, contact quality and detected events, sent ELEMENTS_RATE times per second
+    fn send_elements(&mut self, state: &HeadsetState, now: f32) {
+        let wobble = |random: &mut XorShift, bels: &[f32; 4]| {
+            let mut values = *bels;
+            for value in values.iter_mut() {
+                *value = *value + 0.05 * random.next_signed();
+            }
+            floats(&values)
+        };
+
+        let alpha = wobble(&mut self.random, &state.alpha);
+        let beta = wobble(&mut self.random, &state.beta);
+        let gamma = wobble(&mut self.random, &state.gamma);
+        let delta = wobble(&mut self.random, &state.delta);
+        let theta = wobble(&mut self.random, &state.theta);
+        self.send(message("/muse/elements/alpha_absolute", alpha));
+        self.send(message("/muse/elements/beta_absolute", beta));
+        self.send(message("/muse/elements/gamma_absolute", gamma));
+        self.send(message("/muse/elements/delta_absolute", delta));
+        self.send(message("/muse/elements/theta_absolute", theta));
+        self.send(message(
+            "/muse/elements/horseshoe",
+            floats(&state.horseshoe),
+        ));
+
+        let touching = state.horseshoe.iter().all(|h| *h < HORSESHOE_BAD);
+        self.send(message(
+            "/muse/elements/touching_forehead",
+            vec![Type::Int(touching as i32)],
+        ));
+
+        if self.random.next() < state.blinks_per_second / ELEMENTS_RATE {
+            self.blink_until = now + 0.2;
+            self.send(message("/muse/elements/blink", vec![Type::Int(1)]));
+        }
+
+        let clench = self.random.next() < 0.02;
+        if clench {
+            self.send(message("/muse/elements/jaw_clench", vec![Type::Int(1)]));
+        }
+    }
+}
+
+/// Read "scenario[:seconds]" as given on the command line
+fn parse_step(step: &str) -> (Scenario, f32) {
+    let mut parts = step.splitn(2, |c| c == ':' || c == ' ');
+    let name = parts.next().unwrap_or("").trim();
+    let scenario = Scenario::parse(name).unwrap_or_else(|| {
+        panic!(
+            "Unknown scenario '{}'. Choose from: {}",
+            name,
+            SCENARIO_NAMES.join(", ")
+        )
+    });
+    let duration = match parts.next() {
+        Some(seconds) => seconds
+            .trim()
+            .parse::<f32>()
+            .expect("Scenario duration should be a number of seconds"),
+        None => DEFAULT_SCENARIO_SECONDS,
+    };
+
+    (scenario, duration)
+}
+
+fn read_script(filename: &str) -> Vec<(Scenario, f32)> {
+    fs::read_to_string(filename)
+        .expect("Can not read scenario script")
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_step)
+        .collect()
+}
+
+fn main() {
+    let mut target = DEFAULT_TARGET.to_string();
+    let mut steps: Vec<(Scenario, f32)> = Vec::new();
+    let mut repeat = false;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--target" => target = args.next().expect("--target needs host:port"),
+            "--script" => steps.extend(read_script(
+                &args.next().expect("--script needs a filename"),
+            )),
+            "--repeat" => repeat = true,
+            "--list" => {
+                println!("{}", SCENARIO_NAMES.join("\n"));
+                return;
+            }
+            step => steps.push(parse_step(step)),
+        }
+    }
+
+    if steps.is_empty() {
+        steps.push((Scenario::Baseline, DEFAULT_SCENARIO_SECONDS));
+    }
+
+    println!("Sending simulated Muse OSC messages to {}", target);
+    let mut simulator = Simulator::new(&target);
+    let session_start = Instant::now();
+
+    loop {
+        for (scenario, duration) in &steps {
+            simulator.run_scenario(*scenario, *duration, session_start);
+        }
+
+        if !repeat {
+            break;
+        }
+    }
+
+    println!("Sent {} OSC messages", simulator.sent_count);
+}
@@ -0,0 +1,127 @@
+/// Connect to the live data outlet of a running meme machine, started with MEME_OUTLET set, and
+/// show what arrives: the streams on offer, each marker as it happens and once a second the
+/// samples received on each stream with the latest normalized valence and arousal.
+///
+///     outlet_client [ADDRESS] [--raw]
+///
+/// ADDRESS defaults to 127.0.0.1:8765. --raw prints every JSON message instead.
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::process;
+use std::time::{Duration, Instant};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:8765";
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(1);
+
+const USAGE: &str = "Usage:
+    outlet_client [ADDRESS] [--raw]
+
+ADDRESS  where the meme machine serves live data, the MEME_OUTLET it was started with, by default 127.0.0.1:8765
+--raw    print every JSON message as it arrives";
+
+struct Client {
+    raw: bool,
+    counts: BTreeMap<String, usize>, // Samples on each stream since the last summary
+    valence_arousal: Option<Value>,
+    last_summary: Instant,
+}
+
+impl Client {
+    fn receive(&mut self, message: &Value) {
+        match message["type"].as_str() {
+            Some("info") => {
+                println!(
+                    "Rig {}, session {}, outlet version {}",
+                    message["rig"], message["session_id"], message["version"]
+                );
+                for stream in message["streams"].as_array().into_iter().flatten() {
+                    println!(
+                        "  {} ({}): {} {}",
+                        stream["name"], stream["kind"], stream["channels"], stream["nominal_rate"]
+                    );
+                }
+            }
+            Some("marker") => println!(
+                "{} {} {}",
+                message["session_s"], message["event"], message["detail"]
+            ),
+            Some("sample") => {
+                let stream = message["stream"].as_str().unwrap_or("?").to_string();
+                if stream == "valence_arousal" {
+                    self.valence_arousal = Some(message["values"].clone());
+                }
+                let count = self.counts.entry(stream).or_insert(0);
+                *count = *count + 1;
+            }
+            _ => println!("Unknown message: {}", message),
+        }
+
+        if self.last_summary.elapsed() >= SUMMARY_INTERVAL {
+            let counts: Vec<String> = self
+                .counts
+                .iter()
+                .map(|(stream, count)| format!("{} {}", stream, count))
+                .collect();
+            println!(
+                "  samples: {}, valence and arousal: {}",
+                counts.join(", "),
+                self.valence_arousal.take().unwrap_or(Value::Null)
+            );
+            self.counts.clear();
+            self.last_summary = Instant::now();
+        }
+    }
+}
+
+impl ws::Handler for Client {
+    fn on_message(&mut self, message: ws::Message) -> ws::Result<()> {
+        let text = message.as_text()?;
+
+        if self.raw {
+            println!("{}", text);
+        } else {
+            match serde_json::from_str::<Value>(text) {
+                Ok(message) => self.receive(&message),
+                Err(e) => println!("Can not read message '{}': {}", text, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn on_error(&mut self, e: ws::Error) {
+        eprintln!("{}", e);
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    if args.iter().any(|arg| arg == "help" || arg == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let raw = args.iter().any(|arg| arg == "--raw");
+    let address = match args.iter().find(|arg| !arg.starts_with("--")) {
+        Some(address) => address.as_str(),
+        None => DEFAULT_ADDRESS,
+    };
+    let url = format!("ws://{}", address);
+
+    println!("Connecting to {}", url);
+    ws::connect(url, |_out| Client {
+        raw,
+        counts: BTreeMap::new(),
+        valence_arousal: None,
+        last_summary: Instant::now(),
+    })
+    .map_err(|e| format!("Can not connect to {}: {}", address, e))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,292 @@
+/// Manage the rigs in the database. A rig is one installation of the meme machine: a headset,
+/// screen and input devices at a location. Sessions record the rig they ran on.
+///
+///     rig list [--all] [--json]
+///     rig show RIG [--json]
+///     rig create TITLE [--body TEXT] [--headset SERIAL] [--screen TEXT] [--inputs TEXT] [--location TEXT] [--json]
+///     rig update RIG [--title TITLE] [--body TEXT] [--headset SERIAL] [--screen TEXT] [--inputs TEXT] [--location TEXT] [--json]
+///     rig activate RIG
+///     rig deactivate RIG
+///     rig delete RIG [--yes]
+///
+/// RIG is the exact title of a rig, or its id.
+use meme_machine_database::models::{NewRig, Rig, RigChanges};
+use meme_machine_database::*;
+use std::env;
+use std::io::stdin;
+use std::process;
+
+const USAGE: &str = "Usage:
+    rig list [--all] [--json]
+    rig show RIG [--json]
+    rig create TITLE [--body TEXT] [--headset SERIAL] [--screen TEXT] [--inputs TEXT] [--location TEXT] [--json]
+    rig update RIG [--title TITLE] [--body TEXT] [--headset SERIAL] [--screen TEXT] [--inputs TEXT] [--location TEXT] [--json]
+    rig activate RIG
+    rig deactivate RIG
+    rig delete RIG [--yes]
+
+RIG is the exact title of a rig, or its id.";
+
+/// Everything given on the command line after the subcommand
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    rig: Option<String>, // Title or id
+    all: bool,
+    json: bool,
+    yes: bool,
+    title: Option<String>,
+    body: Option<String>,
+    headset_serial: Option<String>,
+    screen: Option<String>,
+    input_devices: Option<String>,
+    location: Option<String>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .cloned()
+                    .ok_or(format!("{} needs a value", name))
+            };
+            match arg.as_ref() {
+                "--all" => options.all = true,
+                "--json" => options.json = true,
+                "--yes" => options.yes = true,
+                "--title" => options.title = Some(value(arg)?),
+                "--body" => options.body = Some(value(arg)?),
+                "--headset" => options.headset_serial = Some(value(arg)?),
+                "--screen" => options.screen = Some(value(arg)?),
+                "--inputs" => options.input_devices = Some(value(arg)?),
+                "--location" => options.location = Some(value(arg)?),
+                flag if flag.starts_with("--") => return Err(format!("Unknown option {}", flag)),
+                rig if options.rig.is_none() => options.rig = Some(rig.to_string()),
+                extra => return Err(format!("Unexpected argument '{}'", extra)),
+            }
+        }
+
+        Ok(options)
+    }
+
+    fn rig(&self) -> Result<&str, String> {
+        self.rig
+            .as_ref()
+            .map(|rig| rig.as_str())
+            .ok_or("Which rig? Give its title or id".to_string())
+    }
+
+    fn changes(&self) -> RigChanges<'_> {
+        RigChanges {
+            title: self.title.as_ref().map(|s| s.as_str()),
+            body: self.body.as_ref().map(|s| s.as_str()),
+            active: None,
+            headset_serial: self.headset_serial.as_ref().map(|s| s.as_str()),
+            screen: self.screen.as_ref().map(|s| s.as_str()),
+            input_devices: self.input_devices.as_ref().map(|s| s.as_str()),
+            location: self.location.as_ref().map(|s| s.as_str()),
+        }
+    }
+
+    fn has_changes(&self) -> bool {
+        self.title.is_some()
+            || self.body.is_some()
+            || self.headset_serial.is_some()
+            || self.screen.is_some()
+            || self.input_devices.is_some()
+            || self.location.is_some()
+    }
+}
+
+/// A rig by exact title. A number which is not the title of any rig is taken as an id.
+fn find_rig(connection: &DbConnection, rig: &str) -> Result<Rig, String> {
+    let mut matching = find_rigs_by_title(connection, rig).map_err(|e| e.to_string())?;
+
+    match matching.len() {
+        0 => match rig.parse::<i32>() {
+            Ok(rig_id) => find_rig_by_id(connection, rig_id)
+                .map_err(|e| e.to_string())?
+                .ok_or(format!("There is no rig with id {}", rig_id)),
+            Err(_) => Err(format!("There is no rig titled '{}'", rig)),
+        },
+        1 => Ok(matching.remove(0)),
+        n => Err(format!(
+            "{} rigs are titled '{}', use the id of one: {}",
+            n,
+            rig,
+            matching
+                .iter()
+                .map(|rig| rig.id.to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )),
+    }
+}
+
+fn print_rig(rig: &Rig) {
+    let optional = |value: &Option<String>| match value {
+        Some(value) => value.clone(),
+        None => "-".to_string(),
+    };
+
+    println!(
+        "---- {} ({}) ----",
+        rig.title,
+        if rig.active { "active" } else { "inactive" }
+    );
+    println!("id:       {}", rig.id);
+    println!("headset:  {}", optional(&rig.headset_serial));
+    println!("screen:   {}", optional(&rig.screen));
+    println!("inputs:   {}", optional(&rig.input_devices));
+    println!("location: {}", optional(&rig.location));
+    if !rig.body.is_empty() {
+        println!("\n{}", rig.body);
+    }
+    println!("-----------\n");
+}
+
+fn print_json<T: serde::Serialize>(value: &T) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+    println!("{}", json);
+
+    Ok(())
+}
+
+fn output(rig: &Rig, options: &Options) -> Result<(), String> {
+    if options.json {
+        print_json(rig)
+    } else {
+        print_rig(rig);
+        Ok(())
+    }
+}
+
+fn list(connection: &DbConnection, options: &Options) -> Result<(), String> {
+    let rigs = list_rigs(connection, options.all).map_err(|e| e.to_string())?;
+
+    if options.json {
+        return print_json(&rigs);
+    }
+    println!("\nDisplaying {} rigs\n============\n", rigs.len());
+    for rig in &rigs {
+        print_rig(rig);
+    }
+
+    Ok(())
+}
+
+fn create(connection: &DbConnection, options: &Options) -> Result<(), String> {
+    let title = options.rig()?;
+    if !find_rigs_by_title(connection, title)
+        .map_err(|e| e.to_string())?
+        .is_empty()
+    {
+        return Err(format!("A rig titled '{}' already exists", title));
+    }
+
+    let changes = options.changes();
+    let new_rig = NewRig {
+        title,
+        body: changes.body.unwrap_or(""),
+        headset_serial: changes.headset_serial,
+        screen: changes.screen,
+        input_devices: changes.input_devices,
+        location: changes.location,
+    };
+    let rig = create_rig(connection, &new_rig).map_err(|e| e.to_string())?;
+    if !options.json {
+        println!("\nCreated rig '{}' with id {}", rig.title, rig.id);
+    }
+
+    output(&rig, options)
+}
+
+fn update(connection: &DbConnection, options: &Options) -> Result<(), String> {
+    let rig = find_rig(connection, options.rig()?)?;
+    if !options.has_changes() {
+        return Err("Nothing to update, give at least one of --title --body --headset --screen --inputs --location".to_string());
+    }
+
+    let rig = update_rig(connection, rig.id, &options.changes()).map_err(|e| e.to_string())?;
+
+    output(&rig, options)
+}
+
+fn set_active(connection: &DbConnection, options: &Options, active: bool) -> Result<(), String> {
+    let rig = find_rig(connection, options.rig()?)?;
+    let changes = RigChanges {
+        active: Some(active),
+        ..RigChanges::default()
+    };
+
+    let rig = update_rig(connection, rig.id, &changes).map_err(|e| e.to_string())?;
+
+    output(&rig, options)
+}
+
+/// Delete exactly one rig, after the title is typed again unless --yes
+fn delete(connection: &DbConnection, options: &Options) -> Result<(), String> {
+    let rig = find_rig(connection, options.rig()?)?;
+
+    if !options.yes {
+        println!(
+            "\nDelete rig '{}' with id {}? Type its title to confirm:",
+            rig.title, rig.id
+        );
+        let mut confirmation = String::new();
+        stdin()
+            .read_line(&mut confirmation)
+            .map_err(|e| e.to_string())?;
+        if confirmation.trim() != rig.title {
+            return Err("Not confirmed, nothing was deleted".to_string());
+        }
+    }
+
+    match delete_rig(connection, rig.id) {
+        Ok(1) => {
+            println!("\nDeleted rig '{}'", rig.title);
+            Ok(())
+        }
+        Ok(_) => Err(format!("Rig '{}' was not deleted", rig.title)),
+        Err(e) => Err(format!(
+            "Can not delete rig '{}', sessions may have run on it. Deactivate it instead: {}",
+            rig.title, e
+        )),
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err(USAGE.to_string()),
+    };
+    if command == "help" || command == "--help" {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let options = Options::parse(rest)?;
+    let connection = try_establish_connection()?;
+
+    match command {
+        "list" => list(&connection, &options),
+        "show" => output(&find_rig(&connection, options.rig()?)?, &options),
+        "create" => create(&connection, &options),
+        "update" => update(&connection, &options),
+        "activate" => set_active(&connection, &options, true),
+        "deactivate" => set_active(&connection, &options, false),
+        "delete" => delete(&connection, &options),
+        unknown => Err(format!("Unknown command '{}'\n\n{}", unknown, USAGE)),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,114 @@
+/// Copy sessions recorded on a laptop to the central PostgreSQL database, once it can be reached.
+/// Each session is copied with its rig, participant, stimuli, events, responses and samples, and
+/// is then marked as synced in the local database so it is never copied twice.
+///
+///     sync [--from DATABASE] [--to POSTGRES_URL] [--all] [--dry-run]
+///
+/// --from defaults to DATABASE_URL, or the local SQLite file. --to defaults to CENTRAL_DATABASE_URL.
+use meme_machine_database::sync::{sessions_to_sync, sync_session};
+use meme_machine_database::*;
+use std::env;
+use std::process;
+
+const ENV_CENTRAL_DATABASE_URL: &str = "CENTRAL_DATABASE_URL"; // The central PostgreSQL database, in the environment or .env
+
+const USAGE: &str = "Usage:
+    sync [--from DATABASE] [--to POSTGRES_URL] [--all] [--dry-run]
+
+--from     the local database, by default DATABASE_URL or the local SQLite file
+--to       the central database, by default CENTRAL_DATABASE_URL
+--all      also copy sessions which have not ended, they may still be running
+--dry-run  list the sessions which would be copied";
+
+#[derive(Debug, Default, PartialEq)]
+struct Options {
+    from: Option<String>,
+    to: Option<String>,
+    all: bool,
+    dry_run: bool,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .cloned()
+                    .ok_or(format!("{} needs a value", name))
+            };
+            match arg.as_ref() {
+                "--from" => options.from = Some(value(arg)?),
+                "--to" => options.to = Some(value(arg)?),
+                "--all" => options.all = true,
+                "--dry-run" => options.dry_run = true,
+                unknown => return Err(format!("Unknown argument '{}'\n\n{}", unknown, USAGE)),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    if args.iter().any(|arg| arg == "help" || arg == "--help") {
+        println!("{}", USAGE);
+        return Ok(());
+    }
+    let options = Options::parse(args)?;
+    let from = match options.from {
+        Some(from) => from,
+        None => {
+            database_url().ok_or("DATABASE_URL is none, give the local database with --from")?
+        }
+    };
+    let local = establish(&from)?;
+    let sessions = sessions_to_sync(&local, options.all).map_err(|e| e.to_string())?;
+
+    println!("\n{} sessions to copy from {}", sessions.len(), from);
+    if options.dry_run || sessions.is_empty() {
+        for session in &sessions {
+            println!(
+                "  session {} started {}, protocol {}",
+                session.id, session.started_at, session.protocol_version
+            );
+        }
+        return Ok(());
+    }
+
+    let to = match options.to {
+        Some(to) => to,
+        None => env::var(ENV_CENTRAL_DATABASE_URL).map_err(|_| {
+            format!(
+                "Which central database? Give --to or set {}",
+                ENV_CENTRAL_DATABASE_URL
+            )
+        })?,
+    };
+    let central = establish(&to)?;
+    if central.is_sqlite() {
+        return Err(format!("{} is not a PostgreSQL database", to));
+    }
+
+    for session in &sessions {
+        let synced = sync_session(&local, &central, session)
+            .map_err(|e| format!("Can not copy session {}: {}", session.id, e))?;
+        println!(
+            "  session {} is central session {}, {} rows",
+            synced.local_id, synced.central_id, synced.rows
+        );
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    if let Err(e) = run(&args) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,7 @@
+-- Rigs may already exist in databases set up by the earlier meme-machine-database crate
+CREATE TABLE IF NOT EXISTS rigs (
+  id SERIAL PRIMARY KEY,
+  title VARCHAR NOT NULL,
+  body TEXT NOT NULL,
+  active BOOLEAN NOT NULL DEFAULT 't'
+);
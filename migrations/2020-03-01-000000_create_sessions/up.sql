@@ -0,0 +1,50 @@
+CREATE TABLE participants (
+  id SERIAL PRIMARY KEY,
+  code VARCHAR NOT NULL UNIQUE,
+  created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE sessions (
+  id SERIAL PRIMARY KEY,
+  rig_id INTEGER REFERENCES rigs (id),
+  participant_id INTEGER REFERENCES participants (id),
+  started_at TIMESTAMP NOT NULL,
+  ended_at TIMESTAMP,
+  protocol_version VARCHAR NOT NULL,
+  valence_mean REAL,
+  valence_deviation REAL,
+  arousal_mean REAL,
+  arousal_deviation REAL
+);
+
+CREATE TABLE stimuli (
+  id SERIAL PRIMARY KEY,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  sequence VARCHAR NOT NULL,
+  position INTEGER NOT NULL,
+  image VARCHAR NOT NULL,
+  onset TIMESTAMP NOT NULL
+);
+
+CREATE TABLE events (
+  id SERIAL PRIMARY KEY,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  time TIMESTAMP NOT NULL,
+  event VARCHAR NOT NULL,
+  detail TEXT NOT NULL
+);
+
+CREATE TABLE responses (
+  id SERIAL PRIMARY KEY,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  stimulus_id INTEGER REFERENCES stimuli (id),
+  familiar BOOLEAN,
+  liked BOOLEAN,
+  rating SMALLINT,
+  reaction_time_ms INTEGER,
+  input VARCHAR
+);
+
+CREATE INDEX events_session_id ON events (session_id);
+CREATE INDEX stimuli_session_id ON stimuli (session_id);
+CREATE INDEX responses_session_id ON responses (session_id);
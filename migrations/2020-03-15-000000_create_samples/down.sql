@@ -0,0 +1,3 @@
+DROP TABLE valence_arousal;
+DROP TABLE band_powers;
+DROP TABLE eeg_samples;
@@ -0,0 +1,36 @@
+CREATE TABLE eeg_samples (
+  id BIGSERIAL PRIMARY KEY,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  time TIMESTAMP NOT NULL,
+  tp9 REAL NOT NULL,
+  af7 REAL NOT NULL,
+  af8 REAL NOT NULL,
+  tp10 REAL NOT NULL,
+  aux_right REAL,
+  aux_left REAL
+);
+
+CREATE TABLE band_powers (
+  id BIGSERIAL PRIMARY KEY,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  time TIMESTAMP NOT NULL,
+  band VARCHAR NOT NULL,
+  tp9 REAL NOT NULL,
+  af7 REAL NOT NULL,
+  af8 REAL NOT NULL,
+  tp10 REAL NOT NULL
+);
+
+CREATE TABLE valence_arousal (
+  id BIGSERIAL PRIMARY KEY,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  time TIMESTAMP NOT NULL,
+  valence REAL,
+  arousal REAL,
+  normalized_valence REAL,
+  normalized_arousal REAL
+);
+
+CREATE INDEX eeg_samples_session_id_time ON eeg_samples (session_id, time);
+CREATE INDEX band_powers_session_id_time ON band_powers (session_id, time);
+CREATE INDEX valence_arousal_session_id_time ON valence_arousal (session_id, time);
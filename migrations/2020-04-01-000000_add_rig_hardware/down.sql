@@ -0,0 +1,5 @@
+ALTER TABLE rigs
+  DROP COLUMN location,
+  DROP COLUMN input_devices,
+  DROP COLUMN screen,
+  DROP COLUMN headset_serial;
@@ -0,0 +1,5 @@
+ALTER TABLE rigs
+  ADD COLUMN headset_serial VARCHAR,
+  ADD COLUMN screen VARCHAR,
+  ADD COLUMN input_devices VARCHAR,
+  ADD COLUMN location VARCHAR;
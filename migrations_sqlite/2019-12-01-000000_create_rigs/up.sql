@@ -0,0 +1,7 @@
+-- The same tables as ../migrations, in the SQLite dialect
+CREATE TABLE IF NOT EXISTS rigs (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  title VARCHAR NOT NULL,
+  body TEXT NOT NULL,
+  active BOOLEAN NOT NULL DEFAULT 1
+);
@@ -0,0 +1,5 @@
+DROP TABLE responses;
+DROP TABLE events;
+DROP TABLE stimuli;
+DROP TABLE sessions;
+DROP TABLE participants;
@@ -0,0 +1,37 @@
+-- SQLite stores NaN as NULL, so the electrode channels allow NULL here for dropped samples
+CREATE TABLE eeg_samples (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  time TIMESTAMP NOT NULL,
+  tp9 REAL,
+  af7 REAL,
+  af8 REAL,
+  tp10 REAL,
+  aux_right REAL,
+  aux_left REAL
+);
+
+CREATE TABLE band_powers (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  time TIMESTAMP NOT NULL,
+  band VARCHAR NOT NULL,
+  tp9 REAL,
+  af7 REAL,
+  af8 REAL,
+  tp10 REAL
+);
+
+CREATE TABLE valence_arousal (
+  id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+  session_id INTEGER NOT NULL REFERENCES sessions (id),
+  time TIMESTAMP NOT NULL,
+  valence REAL,
+  arousal REAL,
+  normalized_valence REAL,
+  normalized_arousal REAL
+);
+
+CREATE INDEX eeg_samples_session_id_time ON eeg_samples (session_id, time);
+CREATE INDEX band_powers_session_id_time ON band_powers (session_id, time);
+CREATE INDEX valence_arousal_session_id_time ON valence_arousal (session_id, time);
@@ -0,0 +1,5 @@
+-- DROP COLUMN needs SQLite 3.35 or later
+ALTER TABLE rigs DROP COLUMN location;
+ALTER TABLE rigs DROP COLUMN input_devices;
+ALTER TABLE rigs DROP COLUMN screen;
+ALTER TABLE rigs DROP COLUMN headset_serial;
@@ -0,0 +1,4 @@
+ALTER TABLE rigs ADD COLUMN headset_serial VARCHAR;
+ALTER TABLE rigs ADD COLUMN screen VARCHAR;
+ALTER TABLE rigs ADD COLUMN input_devices VARCHAR;
+ALTER TABLE rigs ADD COLUMN location VARCHAR;
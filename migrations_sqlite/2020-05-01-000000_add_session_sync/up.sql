@@ -0,0 +1,2 @@
+-- Id of a session in the central database once it has been copied there from a local database
+ALTER TABLE sessions ADD COLUMN synced_session_id INTEGER;
@@ -0,0 +1,2 @@
+-- Percentage of raw EEG samples which fell in a period rejected for artifacts
+ALTER TABLE sessions ADD COLUMN rejected_percent REAL;
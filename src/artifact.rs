@@ -0,0 +1,288 @@
+/// Detect periods when the EEG is contaminated by blinks, jaw clenches, head movement or poor
+/// electrode contact, so they can be left out of valence and arousal
+use crate::muse_model::{MuseMessage, MuseMessageType, N_EEG_CHANNELS};
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+const ARTIFACT_HOLD: Duration = Duration::from_secs(1); // Band values are computed over a window, so stay bad this long after an artifact
+const AMPLITUDE_WINDOW: usize = 128; // Raw EEG samples, half a second, checked for peak-to-peak amplitude
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArtifactKind {
+    Blink,
+    JawClench,
+    Motion,
+    PoorContact,
+    Amplitude,
+}
+
+impl fmt::Display for ArtifactKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ArtifactKind::Blink => "blink",
+            ArtifactKind::JawClench => "jaw clench",
+            ArtifactKind::Motion => "motion",
+            ArtifactKind::PoorContact => "poor contact",
+            ArtifactKind::Amplitude => "amplitude",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Limits beyond which the EEG is not trusted
+#[derive(Clone, Debug)]
+pub struct ArtifactThresholds {
+    pub max_peak_to_peak: f32, // microVolts of EEG in AMPLITUDE_WINDOW
+    pub max_rotation: f32,     // Gyro degrees/sec on any axis
+    pub max_acceleration: f32, // g away from the 1g of gravity
+    pub max_horseshoe: f32,    // Contact quality, 1 = good, 2 = medium, 4 = bad
+}
+
+impl Default for ArtifactThresholds {
+    fn default() -> Self {
+        Self {
+            max_peak_to_peak: 150.0,
+            max_rotation: 10.0,
+            max_acceleration: 0.2,
+            max_horseshoe: 2.0,
+        }
+    }
+}
+
+/// Marks time as bad for a while after each artifact, and counts how much raw EEG was rejected
+pub struct ArtifactDetector {
+    pub thresholds: ArtifactThresholds,
+    bad_until: Option<Duration>,
+    eeg_window: [VecDeque<f32>; N_EEG_CHANNELS],
+    pub eeg_samples: u64,
+    pub rejected_samples: u64,
+    pub last_artifact: Option<ArtifactKind>,
+}
+
+impl ArtifactDetector {
+    pub fn new(thresholds: ArtifactThresholds) -> Self {
+        Self {
+            thresholds,
+            bad_until: None,
+            eeg_window: Default::default(),
+            eeg_samples: 0,
+            rejected_samples: 0,
+            last_artifact: None,
+        }
+    }
+
+    /// Check a message for artifacts. Returns the kind of artifact if this starts a new bad period.
+    pub fn observe(&mut self, muse_message: &MuseMessage) -> Option<ArtifactKind> {
+        let time = muse_message.time;
+        let thresholds = &self.thresholds;
+
+        let artifact = match muse_message.muse_message_type {
+            MuseMessageType::Blink { blink: true } => Some(ArtifactKind::Blink),
+            MuseMessageType::JawClench { clench: true } => Some(ArtifactKind::JawClench),
+            MuseMessageType::TouchingForehead { touch: false } => Some(ArtifactKind::PoorContact),
+            MuseMessageType::Horseshoe { a, b, c, d } => {
+                if [a, b, c, d].iter().any(|h| *h > thresholds.max_horseshoe) {
+                    Some(ArtifactKind::PoorContact)
+                } else {
+                    None
+                }
+            }
+            MuseMessageType::Gyro { x, y, z } => {
+                if [x, y, z].iter().any(|r| r.abs() > thresholds.max_rotation) {
+                    Some(ArtifactKind::Motion)
+                } else {
+                    None
+                }
+            }
+            MuseMessageType::Accelerometer { x, y, z } => {
+                let g = (x * x + y * y + z * z).sqrt();
+                if (g - 1.0).abs() > thresholds.max_acceleration {
+                    Some(ArtifactKind::Motion)
+                } else {
+                    None
+                }
+            }
+            MuseMessageType::Eeg { channels, .. } => self.check_amplitude(&channels),
+            _ => None,
+        };
+
+        let new_artifact = match artifact {
+            Some(kind) => {
+                let already_bad = !self.is_clean(time);
+                // Each stream's times are spread back separately, so an artifact may arrive with
+                // an earlier time than one already seen. It never shortens the bad period.
+                let end = time + ARTIFACT_HOLD;
+                self.bad_until = Some(self.bad_until.map_or(end, |bad_until| bad_until.max(end)));
+                self.last_artifact = Some(kind);
+                if already_bad {
+                    None
+                } else {
+                    Some(kind)
+                }
+            }
+            None => None,
+        };
+
+        if let MuseMessageType::Eeg { .. } = muse_message.muse_message_type {
+            self.eeg_samples = self.eeg_samples + 1;
+            if !self.is_clean(time) {
+                self.rejected_samples = self.rejected_samples + 1;
+            }
+        }
+
+        new_artifact
+    }
+
+    /// Peak-to-peak over the most recent half second of each electrode. Dropped samples are skipped.
+    fn check_amplitude(&mut self, channels: &[f32; N_EEG_CHANNELS]) -> Option<ArtifactKind> {
+        let mut artifact = None;
+
+        for chan in 0..N_EEG_CHANNELS {
+            if channels[chan].is_nan() {
+                continue;
+            }
+            let window = &mut self.eeg_window[chan];
+            window.push_back(channels[chan]);
+            if window.len() > AMPLITUDE_WINDOW {
+                window.pop_front();
+            }
+            let min = window.iter().cloned().fold(std::f32::INFINITY, f32::min);
+            let max = window
+                .iter()
+                .cloned()
+                .fold(std::f32::NEG_INFINITY, f32::max);
+            if max - min > self.thresholds.max_peak_to_peak {
+                artifact = Some(ArtifactKind::Amplitude);
+            }
+        }
+
+        artifact
+    }
+
+    /// True if no artifact has been seen recently
+    pub fn is_clean(&self, time: Duration) -> bool {
+        match self.bad_until {
+            Some(bad_until) => time >= bad_until,
+            None => true,
+        }
+    }
+
+    /// Percentage of raw EEG samples this session which fell in a bad period
+    pub fn rejected_percent(&self) -> f32 {
+        if self.eeg_samples == 0 {
+            0.0
+        } else {
+            100.0 * self.rejected_samples as f32 / self.eeg_samples as f32
+        }
+    }
+}
+
+impl fmt::Display for ArtifactDetector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Artifacts: {:.1}% rejected", self.rejected_percent())?;
+        if let Some(kind) = self.last_artifact {
+            write!(f, ", last {}", kind)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn message(millis: u64, muse_message_type: MuseMessageType) -> MuseMessage {
+        let ip_address: SocketAddr = "127.0.0.1:34254".parse().unwrap();
+
+        MuseMessage {
+            time: Duration::from_millis(millis),
+            ip_address,
+            muse_message_type,
+        }
+    }
+
+    fn eeg(millis: u64, value: f32) -> MuseMessage {
+        message(
+            millis,
+            MuseMessageType::Eeg {
+                channels: [value; N_EEG_CHANNELS],
+                aux: [None, None],
+            },
+        )
+    }
+
+    #[test]
+    fn test_blink_holds_bad_period() {
+        let mut detector = ArtifactDetector::new(ArtifactThresholds::default());
+        let first = detector.observe(&message(1000, MuseMessageType::Blink { blink: true }));
+        let second = detector.observe(&message(1200, MuseMessageType::Blink { blink: true }));
+
+        assert_eq!(Some(ArtifactKind::Blink), first);
+        assert_eq!(None, second);
+        assert!(!detector.is_clean(Duration::from_millis(2100)));
+        assert!(detector.is_clean(Duration::from_millis(2200)));
+    }
+
+    #[test]
+    fn test_earlier_artifact_keeps_bad_period() {
+        let mut detector = ArtifactDetector::new(ArtifactThresholds::default());
+        detector.observe(&message(1000, MuseMessageType::Blink { blink: true }));
+        let late = detector.observe(&message(600, MuseMessageType::JawClench { clench: true }));
+
+        assert_eq!(None, late);
+        assert!(!detector.is_clean(Duration::from_millis(1900)));
+        assert!(detector.is_clean(Duration::from_millis(2000)));
+    }
+
+    #[test]
+    fn test_amplitude_and_rejected_percent() {
+        let mut detector = ArtifactDetector::new(ArtifactThresholds::default());
+
+        for i in 0..10 {
+            detector.observe(&eeg(i, 800.0));
+        }
+        assert_eq!(0.0, detector.rejected_percent());
+
+        let artifact = detector.observe(&eeg(10, 1000.0));
+        assert_eq!(Some(ArtifactKind::Amplitude), artifact);
+        assert!((detector.rejected_percent() - 100.0 / 11.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_motion_and_contact() {
+        let mut detector = ArtifactDetector::new(ArtifactThresholds::default());
+        let still = detector.observe(&message(
+            0,
+            MuseMessageType::Accelerometer {
+                x: 0.0,
+                y: 0.0,
+                z: 1.0,
+            },
+        ));
+        let turning = detector.observe(&message(
+            10,
+            MuseMessageType::Gyro {
+                x: 40.0,
+                y: 0.0,
+                z: 0.0,
+            },
+        ));
+        let contact = detector.observe(&message(
+            5000,
+            MuseMessageType::Horseshoe {
+                a: 1.0,
+                b: 1.0,
+                c: 1.0,
+                d: 4.0,
+            },
+        ));
+
+        assert_eq!(None, still);
+        assert_eq!(Some(ArtifactKind::Motion), turning);
+        assert_eq!(Some(ArtifactKind::PoorContact), contact);
+    }
+}
@@ -0,0 +1,376 @@
+/// Compute EEG frequency band power from the raw EEG stream, as an alternative to the
+/// /muse/elements/*_absolute values calculated by MindMonitor
+use crate::muse_model::N_EEG_CHANNELS;
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+pub const DELTA: usize = 0; // Band array index
+pub const THETA: usize = 1;
+pub const ALPHA: usize = 2;
+pub const BETA: usize = 3;
+pub const GAMMA: usize = 4;
+pub const N_BANDS: usize = 5;
+const MAX_HELD_UPDATES: usize = 10; // An electrode with dropped samples keeps its last value this many updates, about a second, then is NaN
+pub const BAND_EDGES: [(f32, f32); N_BANDS] = [
+    (1.0, 4.0),   // Delta Hz
+    (4.0, 8.0),   // Theta
+    (7.5, 13.0),  // Alpha
+    (13.0, 30.0), // Beta
+    (30.0, 44.0), // Gamma
+];
+
+/// Where the values in MuseModel.alpha, .beta, .. come from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BandSource {
+    MindMonitor, // /muse/elements/*_absolute messages
+    Computed,    // Welch power spectrum of the raw /muse/eeg messages
+}
+
+impl BandSource {
+    /// "mindmonitor" or "computed"
+    pub fn parse(name: &str) -> Option<BandSource> {
+        match name.trim().to_lowercase().as_ref() {
+            "mindmonitor" => Some(BandSource::MindMonitor),
+            "computed" => Some(BandSource::Computed),
+            _ => None,
+        }
+    }
+}
+
+/// Welch's method: the most recent window of samples is split into overlapping segments, each
+/// Hann windowed and transformed, and the resulting power spectra averaged
+#[derive(Clone, Debug)]
+pub struct BandPowerSettings {
+    pub sample_rate: f32,       // Samples per second
+    pub window_length: usize,   // Samples of history analysed each update
+    pub segment_length: usize,  // Samples per FFT, must be a power of two
+    pub segment_overlap: usize, // Samples shared by neighbouring segments
+    pub update_interval: usize, // New samples between updates
+}
+
+impl Default for BandPowerSettings {
+    fn default() -> Self {
+        Self {
+            sample_rate: 256.0,
+            window_length: 512, // 2 seconds
+            segment_length: 256,
+            segment_overlap: 128,
+            update_interval: 26, // About 10x/sec, the same as MindMonitor
+        }
+    }
+}
+
+impl BandPowerSettings {
+    pub fn check(&self) -> Result<(), String> {
+        if !self.segment_length.is_power_of_two() {
+            return Err(format!(
+                "Segment length {} is not a power of two",
+                self.segment_length
+            ));
+        }
+        if self.segment_length > self.window_length {
+            return Err(format!(
+                "Segment length {} is longer than the window length {}",
+                self.segment_length, self.window_length
+            ));
+        }
+        if self.segment_overlap >= self.segment_length {
+            return Err(format!(
+                "Segment overlap {} must be less than the segment length {}",
+                self.segment_overlap, self.segment_length
+            ));
+        }
+        if self.update_interval == 0 || self.sample_rate <= 0.0 {
+            return Err("Update interval and sample rate must be positive".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+/// Power in each band for each electrode, indexed [band][channel]
+#[derive(Clone, Debug)]
+pub struct BandPowers {
+    pub absolute: [[f32; N_EEG_CHANNELS]; N_BANDS], // log10 of µV², the same scale as MindMonitor
+    pub relative: [[f32; N_EEG_CHANNELS]; N_BANDS], // Fraction of the total power of all bands
+}
+
+/// Collects raw EEG samples and periodically computes the band power of each electrode
+pub struct BandPowerCalculator {
+    settings: BandPowerSettings,
+    history: [VecDeque<f32>; N_EEG_CHANNELS],
+    samples_since_update: usize,
+    previous: Option<BandPowers>,
+    held_updates: [usize; N_EEG_CHANNELS], // Updates since each electrode last had a clean window
+}
+
+impl BandPowerCalculator {
+    pub fn new(settings: BandPowerSettings) -> Self {
+        settings.check().expect("Band power settings are not valid");
+
+        Self {
+            settings,
+            history: Default::default(),
+            samples_since_update: 0,
+            previous: None,
+            held_updates: [0; N_EEG_CHANNELS],
+        }
+    }
+
+    /// Add one raw sample per electrode. Returns new band powers every update interval, once a full
+    /// window has arrived. An electrode with dropped (NaN) samples in the window keeps its previous
+    /// value for MAX_HELD_UPDATES, then is NaN until it has a clean window. It is NaN from the
+    /// start if it has never had one.
+    pub fn push(&mut self, channels: &[f32; N_EEG_CHANNELS]) -> Option<BandPowers> {
+        for chan in 0..N_EEG_CHANNELS {
+            self.history[chan].push_back(channels[chan]);
+            if self.history[chan].len() > self.settings.window_length {
+                self.history[chan].pop_front();
+            }
+        }
+        self.samples_since_update = self.samples_since_update + 1;
+
+        if self.history[0].len() < self.settings.window_length
+            || self.samples_since_update < self.settings.update_interval
+        {
+            return None;
+        }
+        self.samples_since_update = 0;
+
+        let mut band_powers = self.previous.clone().unwrap_or(BandPowers {
+            absolute: [[std::f32::NAN; N_EEG_CHANNELS]; N_BANDS],
+            relative: [[std::f32::NAN; N_EEG_CHANNELS]; N_BANDS],
+        });
+
+        for chan in 0..N_EEG_CHANNELS {
+            let signal: Vec<f32> = self.history[chan].iter().cloned().collect();
+            if signal.iter().any(|v| v.is_nan()) {
+                self.held_updates[chan] = self.held_updates[chan] + 1;
+                if self.held_updates[chan] > MAX_HELD_UPDATES {
+                    for band in 0..N_BANDS {
+                        band_powers.absolute[band][chan] = std::f32::NAN;
+                        band_powers.relative[band][chan] = std::f32::NAN;
+                    }
+                }
+                continue;
+            }
+            self.held_updates[chan] = 0;
+            let psd = welch_psd(&signal, &self.settings);
+            let resolution = self.settings.sample_rate / self.settings.segment_length as f32;
+            let mut powers = [0.0; N_BANDS];
+            for band in 0..N_BANDS {
+                let (low, high) = BAND_EDGES[band];
+                powers[band] = band_power(&psd, resolution, low, high);
+            }
+            let total: f32 = powers.iter().sum();
+
+            for band in 0..N_BANDS {
+                band_powers.absolute[band][chan] = powers[band].log10();
+                band_powers.relative[band][chan] = if total > 0.0 {
+                    powers[band] / total
+                } else {
+                    0.0
+                };
+            }
+        }
+        self.previous = Some(band_powers.clone());
+
+        Some(band_powers)
+    }
+}
+
+/// One-sided power spectral density in µV²/Hz, averaged over Hann windowed segments
+pub fn welch_psd(signal: &[f32], settings: &BandPowerSettings) -> Vec<f32> {
+    let n = settings.segment_length;
+    let step = n - settings.segment_overlap;
+    let window: Vec<f32> = (0..n)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos())
+        .collect();
+    let window_power: f32 = window.iter().map(|w| w * w).sum();
+    let mut psd = vec![0.0; n / 2 + 1];
+    let mut segments = 0;
+    let mut start = 0;
+
+    while start + n <= signal.len() {
+        let segment = &signal[start..start + n];
+        let mean = segment.iter().sum::<f32>() / n as f32;
+        let mut re: Vec<f32> = segment
+            .iter()
+            .zip(&window)
+            .map(|(v, w)| (v - mean) * w)
+            .collect();
+        let mut im = vec![0.0; n];
+        fft(&mut re, &mut im);
+
+        for k in 0..psd.len() {
+            let mut p = (re[k] * re[k] + im[k] * im[k]) / (settings.sample_rate * window_power);
+            if k != 0 && k != n / 2 {
+                p = p * 2.0; // Fold in the negative frequencies
+            }
+            psd[k] = psd[k] + p;
+        }
+        segments = segments + 1;
+        start = start + step;
+    }
+
+    if segments > 0 {
+        for p in psd.iter_mut() {
+            *p = *p / segments as f32;
+        }
+    }
+
+    psd
+}
+
+/// Sum of the spectrum from low up to but not including high Hz
+pub fn band_power(psd: &[f32], resolution: f32, low: f32, high: f32) -> f32 {
+    psd.iter()
+        .enumerate()
+        .filter(|(k, _)| {
+            let frequency = *k as f32 * resolution;
+            frequency >= low && frequency < high
+        })
+        .map(|(_, p)| p * resolution)
+        .sum()
+}
+
+/// In-place iterative radix-2 FFT. The length must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    // Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j = j ^ bit;
+            bit = bit >> 1;
+        }
+        j = j | bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        for start in (0..n).step_by(len) {
+            for k in 0..len / 2 {
+                let (w_im, w_re) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + len / 2;
+                let t_re = re[b] * w_re - im[b] * w_im;
+                let t_im = re[b] * w_im + im[b] * w_re;
+                re[b] = re[a] - t_re;
+                im[b] = im[a] - t_im;
+                re[a] = re[a] + t_re;
+                im[a] = im[a] + t_im;
+            }
+        }
+        len = len << 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f32, amplitude: f32, samples: usize) -> Vec<f32> {
+        (0..samples)
+            .map(|i| amplitude * (2.0 * PI * frequency * i as f32 / 256.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_fft_peak() {
+        let mut re = sine(32.0, 1.0, 256);
+        let mut im = vec![0.0; 256];
+        fft(&mut re, &mut im);
+        let magnitudes: Vec<f32> = re.iter().zip(&im).map(|(r, i)| r.hypot(*i)).collect();
+
+        assert!((magnitudes[32] - 128.0).abs() < 0.01);
+        assert!(magnitudes[31] < 0.01);
+    }
+
+    #[test]
+    fn test_sine_power() {
+        // A sine of amplitude A has power A²/2
+        let settings = BandPowerSettings::default();
+        let psd = welch_psd(&sine(10.0, 4.0, 512), &settings);
+        let power = band_power(&psd, 1.0, 7.5, 13.0);
+
+        assert!((power - 8.0).abs() < 0.5, "Power was {}", power);
+    }
+
+    #[test]
+    fn test_alpha_dominates() {
+        let mut calculator = BandPowerCalculator::new(BandPowerSettings::default());
+        let alpha = sine(10.0, 10.0, 600);
+        let beta = sine(20.0, 1.0, 600);
+        let mut band_powers = None;
+
+        for i in 0..600 {
+            let v = alpha[i] + beta[i];
+            if let Some(powers) = calculator.push(&[v, v, v, v]) {
+                band_powers = Some(powers);
+            }
+        }
+        let band_powers = band_powers.expect("Expected band powers after a full window");
+
+        assert!(band_powers.relative[ALPHA][0] > 0.9);
+        assert!(band_powers.absolute[ALPHA][0] > band_powers.absolute[BETA][0]);
+        let total: f32 = (0..N_BANDS).map(|b| band_powers.relative[b][2]).sum();
+        assert!((total - 1.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn test_dropped_samples_are_nan_without_previous() {
+        let mut calculator = BandPowerCalculator::new(BandPowerSettings::default());
+
+        for v in sine(10.0, 10.0, 512) {
+            calculator.push(&[v, std::f32::NAN, v, v]);
+        }
+        let band_powers = calculator.previous.expect("Expected band powers");
+
+        assert!(band_powers.absolute[ALPHA][1].is_nan());
+        assert!(band_powers.relative[ALPHA][1].is_nan());
+        assert!(band_powers.relative[ALPHA][0] > 0.9);
+    }
+
+    #[test]
+    fn test_dropout_holds_then_is_nan() {
+        let settings = BandPowerSettings::default();
+        let update_interval = settings.update_interval;
+        let mut calculator = BandPowerCalculator::new(settings);
+        let alpha = sine(10.0, 10.0, 512 + update_interval * (MAX_HELD_UPDATES + 1));
+        let mut updates = Vec::new();
+
+        for (i, v) in alpha.iter().enumerate() {
+            let tp9 = if i < 512 { *v } else { std::f32::NAN };
+            if let Some(band_powers) = calculator.push(&[tp9, *v, *v, *v]) {
+                updates.push(band_powers.absolute[ALPHA][0]);
+            }
+        }
+
+        assert_eq!(MAX_HELD_UPDATES + 2, updates.len());
+        assert!(updates[..=MAX_HELD_UPDATES].iter().all(|v| v.is_finite()));
+        assert!(updates[MAX_HELD_UPDATES + 1].is_nan());
+    }
+
+    #[test]
+    fn test_settings_check() {
+        let mut settings = BandPowerSettings::default();
+        assert_eq!(Ok(()), settings.check());
+
+        settings.segment_length = 200;
+        assert!(settings.check().is_err());
+    }
+
+    #[test]
+    fn test_band_source() {
+        assert_eq!(Some(BandSource::Computed), BandSource::parse("Computed"));
+        assert_eq!(None, BandSource::parse("muse"));
+    }
+}
@@ -0,0 +1,249 @@
+/// Pace a breathing exercise: pause, breathe in, pause, breathe out, with the mandala opening and
+/// closing in time. The pace can slow down while measured arousal stays high.
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const PACE_STEP: f32 = 1.1; // Each cycle with high or low arousal stretches or shrinks the cycle by this factor
+const MAX_PACE: f32 = 1.5; // The cycle is never stretched longer than this multiple of the configured timing
+const HIGH_AROUSAL: f32 = 0.5; // Normalized arousal above this slows breathing
+const LOW_AROUSAL: f32 = -0.5; // Normalized arousal below this returns towards the configured pace
+
+/// Length of each part of a breath cycle, seconds. P1 pause, X1 in, P2 pause, X2 out.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct BreathSettings {
+    pub pause_before_in: f32,   // P1
+    pub breathe_in: f32,        // X1
+    pub pause_before_out: f32,  // P2
+    pub breathe_out: f32,       // X2
+    pub adapt_to_arousal: bool, // Slow the cycle while arousal is high
+}
+
+impl Default for BreathSettings {
+    /// Six breaths per minute
+    fn default() -> Self {
+        Self {
+            pause_before_in: 1.0,
+            breathe_in: 4.0,
+            pause_before_out: 1.0,
+            breathe_out: 4.0,
+            adapt_to_arousal: false,
+        }
+    }
+}
+
+impl BreathSettings {
+    pub fn check(&self) -> Result<(), String> {
+        let steps = [
+            self.pause_before_in,
+            self.breathe_in,
+            self.pause_before_out,
+            self.breathe_out,
+        ];
+        if steps
+            .iter()
+            .any(|seconds| !seconds.is_finite() || *seconds < 0.0)
+        {
+            return Err("Breath cycle timings must be zero or more seconds".to_string());
+        }
+        if self.breathe_in <= 0.0 || self.breathe_out <= 0.0 {
+            return Err("Breathing in and out must take some time".to_string());
+        }
+
+        Ok(())
+    }
+
+    fn seconds(&self, step: BreathStep) -> f32 {
+        match step {
+            BreathStep::PauseBeforeIn => self.pause_before_in,
+            BreathStep::BreatheIn => self.breathe_in,
+            BreathStep::PauseBeforeOut => self.pause_before_out,
+            BreathStep::BreatheOut => self.breathe_out,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BreathStep {
+    PauseBeforeIn,
+    BreatheIn,
+    PauseBeforeOut,
+    BreatheOut,
+}
+
+impl BreathStep {
+    fn next(self) -> BreathStep {
+        match self {
+            BreathStep::PauseBeforeIn => BreathStep::BreatheIn,
+            BreathStep::BreatheIn => BreathStep::PauseBeforeOut,
+            BreathStep::PauseBeforeOut => BreathStep::BreatheOut,
+            BreathStep::BreatheOut => BreathStep::PauseBeforeIn,
+        }
+    }
+
+    /// Text shown to the participant
+    pub fn prompt(self) -> &'static str {
+        match self {
+            BreathStep::PauseBeforeIn | BreathStep::PauseBeforeOut => "Hold",
+            BreathStep::BreatheIn => "Breathe in",
+            BreathStep::BreatheOut => "Breathe out",
+        }
+    }
+}
+
+impl fmt::Display for BreathStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BreathStep::PauseBeforeIn => "pause before in",
+            BreathStep::BreatheIn => "in",
+            BreathStep::PauseBeforeOut => "pause before out",
+            BreathStep::BreatheOut => "out",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// A step of the breath cycle which has just started
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BreathBoundary {
+    pub step: BreathStep,
+    pub cycle: u32,   // Counting from 1
+    pub seconds: f32, // How long this step will last, after any change of pace
+}
+
+/// Steps through the breath cycle
+pub struct BreathingPacer {
+    pub settings: BreathSettings,
+    pub pace: f32, // Multiple of the configured timing, 1.0 unless adapting to arousal
+    step: BreathStep,
+    step_end: f32,
+    cycle: u32,
+    started: bool,
+}
+
+impl BreathingPacer {
+    pub fn new(settings: BreathSettings) -> Self {
+        settings
+            .check()
+            .expect("Breath cycle settings are not valid");
+
+        Self {
+            settings,
+            pace: 1.0,
+            step: BreathStep::BreatheOut,
+            step_end: 0.0,
+            cycle: 0,
+            started: false,
+        }
+    }
+
+    /// The step boundaries crossed by 'now', in order. The first call starts the first cycle.
+    /// Arousal is the most recent normalized value, if any.
+    pub fn update(&mut self, now: f32, arousal: Option<f32>) -> Vec<BreathBoundary> {
+        let mut boundaries = Vec::new();
+
+        if !self.started {
+            self.started = true;
+            self.step_end = now;
+        }
+
+        while now >= self.step_end {
+            self.step = self.step.next();
+            if self.step == BreathStep::PauseBeforeIn {
+                self.cycle = self.cycle + 1;
+                self.adapt(arousal);
+            }
+            let seconds = self.settings.seconds(self.step) * self.pace;
+            self.step_end = self.step_end + seconds;
+            boundaries.push(BreathBoundary {
+                step: self.step,
+                cycle: self.cycle,
+                seconds,
+            });
+        }
+
+        boundaries
+    }
+
+    /// Stretch the cycle while arousal is high, and relax back to the configured pace when it is low
+    fn adapt(&mut self, arousal: Option<f32>) {
+        if !self.settings.adapt_to_arousal {
+            return;
+        }
+
+        match arousal {
+            Some(arousal) if arousal > HIGH_AROUSAL => {
+                self.pace = (self.pace * PACE_STEP).min(MAX_PACE)
+            }
+            Some(arousal) if arousal < LOW_AROUSAL => self.pace = (self.pace / PACE_STEP).max(1.0),
+            _ => (),
+        }
+    }
+
+    pub fn step(&self) -> BreathStep {
+        self.step
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_boundaries() {
+        let mut pacer = BreathingPacer::new(BreathSettings::default());
+
+        let first = pacer.update(10.0, None);
+        assert_eq!(1, first.len());
+        assert_eq!(BreathStep::PauseBeforeIn, first[0].step);
+        assert_eq!(1, first[0].cycle);
+
+        assert!(pacer.update(10.5, None).is_empty());
+        let steps: Vec<BreathStep> = pacer
+            .update(20.0, None)
+            .iter()
+            .map(|boundary| boundary.step)
+            .collect();
+        assert_eq!(
+            vec![
+                BreathStep::BreatheIn,
+                BreathStep::PauseBeforeOut,
+                BreathStep::BreatheOut,
+                BreathStep::PauseBeforeIn
+            ],
+            steps
+        );
+        assert_eq!(BreathStep::PauseBeforeIn, pacer.step());
+    }
+
+    #[test]
+    fn test_high_arousal_slows_pace() {
+        let settings = BreathSettings {
+            adapt_to_arousal: true,
+            ..BreathSettings::default()
+        };
+        let mut pacer = BreathingPacer::new(settings);
+        pacer.update(0.0, Some(2.0));
+        assert!((pacer.pace - PACE_STEP).abs() < 0.001);
+
+        for cycle in 1..20 {
+            pacer.update(cycle as f32 * 15.0, Some(2.0));
+        }
+        assert_eq!(MAX_PACE, pacer.pace);
+
+        pacer.update(1000.0, Some(-2.0));
+        assert!(pacer.pace < MAX_PACE);
+    }
+
+    #[test]
+    fn test_settings_check() {
+        let settings = BreathSettings {
+            breathe_in: 0.0,
+            ..BreathSettings::default()
+        };
+
+        assert!(settings.check().is_err());
+        assert_eq!(Ok(()), BreathSettings::default().check());
+    }
+}
@@ -1,40 +1,572 @@
+// diesel and the bundled SQLite are only built for native targets, see Cargo.toml. On wasm this
+// crate is empty, and the app's database code is gated the same way.
+#![cfg(not(target_arch = "wasm32"))]
+
 #[macro_use]
 extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
 extern crate dotenv;
 
 pub mod models;
 pub mod schema;
 
-use self::models::{NewRig, Rig};
+use self::models::{
+    NewBandPower, NewEegSample, NewEvent, NewParticipant, NewResponse, NewRig, NewSession,
+    NewStimulus, NewValenceArousal, Participant, Rig, RigChanges, SampleBatch, Session, SessionEnd,
+};
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use diesel::pg::PgConnection;
 use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
 use dotenv::dotenv;
+use serde::{Deserialize, Serialize};
 use std::env;
+use std::time::SystemTime;
+
+pub const DEFAULT_SQLITE_FILE: &str = "meme_machine.sqlite"; // Used if DATABASE_URL is not set, so a laptop needs no database server
+pub const NO_DATABASE: &str = "none"; // DATABASE_URL value to run with CSV logs only
+const SQLITE_BUSY_TIMEOUT_MS: u32 = 5000; // Wait for another process writing the same SQLite file, such as sync
+
+// Tables for a new or older SQLite file are created when it is opened. PostgreSQL is updated with
+// 'diesel migration run'.
+embed_migrations!("migrations_sqlite");
+
+no_arg_sql_function!(
+    last_insert_rowid,
+    diesel::sql_types::Integer,
+    "Id of the row just inserted on this SQLite connection"
+);
+
+/// A connection to the central PostgreSQL database or to a local SQLite file. The same tables and
+/// queries are used with both.
+pub enum DbConnection {
+    Postgres(PgConnection),
+    Sqlite(SqliteConnection),
+}
+
+/// Run the same diesel query on whichever kind of connection this is
+macro_rules! with_connection {
+    ($conn:expr, |$c:ident| $body:expr) => {
+        match $conn {
+            DbConnection::Postgres($c) => $body,
+            DbConnection::Sqlite($c) => $body,
+        }
+    };
+}
+
+/// Insert one row and return it. SQLite has no RETURNING, so the row is read back by its id.
+macro_rules! insert_returning {
+    ($conn:expr, $table:expr, $values:expr) => {
+        match $conn {
+            DbConnection::Postgres(c) => diesel::insert_into($table).values($values).get_result(c),
+            DbConnection::Sqlite(c) => c.transaction(|| {
+                diesel::insert_into($table).values($values).execute(c)?;
+                let row_id = diesel::select(last_insert_rowid).get_result::<i32>(c)?;
+                $table.find(row_id).first(c)
+            }),
+        }
+    };
+}
 
-pub fn establish_connection() -> PgConnection {
+pub mod edf;
+pub mod sync;
+
+impl DbConnection {
+    /// Commit everything done inside 'f', or nothing if it fails
+    pub fn transaction<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+        E: From<diesel::result::Error>,
+    {
+        with_connection!(self, |c| c.transaction(f))
+    }
+
+    pub fn is_sqlite(&self) -> bool {
+        match self {
+            DbConnection::Postgres(_) => false,
+            DbConnection::Sqlite(_) => true,
+        }
+    }
+}
+
+pub fn establish_connection() -> DbConnection {
+    let database_url = database_url().expect("DATABASE_URL is none");
+
+    establish(&database_url).expect("Can not connect to the database")
+}
+
+/// DATABASE_URL from the environment or .env, or the local SQLite file if it is not set.
+/// None if DATABASE_URL is "none", to run without a database.
+pub fn database_url() -> Option<String> {
     dotenv().ok();
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url).expect(&format!("Error connecting to {}", database_url))
+    match env::var("DATABASE_URL") {
+        Ok(url) if url.trim() == NO_DATABASE => None,
+        Ok(url) => Some(url),
+        Err(_) => Some(format!("sqlite://{}", DEFAULT_SQLITE_FILE)),
+    }
+}
+
+/// Connect if a database is configured and reachable. The app can run without one.
+pub fn try_establish_connection() -> Result<DbConnection, String> {
+    let database_url = database_url().ok_or("DATABASE_URL is none")?;
+
+    establish(&database_url)
+}
+
+/// 'postgres://..' or 'postgresql://..' is a PostgreSQL server. Anything else is a SQLite file,
+/// with or without 'sqlite://' in front. A new SQLite file is created with all tables.
+pub fn establish(database_url: &str) -> Result<DbConnection, String> {
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        return PgConnection::establish(database_url)
+            .map(DbConnection::Postgres)
+            .map_err(|e| format!("Error connecting to {}: {}", database_url, e));
+    }
+
+    let path = sqlite_path(database_url);
+    let connection = SqliteConnection::establish(path)
+        .map_err(|e| format!("Error opening SQLite database {}: {}", path, e))?;
+    connection
+        .execute(&format!(
+            "PRAGMA busy_timeout = {};",
+            SQLITE_BUSY_TIMEOUT_MS
+        ))
+        .map_err(|e| format!("Error configuring SQLite database {}: {}", path, e))?;
+    embedded_migrations::run(&connection)
+        .map_err(|e| format!("Error creating tables in SQLite database {}: {}", path, e))?;
+
+    Ok(DbConnection::Sqlite(connection))
+}
+
+fn sqlite_path(database_url: &str) -> &str {
+    if database_url.starts_with("sqlite://") {
+        &database_url["sqlite://".len()..]
+    } else if database_url.starts_with("sqlite:") {
+        &database_url["sqlite:".len()..]
+    } else {
+        database_url
+    }
+}
+
+/// Timestamps are stored as UTC without a time zone
+pub fn naive_utc(time: SystemTime) -> NaiveDateTime {
+    chrono::DateTime::<Utc>::from(time).naive_utc()
+}
+
+pub fn system_time(time: NaiveDateTime) -> SystemTime {
+    Utc.from_utc_datetime(&time).into()
+}
+
+/// Valence and arousal statistics from the calibration phase and the share of EEG rejected for
+/// artifacts, stored when a session ends
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CalibrationStats {
+    pub valence_mean: Option<f32>,
+    pub valence_deviation: Option<f32>,
+    pub arousal_mean: Option<f32>,
+    pub arousal_deviation: Option<f32>,
+    #[serde(default)]
+    pub rejected_percent: Option<f32>, // Not in spool files written before it was stored
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    #[test]
+    fn test_sqlite_path() {
+        assert_eq!(
+            "meme_machine.sqlite",
+            sqlite_path("sqlite://meme_machine.sqlite")
+        );
+        assert_eq!(
+            "/data/event.sqlite",
+            sqlite_path("sqlite:///data/event.sqlite")
+        );
+        assert_eq!(":memory:", sqlite_path("sqlite::memory:"));
+        assert_eq!("event.sqlite", sqlite_path("event.sqlite"));
+    }
+
+    #[test]
+    fn test_time_round_trip() {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_micros(1_583_020_800_123_456);
+
+        assert_eq!(time, system_time(naive_utc(time)));
+        assert_eq!(
+            "2020-03-01 00:00:00.123456",
+            naive_utc(time).format("%F %T%.f").to_string()
+        );
+    }
+
+    #[test]
+    fn test_sqlite_session() {
+        let connection = establish("sqlite::memory:").unwrap();
+        assert!(connection.is_sqlite());
+        let rig = create_rig(
+            &connection,
+            &NewRig {
+                title: "Lab rig 1",
+                body: "",
+                ..NewRig::default()
+            },
+        )
+        .unwrap();
+        let participant = find_or_create_participant(&connection, "P017").unwrap();
+        assert_eq!(
+            participant.id,
+            find_or_create_participant(&connection, "P017").unwrap().id
+        );
+
+        let started_at = SystemTime::now();
+        let session = create_session(
+            &connection,
+            Some(&rig),
+            Some(&participant),
+            "1.0",
+            started_at,
+        )
+        .unwrap();
+        assert_eq!(Some(rig.id), session.rig_id);
+        assert_eq!(naive_utc(started_at), session.started_at);
+
+        let session = end_session(
+            &connection,
+            session.id,
+            &CalibrationStats {
+                valence_mean: Some(0.5),
+                rejected_percent: Some(12.5),
+                ..CalibrationStats::default()
+            },
+            started_at,
+        )
+        .unwrap();
+        assert_eq!(Some(0.5), session.valence_mean);
+        assert_eq!(Some(12.5), session.rejected_percent);
+        assert!(session.ended_at.is_some());
+    }
+
+    #[test]
+    fn test_insert_stimuli_events_and_responses() {
+        use models::{EventRow, Response, ResponseRow, Stimulus, StimulusRow};
+
+        let connection = establish("sqlite::memory:").unwrap();
+        let started_at = SystemTime::now();
+        let session = create_session(&connection, None, None, "1.0", started_at).unwrap();
+        let stimulus = |position| StimulusRow {
+            onset: started_at,
+            sequence: "negative".to_string(),
+            position,
+            image: format!("negative-images/{}.jpg", position),
+        };
+        let batch = SampleBatch {
+            stimuli: vec![stimulus(0), stimulus(1)],
+            events: vec![EventRow {
+                time: started_at,
+                event: "onset".to_string(),
+                detail: "0 negative-images/0.jpg".to_string(),
+            }],
+            ..SampleBatch::default()
+        };
+        assert_eq!(3, insert_samples(&connection, session.id, &batch).unwrap());
+
+        // A response in a later batch still finds its stimulus
+        let batch = SampleBatch {
+            responses: vec![ResponseRow {
+                sequence: "negative".to_string(),
+                position: 1,
+                familiar: None,
+                liked: Some(true),
+                rating: Some(4),
+                reaction_time_ms: Some(800),
+                input: Some("keyboard".to_string()),
+            }],
+            ..SampleBatch::default()
+        };
+        assert_eq!(1, insert_samples(&connection, session.id, &batch).unwrap());
+
+        let response = with_connection!(&connection, |c| schema::responses::table
+            .first::<Response>(c))
+        .unwrap();
+        let stimulus = with_connection!(&connection, |c| schema::stimuli::table
+            .find(response.stimulus_id.unwrap())
+            .first::<Stimulus>(c))
+        .unwrap();
+        assert_eq!(1, stimulus.position);
+        assert_eq!(session.id, response.session_id);
+    }
 }
 
-pub fn create_rig<'a>(conn: &PgConnection, title: &'a str, body: &'a str) -> Rig {
+pub fn create_rig(conn: &DbConnection, new_rig: &NewRig) -> QueryResult<Rig> {
     use schema::rigs;
 
-    let new_rig = NewRig {
-        title: title,
-        body: body,
+    insert_returning!(conn, rigs::table, new_rig)
+}
+
+/// Rigs in order of creation, active ones only unless 'include_inactive'
+pub fn list_rigs(conn: &DbConnection, include_inactive: bool) -> QueryResult<Vec<Rig>> {
+    use schema::rigs::dsl::*;
+
+    if include_inactive {
+        with_connection!(conn, |c| rigs.order(id).load::<Rig>(c))
+    } else {
+        with_connection!(conn, |c| rigs
+            .filter(active.eq(true))
+            .order(id)
+            .load::<Rig>(c))
+    }
+}
+
+/// Rigs with exactly this title, active or not
+pub fn find_rigs_by_title(conn: &DbConnection, rig_title: &str) -> QueryResult<Vec<Rig>> {
+    use schema::rigs::dsl::*;
+
+    with_connection!(conn, |c| rigs
+        .filter(title.eq(rig_title))
+        .order(id)
+        .load::<Rig>(c))
+}
+
+pub fn find_rig_by_id(conn: &DbConnection, rig_id: i32) -> QueryResult<Option<Rig>> {
+    use schema::rigs::dsl::*;
+
+    with_connection!(conn, |c| rigs.find(rig_id).first::<Rig>(c).optional())
+}
+
+pub fn update_rig(conn: &DbConnection, rig_id: i32, changes: &RigChanges) -> QueryResult<Rig> {
+    use schema::rigs;
+
+    with_connection!(conn, |c| c.transaction(|| {
+        diesel::update(rigs::table.find(rig_id))
+            .set(changes)
+            .execute(c)?;
+        rigs::table.find(rig_id).first(c)
+    }))
+}
+
+/// Remove one rig. Fails if any session ran on it, deactivate the rig instead.
+pub fn delete_rig(conn: &DbConnection, rig_id: i32) -> QueryResult<usize> {
+    use schema::rigs;
+
+    with_connection!(conn, |c| diesel::delete(rigs::table.find(rig_id))
+        .execute(c))
+}
+
+/// The active rig with this title, if there is one
+pub fn find_rig(conn: &DbConnection, rig_title: &str) -> QueryResult<Option<Rig>> {
+    use schema::rigs::dsl::*;
+
+    with_connection!(conn, |c| rigs
+        .filter(active.eq(true))
+        .filter(title.eq(rig_title))
+        .first::<Rig>(c)
+        .optional())
+}
+
+/// Participants are known only by an anonymous code, which is added the first time it is seen
+pub fn find_or_create_participant(
+    conn: &DbConnection,
+    participant_code: &str,
+) -> QueryResult<Participant> {
+    use schema::participants::dsl::*;
+
+    let existing = with_connection!(conn, |c| participants
+        .filter(code.eq(participant_code))
+        .first::<Participant>(c)
+        .optional())?;
+
+    match existing {
+        Some(participant) => Ok(participant),
+        None => insert_returning!(
+            conn,
+            participants,
+            &NewParticipant {
+                code: participant_code,
+            }
+        ),
+    }
+}
+
+/// Record the start of a session
+pub fn create_session(
+    conn: &DbConnection,
+    rig: Option<&Rig>,
+    participant: Option<&Participant>,
+    protocol_version: &str,
+    started_at: SystemTime,
+) -> QueryResult<Session> {
+    use schema::sessions;
+
+    let new_session = NewSession {
+        rig_id: rig.map(|rig| rig.id),
+        participant_id: participant.map(|participant| participant.id),
+        started_at: naive_utc(started_at),
+        protocol_version,
+    };
+
+    insert_returning!(conn, sessions::table, &new_session)
+}
+
+/// Record the end of a session with its calibration statistics
+pub fn end_session(
+    conn: &DbConnection,
+    session_id: i32,
+    calibration: &CalibrationStats,
+    ended_at: SystemTime,
+) -> QueryResult<Session> {
+    use schema::sessions;
+
+    let session_end = SessionEnd {
+        ended_at: Some(naive_utc(ended_at)),
+        valence_mean: calibration.valence_mean,
+        valence_deviation: calibration.valence_deviation,
+        arousal_mean: calibration.arousal_mean,
+        arousal_deviation: calibration.arousal_deviation,
+        rejected_percent: calibration.rejected_percent,
     };
 
-    diesel::insert_into(rigs::table)
-        .values(&new_rig)
-        .get_result(conn)
-        .expect("Error saving new rig")
+    with_connection!(conn, |c| c.transaction(|| {
+        diesel::update(sessions::table.find(session_id))
+            .set(&session_end)
+            .execute(c)?;
+        sessions::table.find(session_id).first(c)
+    }))
+}
+
+const ROWS_PER_INSERT: usize = 1000; // Stay well below the PostgreSQL limit of 65535 bind parameters
+
+/// Store a batch of EEG samples, band powers, valence and arousal, stimuli, events and responses in
+/// one transaction. Returns the number of rows inserted. SQLite stores a NaN channel as NULL.
+pub fn insert_samples(
+    conn: &DbConnection,
+    session_id: i32,
+    batch: &SampleBatch,
+) -> QueryResult<usize> {
+    use schema::{band_powers, eeg_samples, events, responses, stimuli, valence_arousal};
+
+    let eeg: Vec<NewEegSample> = batch
+        .eeg
+        .iter()
+        .map(|row| NewEegSample {
+            session_id,
+            time: naive_utc(row.time),
+            tp9: row.channels[0],
+            af7: row.channels[1],
+            af8: row.channels[2],
+            tp10: row.channels[3],
+            aux_right: row.aux[0],
+            aux_left: row.aux[1],
+        })
+        .collect();
+    let bands: Vec<NewBandPower> = batch
+        .band_powers
+        .iter()
+        .map(|row| NewBandPower {
+            session_id,
+            time: naive_utc(row.time),
+            band: &row.band,
+            tp9: row.channels[0],
+            af7: row.channels[1],
+            af8: row.channels[2],
+            tp10: row.channels[3],
+        })
+        .collect();
+    let valence_arousal_rows: Vec<NewValenceArousal> = batch
+        .valence_arousal
+        .iter()
+        .map(|row| NewValenceArousal {
+            session_id,
+            time: naive_utc(row.time),
+            valence: row.valence,
+            arousal: row.arousal,
+            normalized_valence: row.normalized_valence,
+            normalized_arousal: row.normalized_arousal,
+        })
+        .collect();
+    let new_stimuli: Vec<NewStimulus> = batch
+        .stimuli
+        .iter()
+        .map(|row| NewStimulus {
+            session_id,
+            sequence: row.sequence.clone(),
+            position: row.position,
+            image: row.image.clone(),
+            onset: naive_utc(row.onset),
+        })
+        .collect();
+    let new_events: Vec<NewEvent> = batch
+        .events
+        .iter()
+        .map(|row| NewEvent {
+            session_id,
+            time: naive_utc(row.time),
+            event: row.event.clone(),
+            detail: row.detail.clone(),
+        })
+        .collect();
+
+    with_connection!(conn, |c| c.transaction(|| {
+        let mut count = 0;
+        // Stimuli first, so each response can find the stimulus it is about
+        for chunk in new_stimuli.chunks(ROWS_PER_INSERT) {
+            count = count
+                + diesel::insert_into(stimuli::table)
+                    .values(chunk)
+                    .execute(c)?;
+        }
+        for chunk in new_events.chunks(ROWS_PER_INSERT) {
+            count = count
+                + diesel::insert_into(events::table)
+                    .values(chunk)
+                    .execute(c)?;
+        }
+        for row in &batch.responses {
+            // The latest showing of this image, a sequence may be run more than once
+            let stimulus_id = stimuli::table
+                .select(stimuli::id)
+                .filter(stimuli::session_id.eq(session_id))
+                .filter(stimuli::sequence.eq(&row.sequence))
+                .filter(stimuli::position.eq(row.position))
+                .order(stimuli::id.desc())
+                .first::<i32>(c)
+                .optional()?;
+            count = count
+                + diesel::insert_into(responses::table)
+                    .values(&NewResponse {
+                        session_id,
+                        stimulus_id,
+                        familiar: row.familiar,
+                        liked: row.liked,
+                        rating: row.rating,
+                        reaction_time_ms: row.reaction_time_ms,
+                        input: row.input.clone(),
+                    })
+                    .execute(c)?;
+        }
+        for chunk in eeg.chunks(ROWS_PER_INSERT) {
+            count = count
+                + diesel::insert_into(eeg_samples::table)
+                    .values(chunk)
+                    .execute(c)?;
+        }
+        for chunk in bands.chunks(ROWS_PER_INSERT) {
+            count = count
+                + diesel::insert_into(band_powers::table)
+                    .values(chunk)
+                    .execute(c)?;
+        }
+        for chunk in valence_arousal_rows.chunks(ROWS_PER_INSERT) {
+            count = count
+                + diesel::insert_into(valence_arousal::table)
+                    .values(chunk)
+                    .execute(c)?;
+        }
+
+        Ok(count)
+    }))
 }
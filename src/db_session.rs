@@ -0,0 +1,637 @@
+use crate::response::StimulusResponse;
+use crate::rig::RigIdentity;
+/// The database record of this run of the app: which rig and participant, when it started and
+/// ended, the calibration statistics, every EEG sample, band power and valence and arousal value,
+/// and each stimulus, event and response. Rows are inserted in batches on a background thread so the update loop never waits for
+/// the database. While the database is unreachable batches wait in a spool file on disk, and are
+/// back-filled once it can be reached again. With DATABASE_URL=none the app runs without a database.
+use meme_machine_database::models::{
+    BandPowerRow, EegRow, EventRow, ResponseRow, SampleBatch, StimulusRow, ValenceArousalRow,
+};
+use meme_machine_database::{self as database, CalibrationStats, DbConnection};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const ENV_PARTICIPANT: &str = "MEME_PARTICIPANT"; // Anonymous participant code
+const SPOOL_DIR: &str = "db_spool"; // Batches wait here while the database is unreachable
+const BATCH_SIZE: usize = 2560; // Rows, about ten seconds of raw EEG
+const FLUSH_INTERVAL: Duration = Duration::from_secs(2); // Insert at least this often, however small the batch
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(30); // Wait this long before trying an unreachable database again
+const SESSION_WAIT: Duration = Duration::from_secs(2); // Startup waits this long for the session id, then goes on until it arrives
+
+/// Enough to create the session later, if the database was unreachable when it started
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct SessionStart {
+    session_id: Option<i32>, // None until the session is in the database
+    started_at: SystemTime,
+    #[serde(default)]
+    rig_id: Option<i32>, // Chosen at startup, None if the rig is not in the database
+    rig: Option<String>, // Title, looked up when there is no id
+    participant: Option<String>,
+    protocol_version: String,
+}
+
+/// One line of a spool file. The first line of each file is the session.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+enum SpoolLine {
+    Session(SessionStart),
+    Samples(SampleBatch),
+    End {
+        calibration: CalibrationStats,
+        ended_at: SystemTime,
+    },
+}
+
+enum DbMessage {
+    Eeg(EegRow),
+    BandPower(BandPowerRow),
+    ValenceArousal(ValenceArousalRow),
+    Stimulus(StimulusRow),
+    Event(EventRow),
+    Response(ResponseRow),
+    Close {
+        calibration: CalibrationStats,
+        ended_at: SystemTime,
+    },
+}
+
+/// Hands rows to the database thread without waiting
+#[derive(Clone)]
+pub struct SampleSender {
+    tx: Sender<DbMessage>,
+}
+
+impl SampleSender {
+    /// Raw EEG, time since UNIX_EPOCH
+    pub fn eeg(&self, time: Duration, channels: [f32; 4], aux: [Option<f32>; 2]) {
+        self.send(DbMessage::Eeg(EegRow {
+            time: UNIX_EPOCH + time,
+            channels,
+            aux,
+        }));
+    }
+
+    pub fn band_power(&self, time: Duration, band: &str, channels: [f32; 4]) {
+        self.send(DbMessage::BandPower(BandPowerRow {
+            time: UNIX_EPOCH + time,
+            band: band.to_string(),
+            channels,
+        }));
+    }
+
+    pub fn valence_arousal(
+        &self,
+        time: Duration,
+        valence: Option<f32>,
+        arousal: Option<f32>,
+        normalized_valence: Option<f32>,
+        normalized_arousal: Option<f32>,
+    ) {
+        self.send(DbMessage::ValenceArousal(ValenceArousalRow {
+            time: UNIX_EPOCH + time,
+            valence,
+            arousal,
+            normalized_valence,
+            normalized_arousal,
+        }));
+    }
+
+    /// An image shown at 'onset', time since UNIX_EPOCH
+    pub fn stimulus(&self, onset: Duration, sequence: &str, position: usize, image: &str) {
+        self.send(DbMessage::Stimulus(StimulusRow {
+            onset: UNIX_EPOCH + onset,
+            sequence: sequence.to_string(),
+            position: position as i32,
+            image: image.to_string(),
+        }));
+    }
+
+    pub fn event(&self, time: Duration, event: &str, detail: &str) {
+        self.send(DbMessage::Event(EventRow {
+            time: UNIX_EPOCH + time,
+            event: event.to_string(),
+            detail: detail.to_string(),
+        }));
+    }
+
+    /// Everything answered about one stimulus, stored next to the EEG of the same session
+    pub fn response(&self, response: &StimulusResponse) {
+        self.send(DbMessage::Response(ResponseRow {
+            sequence: response.sequence.clone(),
+            position: response.index as i32,
+            familiar: response.familiar,
+            liked: response.liked,
+            rating: response.rating.map(|rating| rating as i16),
+            reaction_time_ms: response
+                .reaction_time
+                .map(|reaction_time| reaction_time.as_millis() as i32),
+            input: response.source.map(|source| source.to_string()),
+        }));
+    }
+
+    /// If the database thread has stopped the row is dropped, the CSV logs still have it
+    fn send(&self, message: DbMessage) {
+        let _result = self.tx.send(message);
+    }
+}
+
+pub struct DbSession {
+    session_id: Option<i32>,
+    session_rx: Receiver<Option<i32>>, // The id once the database thread creates the session
+    sender: SampleSender,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl DbSession {
+    /// Start recording this session. None if no database is configured. The session row is
+    /// created on the database thread, startup only waits SESSION_WAIT for its id. If it comes
+    /// later it is given by session_id(). Spool files are back-filled on that thread after the id
+    /// is known.
+    pub fn open(
+        protocol_version: &str,
+        rig: &RigIdentity,
+        started_at: SystemTime,
+    ) -> Option<DbSession> {
+        if database::database_url().is_none() {
+            info!("DATABASE_URL is none, the session is only logged to CSV");
+            return None;
+        }
+
+        let start = SessionStart {
+            session_id: None,
+            started_at,
+            rig_id: rig.id,
+            rig: if *rig == RigIdentity::unknown() {
+                None
+            } else {
+                Some(rig.title.clone())
+            },
+            participant: env::var(ENV_PARTICIPANT).ok(),
+            protocol_version: protocol_version.to_string(),
+        };
+        let spool = Spool::new(Path::new(SPOOL_DIR), &start);
+        let (tx, rx) = mpsc::channel();
+        let (session_tx, session_rx) = mpsc::channel();
+        let db_worker = DbWorker::new(start, spool, session_tx);
+        let worker = thread::Builder::new()
+            .name("database".to_string())
+            .spawn(move || db_worker.run(rx))
+            .expect("Can not start the database thread");
+        let session_id = match session_rx.recv_timeout(SESSION_WAIT) {
+            Ok(session_id) => session_id,
+            Err(_) => {
+                warn!(
+                    "No database session after {:?}, going on until its id arrives",
+                    SESSION_WAIT
+                );
+                None
+            }
+        };
+
+        Some(DbSession {
+            session_id,
+            session_rx,
+            sender: SampleSender { tx },
+            worker: Some(worker),
+        })
+    }
+
+    /// The session row in the database, None until the database thread has created it
+    pub fn session_id(&mut self) -> Option<i32> {
+        for session_id in self.session_rx.try_iter() {
+            self.session_id = session_id.or(self.session_id);
+        }
+
+        self.session_id
+    }
+
+    /// For the model to send rows as they arrive
+    pub fn sample_sender(&self) -> SampleSender {
+        self.sender.clone()
+    }
+
+    /// Record the end of the session, waiting for the last rows to be stored or spooled. Returns
+    /// the session id, which may only have been created while closing.
+    pub fn close(mut self, calibration: &CalibrationStats) -> Option<i32> {
+        self.sender.send(DbMessage::Close {
+            calibration: calibration.clone(),
+            ended_at: SystemTime::now(),
+        });
+        if let Some(worker) = self.worker.take() {
+            if worker.join().is_err() {
+                error!("Database thread failed, the spool may hold rows which were not stored");
+            }
+        }
+
+        self.session_id()
+    }
+}
+
+/// Runs on the database thread, collecting rows into batches
+struct DbWorker {
+    start: SessionStart,
+    connection: Option<DbConnection>,
+    last_connect: Option<Instant>,
+    spool_pending: bool, // Connected since the spool directory was last back-filled
+    batch: SampleBatch,
+    last_flush: Instant,
+    spool: Spool,
+    session_tx: Sender<Option<i32>>, // Tells DbSession the id once the session is created
+}
+
+impl DbWorker {
+    fn new(start: SessionStart, spool: Spool, session_tx: Sender<Option<i32>>) -> Self {
+        Self {
+            start,
+            connection: None,
+            last_connect: None,
+            spool_pending: false,
+            batch: SampleBatch::default(),
+            last_flush: Instant::now(),
+            spool,
+            session_tx,
+        }
+    }
+
+    fn run(mut self, rx: Receiver<DbMessage>) {
+        if !self.connect() {
+            // Startup need not wait any longer, the id is sent if the session is created later
+            let _result = self.session_tx.send(None);
+        }
+        self.backfill_spool();
+
+        loop {
+            match rx.recv_timeout(FLUSH_INTERVAL) {
+                Ok(DbMessage::Eeg(row)) => self.batch.eeg.push(row),
+                Ok(DbMessage::BandPower(row)) => self.batch.band_powers.push(row),
+                Ok(DbMessage::ValenceArousal(row)) => self.batch.valence_arousal.push(row),
+                Ok(DbMessage::Stimulus(row)) => self.batch.stimuli.push(row),
+                Ok(DbMessage::Event(row)) => self.batch.events.push(row),
+                Ok(DbMessage::Response(row)) => self.batch.responses.push(row),
+                Ok(DbMessage::Close {
+                    calibration,
+                    ended_at,
+                }) => {
+                    self.flush();
+                    self.end(calibration, ended_at);
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.flush();
+                    return;
+                }
+            }
+
+            if self.batch.len() >= BATCH_SIZE || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+                self.flush();
+            }
+        }
+    }
+
+    /// True if connected and the session is in the database. An unreachable database is only
+    /// tried again after RECONNECT_INTERVAL.
+    fn connect(&mut self) -> bool {
+        if self.connection.is_some() {
+            return true;
+        }
+        if let Some(last_connect) = self.last_connect {
+            if last_connect.elapsed() < RECONNECT_INTERVAL {
+                return false;
+            }
+        }
+        self.last_connect = Some(Instant::now());
+
+        let connection = match database::try_establish_connection() {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!(
+                    "Database unreachable, spooling to {:?}: {}",
+                    self.spool.path, e
+                );
+                return false;
+            }
+        };
+        if self.start.session_id.is_none() {
+            match create_session(&connection, &self.start) {
+                Ok(session_id) => {
+                    info!(
+                        "Database session {} started, protocol {}",
+                        session_id, self.start.protocol_version
+                    );
+                    self.start.session_id = Some(session_id);
+                    let _result = self.session_tx.send(Some(session_id));
+                }
+                Err(e) => {
+                    warn!("Can not create database session: {}", e);
+                    return false;
+                }
+            }
+        }
+        self.connection = Some(connection);
+        self.spool_pending = true;
+
+        true
+    }
+
+    /// True if connected, after storing anything spooled since the last connection
+    fn connect_and_backfill(&mut self) -> bool {
+        if !self.connect() {
+            return false;
+        }
+        self.backfill_spool();
+
+        true
+    }
+
+    /// Store every spooled session once per connection
+    fn backfill_spool(&mut self) {
+        if !self.spool_pending {
+            return;
+        }
+        if let Some(connection) = &self.connection {
+            backfill_spool_dir(connection, &self.spool, self.start.session_id);
+            self.spool_pending = false;
+        }
+    }
+
+    /// Insert the rows collected so far, or spool them if that is not possible
+    fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        if self.batch.is_empty() {
+            return;
+        }
+        let batch = std::mem::replace(&mut self.batch, SampleBatch::default());
+
+        if self.connect_and_backfill() {
+            let session_id = self.start.session_id.expect("Connected without a session");
+            let connection = self
+                .connection
+                .as_ref()
+                .expect("Connected without a connection");
+            match database::insert_samples(connection, session_id, &batch) {
+                Ok(_count) => return,
+                Err(e) => {
+                    warn!("Can not insert samples, spooling them: {}", e);
+                    self.connection = None;
+                }
+            }
+        }
+
+        self.spool.append(&self.start, &SpoolLine::Samples(batch));
+    }
+
+    fn end(&mut self, calibration: CalibrationStats, ended_at: SystemTime) {
+        if self.connect_and_backfill() {
+            let session_id = self.start.session_id.expect("Connected without a session");
+            let connection = self
+                .connection
+                .as_ref()
+                .expect("Connected without a connection");
+            match database::end_session(connection, session_id, &calibration, ended_at) {
+                Ok(_session) => {
+                    info!("Database session {} ended", session_id);
+                    return;
+                }
+                Err(e) => warn!("Can not end database session {}: {}", session_id, e),
+            }
+        }
+
+        self.spool.append(
+            &self.start,
+            &SpoolLine::End {
+                calibration,
+                ended_at,
+            },
+        );
+    }
+}
+
+/// Create the session row, looking up the rig and participant if given
+fn create_session(connection: &DbConnection, start: &SessionStart) -> diesel::QueryResult<i32> {
+    let rig = match (start.rig_id, &start.rig) {
+        (Some(rig_id), _) => database::find_rig_by_id(connection, rig_id)?,
+        (None, Some(title)) => {
+            let rig = database::find_rig(connection, title)?;
+            if rig.is_none() {
+                warn!("No active rig '{}' in the database", title);
+            }
+            rig
+        }
+        (None, None) => None,
+    };
+    let participant = match &start.participant {
+        Some(code) => Some(database::find_or_create_participant(connection, code)?),
+        None => None,
+    };
+
+    database::create_session(
+        connection,
+        rig.as_ref(),
+        participant.as_ref(),
+        &start.protocol_version,
+        start.started_at,
+    )
+    .map(|session| session.id)
+}
+
+/// JSON lines, one file per session, appended to while the database is unreachable
+struct Spool {
+    path: PathBuf,
+}
+
+impl Spool {
+    fn new(dir: &Path, start: &SessionStart) -> Self {
+        let started_at = start
+            .started_at
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        Self {
+            path: dir.join(format!("session_{}.jsonl", started_at.as_millis())),
+        }
+    }
+
+    /// A new file begins with the session so it can be back-filled by a later run
+    fn append(&self, start: &SessionStart, line: &SpoolLine) {
+        if let Err(e) = self.try_append(start, line) {
+            error!("Can not spool to {:?}, rows are lost: {}", self.path, e);
+        }
+    }
+
+    fn try_append(&self, start: &SessionStart, line: &SpoolLine) -> std::io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let is_new = !self.path.exists();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+
+        if is_new {
+            writeln!(file, "{}", to_json(&SpoolLine::Session(start.clone()))?)?;
+        }
+        writeln!(file, "{}", to_json(line)?)?;
+        file.flush()
+    }
+}
+
+fn to_json(line: &SpoolLine) -> std::io::Result<String> {
+    serde_json::to_string(line).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+}
+
+/// Read a spool file. A line cut short when the app stopped is skipped.
+fn read_spool(path: &Path) -> Result<Vec<SpoolLine>, String> {
+    let file = File::open(path).map_err(|e| format!("Can not open {:?}: {}", path, e))?;
+    let mut lines = Vec::new();
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Can not read {:?}: {}", path, e))?;
+        match serde_json::from_str(&line) {
+            Ok(spool_line) => lines.push(spool_line),
+            Err(e) => warn!("Skipping line {} of {:?}: {}", i + 1, path, e),
+        }
+    }
+
+    Ok(lines)
+}
+
+/// Store every spooled session, this one and any left by earlier runs. Each file is stored in
+/// one transaction and removed once stored.
+fn backfill_spool_dir(connection: &DbConnection, spool: &Spool, session_id: Option<i32>) {
+    let dir = match spool.path.parent() {
+        Some(dir) => dir,
+        None => return,
+    };
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return, // Nothing has been spooled
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("jsonl") {
+            continue;
+        }
+        // This session is already in the database, other files may need their session created
+        let known_session_id = if path == spool.path { session_id } else { None };
+
+        match backfill(connection, &path, known_session_id) {
+            Ok(count) => {
+                info!("Back-filled {} rows from {:?}", count, path);
+                if let Err(e) = fs::remove_file(&path) {
+                    error!(
+                        "Can not remove {:?}, it may be back-filled twice: {}",
+                        path, e
+                    );
+                }
+            }
+            Err(e) => warn!("Can not back-fill {:?}, will try again later: {}", path, e),
+        }
+    }
+}
+
+fn backfill(
+    connection: &DbConnection,
+    path: &Path,
+    known_session_id: Option<i32>,
+) -> Result<usize, String> {
+    let lines = read_spool(path)?;
+
+    connection
+        .transaction::<_, diesel::result::Error, _>(|| {
+            let mut session_id = known_session_id;
+            let mut count = 0;
+
+            for line in &lines {
+                match line {
+                    SpoolLine::Session(start) => {
+                        if session_id.is_none() {
+                            session_id = Some(match start.session_id {
+                                Some(session_id) => session_id,
+                                None => create_session(connection, start)?,
+                            });
+                        }
+                    }
+                    SpoolLine::Samples(batch) => {
+                        let session_id = session_id.ok_or(diesel::result::Error::NotFound)?;
+                        count = count + database::insert_samples(connection, session_id, batch)?;
+                    }
+                    SpoolLine::End {
+                        calibration,
+                        ended_at,
+                    } => {
+                        let session_id = session_id.ok_or(diesel::result::Error::NotFound)?;
+                        database::end_session(connection, session_id, calibration, *ended_at)?;
+                    }
+                }
+            }
+
+            Ok(count)
+        })
+        .map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start() -> SessionStart {
+        SessionStart {
+            session_id: None,
+            started_at: UNIX_EPOCH + Duration::from_millis(1_583_020_800_123),
+            rig_id: Some(3),
+            rig: Some("Lab rig 1".to_string()),
+            participant: None,
+            protocol_version: "1.0".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_spool_round_trip() {
+        let dir = env::temp_dir().join(format!("meme_spool_test_{}", std::process::id()));
+        let start = start();
+        let spool = Spool::new(&dir, &start);
+        assert_eq!(
+            Some("session_1583020800123.jsonl"),
+            spool.path.file_name().and_then(|name| name.to_str())
+        );
+        let _result = fs::remove_file(&spool.path);
+
+        let mut batch = SampleBatch::default();
+        batch.eeg.push(EegRow {
+            time: start.started_at,
+            channels: [1.0, std::f32::NAN, 3.0, 4.0],
+            aux: [None, Some(0.5)],
+        });
+        batch.valence_arousal.push(ValenceArousalRow {
+            time: start.started_at,
+            valence: Some(1.5),
+            arousal: None,
+            normalized_valence: Some(0.2),
+            normalized_arousal: None,
+        });
+        spool.append(&start, &SpoolLine::Samples(batch.clone()));
+        spool.append(&start, &SpoolLine::Samples(batch));
+
+        let lines = read_spool(&spool.path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(3, lines.len());
+        assert_eq!(SpoolLine::Session(start), lines[0]);
+        match &lines[2] {
+            SpoolLine::Samples(batch) => {
+                assert_eq!(2, batch.len());
+                assert!(batch.eeg[0].channels[1].is_nan());
+                assert_eq!(Some(0.5), batch.eeg[0].aux[1]);
+            }
+            line => panic!("Expected samples, got {:?}", line),
+        }
+    }
+}
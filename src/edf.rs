@@ -0,0 +1,513 @@
+/// Export a recorded session directory to EDF+, which EEGLAB, MNE and EDFbrowser can read. The
+/// raw EEG and accelerometer CSV logs are resampled onto a fixed rate and events.csv becomes the
+/// annotations. The file is continuous (EDF+C) with one second data records.
+use chrono::{DateTime, Datelike, Utc};
+use csv::{ReaderBuilder, StringRecord};
+use serde::Deserialize;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, UNIX_EPOCH};
+
+pub const EDF_FILE: &str = "session.edf"; // Written in the session directory
+const EEG_FILE: &str = "eeg.csv"; // Raw EEG, before the filter chain
+const ACCELEROMETER_FILE: &str = "accelerometer.csv";
+const EVENT_FILE: &str = "events.csv";
+const MANIFEST_FILE: &str = "manifest.json";
+
+const EEG_LABELS: [&str; 4] = ["TP9", "AF7", "AF8", "TP10"]; // Also the eeg.csv column names
+const EEG_RATE: usize = 256; // Muse raw EEG samples per second
+const EEG_PHYSICAL_RANGE: (f64, f64) = (0.0, 1682.815); // microVolts, the range MindMonitor reports
+const ACCELEROMETER_COLUMNS: [&str; 3] = ["x_g", "y_g", "z_g"];
+const ACCELEROMETER_LABELS: [&str; 3] = ["Accel X", "Accel Y", "Accel Z"];
+const ACCELEROMETER_RATE: usize = 52;
+const ACCELEROMETER_PHYSICAL_RANGE: (f64, f64) = (-2.0, 2.0); // g
+const DIGITAL_RANGE: (i32, i32) = (-32767, 32767); // 16 bit samples, as declared in the header
+const MISSING_SAMPLE: i16 = -32768; // Below the declared range, so never a clipped reading
+const RECORD_SECONDS: u64 = 1;
+const ANNOTATIONS_LABEL: &str = "EDF Annotations";
+
+/// What was written to an EDF+ file
+#[derive(Debug)]
+pub struct EdfExport {
+    pub records: usize, // Seconds of recording
+    pub signals: usize, // Not counting the annotations
+    pub annotations: usize,
+}
+
+/// The parts of manifest.json named in the EDF+ header
+#[derive(Default, Deserialize)]
+struct SessionManifest {
+    session_id: Option<i32>,
+    rig_title: Option<String>,
+}
+
+/// One row of a CSV log: microseconds since UNIX_EPOCH and the values of the chosen columns
+type TimedValues = (u64, Vec<f32>);
+
+/// One signal resampled to a fixed number of samples each data record
+struct Signal {
+    label: String,
+    transducer: &'static str,
+    dimension: &'static str,
+    physical_range: (f64, f64),
+    samples_per_record: usize,
+    digital: Vec<i16>,
+}
+
+/// Write the EDF+ file of the session logged in 'dir'
+pub fn export_session(dir: &Path, output: &Path) -> io::Result<EdfExport> {
+    let eeg = read_samples(&dir.join(EEG_FILE), &EEG_LABELS)?;
+    let first_time = eeg
+        .iter()
+        .map(|row| row.0)
+        .min()
+        .ok_or_else(|| invalid(format!("{:?} has no EEG samples", dir.join(EEG_FILE))))?;
+    let last_time = eeg.iter().map(|row| row.0).max().unwrap_or(first_time);
+    let start_us = first_time - first_time % (RECORD_SECONDS * 1_000_000); // EDF starts on a whole second
+    let record_us = RECORD_SECONDS * 1_000_000;
+    let records = ((last_time - start_us) / record_us + 1) as usize;
+
+    let mut signals: Vec<Signal> = EEG_LABELS
+        .iter()
+        .enumerate()
+        .map(|(i, label)| Signal {
+            label: label.to_string(),
+            transducer: "Muse dry electrode",
+            dimension: "uV",
+            physical_range: EEG_PHYSICAL_RANGE,
+            samples_per_record: EEG_RATE * RECORD_SECONDS as usize,
+            digital: digitize(
+                &resample(&eeg, i, start_us, EEG_RATE, records * EEG_RATE),
+                EEG_PHYSICAL_RANGE,
+            ),
+        })
+        .collect();
+
+    // Sessions logged before accelerometer.csv existed have only EEG
+    let accelerometer_path = dir.join(ACCELEROMETER_FILE);
+    if accelerometer_path.exists() {
+        let accelerometer = read_samples(&accelerometer_path, &ACCELEROMETER_COLUMNS)?;
+        let n = records * ACCELEROMETER_RATE;
+        for (i, label) in ACCELEROMETER_LABELS.iter().enumerate() {
+            signals.push(Signal {
+                label: label.to_string(),
+                transducer: "Muse accelerometer",
+                dimension: "g",
+                physical_range: ACCELEROMETER_PHYSICAL_RANGE,
+                samples_per_record: ACCELEROMETER_RATE * RECORD_SECONDS as usize,
+                digital: digitize(
+                    &resample(&accelerometer, i, start_us, ACCELEROMETER_RATE, n),
+                    ACCELEROMETER_PHYSICAL_RANGE,
+                ),
+            });
+        }
+    }
+
+    let events = read_events(&dir.join(EVENT_FILE))?;
+    let annotations = annotation_records(&events, start_us, records);
+    let manifest = read_manifest(&dir.join(MANIFEST_FILE));
+
+    let mut writer = BufWriter::new(File::create(output)?);
+    writer.write_all(&header(
+        &signals,
+        &annotations,
+        start_us,
+        records,
+        &manifest,
+    ))?;
+    for record in 0..records {
+        for signal in &signals {
+            let samples = &signal.digital
+                [record * signal.samples_per_record..(record + 1) * signal.samples_per_record];
+            for sample in samples {
+                writer.write_all(&sample.to_le_bytes())?;
+            }
+        }
+        writer.write_all(&annotations.records[record])?;
+    }
+    writer.flush()?;
+
+    Ok(EdfExport {
+        records,
+        signals: signals.len(),
+        annotations: events.len(),
+    })
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The time and named columns of every row of a CSV log. Dropped samples are NaN.
+fn read_samples(path: &Path, columns: &[&str]) -> io::Result<Vec<TimedValues>> {
+    let mut reader = ReaderBuilder::new().comment(Some(b'#')).from_path(path)?;
+    let headers = reader.headers()?.clone();
+    let index = |name: &str| {
+        headers
+            .iter()
+            .position(|header| header == name)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "{:?} has no {} column, it may be from before CSV schema version 3",
+                    path, name
+                ))
+            })
+    };
+    let time_index = index("epoch_us")?;
+    let indices = columns
+        .iter()
+        .map(|column| index(column))
+        .collect::<io::Result<Vec<usize>>>()?;
+    let mut rows = Vec::new();
+
+    for result in reader.records() {
+        let record = result?;
+        let time = match parse_time(&record, time_index) {
+            Some(time) => time,
+            None => continue,
+        };
+        let values = indices
+            .iter()
+            .map(|&i| {
+                record
+                    .get(i)
+                    .and_then(|value| value.parse::<f32>().ok())
+                    .unwrap_or(std::f32::NAN)
+            })
+            .collect();
+        rows.push((time, values));
+    }
+    rows.sort_by_key(|row| row.0);
+
+    Ok(rows)
+}
+
+fn parse_time(record: &StringRecord, index: usize) -> Option<u64> {
+    record.get(index).and_then(|time| time.parse::<u64>().ok())
+}
+
+/// Each event in events.csv with its time, as "event detail"
+fn read_events(path: &Path) -> io::Result<Vec<(u64, String)>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut reader = ReaderBuilder::new().comment(Some(b'#')).from_path(path)?;
+    let mut events = Vec::new();
+
+    for result in reader.records() {
+        let record = result?;
+        if let Some(time) = parse_time(&record, 0) {
+            let text = format!(
+                "{} {}",
+                record.get(2).unwrap_or(""),
+                record.get(3).unwrap_or("")
+            );
+            events.push((time, text.trim().to_string()));
+        }
+    }
+
+    Ok(events)
+}
+
+/// Without a readable manifest the header only has the start time
+fn read_manifest(path: &Path) -> SessionManifest {
+    fs::read_to_string(path)
+        .ok()
+        .and_then(|json| serde_json::from_str(&json).ok())
+        .unwrap_or_default()
+}
+
+/// 'n' values of one column at 'rate' per second from 'start_us', each the nearest sample in time.
+/// NaN where no sample is within one period.
+fn resample(rows: &[TimedValues], column: usize, start_us: u64, rate: usize, n: usize) -> Vec<f32> {
+    let period_us = 1_000_000.0 / rate as f64;
+    let mut values = Vec::with_capacity(n);
+    let mut i = 0;
+
+    for k in 0..n {
+        let time = start_us as f64 + k as f64 * period_us;
+        while i + 1 < rows.len() && (rows[i + 1].0 as f64) <= time {
+            i = i + 1;
+        }
+        let nearest = rows[i..rows.len().min(i + 2)]
+            .iter()
+            .min_by(|a, b| {
+                let a = (a.0 as f64 - time).abs();
+                let b = (b.0 as f64 - time).abs();
+                a.partial_cmp(&b).unwrap()
+            })
+            .filter(|row| (row.0 as f64 - time).abs() <= period_us);
+
+        values.push(match nearest {
+            Some(row) => row.1[column],
+            None => std::f32::NAN,
+        });
+    }
+
+    values
+}
+
+/// Scale physical values to 16 bit samples. Values outside the range are clipped to it, and
+/// missing values are MISSING_SAMPLE.
+fn digitize(values: &[f32], (physical_min, physical_max): (f64, f64)) -> Vec<i16> {
+    let (digital_min, digital_max) = (DIGITAL_RANGE.0 as f64, DIGITAL_RANGE.1 as f64);
+    let scale = (digital_max - digital_min) / (physical_max - physical_min);
+
+    values
+        .iter()
+        .map(|&value| {
+            if value.is_nan() {
+                MISSING_SAMPLE
+            } else {
+                let digital = (value as f64 - physical_min) * scale + digital_min;
+                digital.round().max(digital_min).min(digital_max) as i16
+            }
+        })
+        .collect()
+}
+
+/// The annotation signal of each data record, all padded to the same length
+struct Annotations {
+    samples_per_record: usize,
+    records: Vec<Vec<u8>>,
+}
+
+/// Every record starts with its onset, as EDF+ requires, followed by the events which happened
+/// during it. Events before the first or after the last record go in that record.
+fn annotation_records(events: &[(u64, String)], start_us: u64, records: usize) -> Annotations {
+    let record_us = RECORD_SECONDS * 1_000_000;
+    let mut tals: Vec<Vec<u8>> = (0..records)
+        .map(|record| format!("+{}\x14\x14\0", record as u64 * RECORD_SECONDS).into_bytes())
+        .collect();
+
+    for (time, text) in events {
+        let record = if *time < start_us {
+            0
+        } else {
+            (((time - start_us) / record_us) as usize).min(records - 1)
+        };
+        tals[record].extend(tal(*time as i64 - start_us as i64, text));
+    }
+
+    let longest = tals.iter().map(|tal| tal.len()).max().unwrap_or(0);
+    let samples_per_record = (longest + 1) / 2;
+    for tal in &mut tals {
+        tal.resize(samples_per_record * 2, 0);
+    }
+
+    Annotations {
+        samples_per_record,
+        records: tals,
+    }
+}
+
+/// Time-stamped annotation list: "+onset", then the text, with the separators of EDF+
+fn tal(onset_us: i64, text: &str) -> Vec<u8> {
+    let sign = if onset_us < 0 { "-" } else { "+" };
+    let onset_us = onset_us.abs();
+    let text: String = text
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect();
+
+    format!(
+        "{}{}.{:06}\x14{}\x14\0",
+        sign,
+        onset_us / 1_000_000,
+        onset_us % 1_000_000,
+        text
+    )
+    .into_bytes()
+}
+
+fn header(
+    signals: &[Signal],
+    annotations: &Annotations,
+    start_us: u64,
+    records: usize,
+    manifest: &SessionManifest,
+) -> Vec<u8> {
+    let start = DateTime::<Utc>::from(UNIX_EPOCH + Duration::from_micros(start_us));
+    let ns = signals.len() + 1;
+    let months = [
+        "JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC",
+    ];
+    let admin_code = match manifest.session_id {
+        Some(session_id) => format!("session_{}", session_id),
+        None => "X".to_string(),
+    };
+    let equipment = match &manifest.rig_title {
+        Some(rig_title) => format!("Muse_{}", subfield(rig_title)),
+        None => "Muse".to_string(),
+    };
+    let recording = format!(
+        "Startdate {:02}-{}-{} {} X {}",
+        start.day(),
+        months[start.month0() as usize],
+        start.year(),
+        admin_code,
+        equipment
+    );
+
+    let mut header = Vec::with_capacity(256 * (ns + 1));
+    header.extend(field("0", 8));
+    header.extend(field("X X X X", 80)); // Participants are not identified in the export
+    header.extend(field(&recording, 80));
+    header.extend(field(&start.format("%d.%m.%y").to_string(), 8));
+    header.extend(field(&start.format("%H.%M.%S").to_string(), 8));
+    header.extend(field(&(256 * (ns + 1)).to_string(), 8));
+    header.extend(field("EDF+C", 44));
+    header.extend(field(&records.to_string(), 8));
+    header.extend(field(&RECORD_SECONDS.to_string(), 8));
+    header.extend(field(&ns.to_string(), 4));
+
+    // Each field for every signal in turn, the annotations last
+    let each =
+        |header: &mut Vec<u8>, width: usize, value: &dyn Fn(&Signal) -> String, last: &str| {
+            for signal in signals {
+                header.extend(field(&value(signal), width));
+            }
+            header.extend(field(last, width));
+        };
+    each(&mut header, 16, &|s| s.label.clone(), ANNOTATIONS_LABEL);
+    each(&mut header, 80, &|s| s.transducer.to_string(), "");
+    each(&mut header, 8, &|s| s.dimension.to_string(), "");
+    each(&mut header, 8, &|s| s.physical_range.0.to_string(), "-1");
+    each(&mut header, 8, &|s| s.physical_range.1.to_string(), "1");
+    each(&mut header, 8, &|_| DIGITAL_RANGE.0.to_string(), "-32768");
+    each(&mut header, 8, &|_| DIGITAL_RANGE.1.to_string(), "32767");
+    each(&mut header, 80, &|_| String::new(), ""); // No filter, this is the raw EEG
+    each(
+        &mut header,
+        8,
+        &|s| s.samples_per_record.to_string(),
+        &annotations.samples_per_record.to_string(),
+    );
+    each(&mut header, 32, &|_| String::new(), "");
+
+    header
+}
+
+/// Printable ASCII, cut or padded with spaces to 'width'
+fn field(text: &str, width: usize) -> Vec<u8> {
+    let mut bytes: Vec<u8> = text
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c as u8
+            } else {
+                b'_'
+            }
+        })
+        .take(width)
+        .collect();
+    bytes.resize(width, b' ');
+
+    bytes
+}
+
+/// EDF+ header subfields are separated by spaces, so they can not contain any
+fn subfield(text: &str) -> String {
+    text.replace(' ', "_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resample_nearest() {
+        let rows: Vec<TimedValues> = vec![
+            (1_000_000, vec![1.0]),
+            (1_250_000, vec![2.0]),
+            (1_490_000, vec![3.0]),
+            (3_000_000, vec![4.0]),
+        ];
+        let values = resample(&rows, 0, 1_000_000, 4, 6);
+
+        assert_eq!(&[1.0, 2.0, 3.0], &values[..3]);
+        assert!(values[3].is_nan() && values[4].is_nan() && values[5].is_nan());
+    }
+
+    #[test]
+    fn test_digitize() {
+        assert_eq!(
+            vec![-32767, 32767, -32767, MISSING_SAMPLE],
+            digitize(&[0.0, 1682.815, -5.0, std::f32::NAN], EEG_PHYSICAL_RANGE)
+        );
+        assert_eq!(
+            vec![0, -32767],
+            digitize(&[0.0, -3.0], ACCELEROMETER_PHYSICAL_RANGE)
+        );
+    }
+
+    #[test]
+    fn test_annotations() {
+        let events = vec![
+            (10_500_000, "phase_start Calibration".to_string()),
+            (11_250_000, "onset 1.jpg".to_string()),
+        ];
+        let annotations = annotation_records(&events, 10_000_000, 2);
+
+        assert!(annotations.records[0]
+            .starts_with(b"+0\x14\x14\0+0.500000\x14phase_start Calibration\x14\0"));
+        assert!(annotations.records[1].starts_with(b"+1\x14\x14\0+1.250000\x14onset 1.jpg\x14\0"));
+        for record in &annotations.records {
+            assert_eq!(annotations.samples_per_record * 2, record.len());
+        }
+    }
+
+    #[test]
+    fn test_export_session() {
+        let dir = std::env::temp_dir().join(format!("meme_edf_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut eeg = "# rig_id: 1, rig_title: Lab rig\n# schema_version: 3\n\
+                       epoch_us,session_s,TP9,AF7,AF8,TP10,Right AUX,Left AUX\n"
+            .to_string();
+        for i in 0..(EEG_RATE * 3 / 2) {
+            let time = 1_588_413_600_000_000 + i as u64 * 1_000_000 / EEG_RATE as u64;
+            eeg.push_str(&format!("{},0,800.0,801.0,NaN,803.0,,\n", time));
+        }
+        fs::write(dir.join(EEG_FILE), eeg).unwrap();
+        fs::write(
+            dir.join(EVENT_FILE),
+            "epoch_us,session_s,Event,Detail\n1588413600500000,0.5,phase_start,Title\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join(MANIFEST_FILE),
+            "{\"session_id\": 17, \"rig_title\": \"Lab rig\"}",
+        )
+        .unwrap();
+
+        let output = dir.join(EDF_FILE);
+        let export = export_session(&dir, &output).unwrap();
+        let bytes = fs::read(&output).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(2, export.records);
+        assert_eq!(4, export.signals);
+        assert_eq!(1, export.annotations);
+        assert_eq!(b"0       ", &bytes[..8]);
+        assert_eq!(
+            "Startdate 02-MAY-2020 session_17 X Muse_Lab_rig",
+            String::from_utf8_lossy(&bytes[88..168]).trim_end()
+        );
+        assert_eq!(b"02.05.20", &bytes[168..176]);
+        assert_eq!(b"10.00.00", &bytes[176..184]);
+        assert_eq!(b"EDF+C", &bytes[192..197]);
+        assert_eq!(b"TP9             ", &bytes[256..272]);
+        assert_eq!(b"-32767  ", &bytes[856..864]); // Digital minimum of TP9
+        let annotation_samples: usize = String::from_utf8_lossy(&bytes[1368..1376])
+            .trim()
+            .parse()
+            .unwrap();
+        assert_eq!(
+            256 * 6 + 2 * 2 * (4 * EEG_RATE + annotation_samples),
+            bytes.len()
+        );
+        let af8 = 256 * 6 + 2 * 2 * EEG_RATE;
+        assert_eq!(&MISSING_SAMPLE.to_le_bytes(), &bytes[af8..af8 + 2]);
+    }
+}
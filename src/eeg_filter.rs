@@ -0,0 +1,238 @@
+/// Streaming filters applied to raw EEG before anything is derived from it: a mains notch,
+/// a high-pass to remove electrode drift and a low-pass below the Nyquist frequency
+use crate::muse_model::N_EEG_CHANNELS;
+use std::f32::consts::PI;
+
+const SAMPLE_RATE: f32 = 256.0; // Muse raw EEG samples per second
+const NOTCH_Q: f32 = 30.0; // Narrow notch, about 2Hz wide at 50Hz
+const BUTTERWORTH_Q: f32 = 0.707; // Flat pass band for the high-pass and low-pass
+
+/// Cutoff frequencies of the filter chain, None to skip that stage
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilterSettings {
+    pub notch: Option<f32>,     // Mains frequency, Hz
+    pub high_pass: Option<f32>, // Hz, removes drift below this
+    pub low_pass: Option<f32>,  // Hz, must be below SAMPLE_RATE / 2
+}
+
+impl FilterSettings {
+    /// "off" for raw values, "50hz" for Europe/Asia mains, "60hz" for the Americas, or "drift" for
+    /// only the high-pass
+    pub fn preset(name: &str) -> Option<FilterSettings> {
+        let (notch, high_pass, low_pass) = match name.trim().to_lowercase().as_ref() {
+            "off" => (None, None, None),
+            "50hz" => (Some(50.0), Some(1.0), Some(45.0)),
+            "60hz" => (Some(60.0), Some(1.0), Some(45.0)),
+            "drift" => (None, Some(1.0), None),
+            _ => return None,
+        };
+
+        Some(FilterSettings {
+            notch,
+            high_pass,
+            low_pass,
+        })
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        let nyquist = SAMPLE_RATE / 2.0;
+
+        for (name, cutoff) in &[
+            ("Notch", self.notch),
+            ("High-pass", self.high_pass),
+            ("Low-pass", self.low_pass),
+        ] {
+            if let Some(frequency) = cutoff {
+                if *frequency <= 0.0 || *frequency >= nyquist {
+                    return Err(format!(
+                        "{} frequency {}Hz must be between 0 and {}Hz",
+                        name, frequency, nyquist
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Second order IIR section, transposed direct form II so the state carries across packets
+#[derive(Clone, Debug)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    /// Coefficients from the Audio EQ Cookbook (R. Bristow-Johnson), normalised by a0
+    fn new(b: [f32; 3], a: [f32; 3]) -> Self {
+        Self {
+            b0: b[0] / a[0],
+            b1: b[1] / a[0],
+            b2: b[2] / a[0],
+            a1: a[1] / a[0],
+            a2: a[2] / a[0],
+            z1: 0.0,
+            z2: 0.0,
+        }
+    }
+
+    fn notch(frequency: f32, q: f32) -> Self {
+        let (sin, cos) = (2.0 * PI * frequency / SAMPLE_RATE).sin_cos();
+        let alpha = sin / (2.0 * q);
+
+        Biquad::new(
+            [1.0, -2.0 * cos, 1.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn high_pass(frequency: f32, q: f32) -> Self {
+        let (sin, cos) = (2.0 * PI * frequency / SAMPLE_RATE).sin_cos();
+        let alpha = sin / (2.0 * q);
+
+        Biquad::new(
+            [(1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn low_pass(frequency: f32, q: f32) -> Self {
+        let (sin, cos) = (2.0 * PI * frequency / SAMPLE_RATE).sin_cos();
+        let alpha = sin / (2.0 * q);
+
+        Biquad::new(
+            [(1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0],
+            [1.0 + alpha, -2.0 * cos, 1.0 - alpha],
+        )
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+
+        y
+    }
+}
+
+/// A filter chain for each electrode
+pub struct EegFilter {
+    pub settings: FilterSettings,
+    chains: [Vec<Biquad>; N_EEG_CHANNELS],
+}
+
+impl EegFilter {
+    pub fn new(settings: FilterSettings) -> Self {
+        settings.check().expect("EEG filter settings are not valid");
+
+        let mut chain = Vec::new();
+        if let Some(frequency) = settings.notch {
+            chain.push(Biquad::notch(frequency, NOTCH_Q));
+        }
+        if let Some(frequency) = settings.high_pass {
+            chain.push(Biquad::high_pass(frequency, BUTTERWORTH_Q));
+        }
+        if let Some(frequency) = settings.low_pass {
+            chain.push(Biquad::low_pass(frequency, BUTTERWORTH_Q));
+        }
+
+        Self {
+            settings,
+            chains: [chain.clone(), chain.clone(), chain.clone(), chain],
+        }
+    }
+
+    /// Filter the next sample from each electrode. A dropped (NaN) sample stays NaN and does not
+    /// disturb the filter state.
+    pub fn filter(&mut self, channels: &[f32; N_EEG_CHANNELS]) -> [f32; N_EEG_CHANNELS] {
+        let mut filtered = *channels;
+
+        for chan in 0..N_EEG_CHANNELS {
+            if channels[chan].is_nan() {
+                continue;
+            }
+            for biquad in self.chains[chan].iter_mut() {
+                filtered[chan] = biquad.process(filtered[chan]);
+            }
+        }
+
+        filtered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Amplitude of a sine after settling through the filter
+    fn filtered_amplitude(settings: FilterSettings, frequency: f32, offset: f32) -> f32 {
+        let mut filter = EegFilter::new(settings);
+        let mut amplitude: f32 = 0.0;
+
+        for i in 0..(SAMPLE_RATE as usize * 10) {
+            let v = offset + (2.0 * PI * frequency * i as f32 / SAMPLE_RATE).sin();
+            let filtered = filter.filter(&[v, v, v, v]);
+            if i > SAMPLE_RATE as usize * 8 {
+                amplitude = amplitude.max(filtered[0].abs());
+            }
+        }
+
+        amplitude
+    }
+
+    #[test]
+    fn test_notch_removes_mains() {
+        let settings = FilterSettings::preset("50hz").unwrap();
+
+        assert!(filtered_amplitude(settings.clone(), 50.0, 0.0) < 0.05);
+        assert!(filtered_amplitude(settings, 10.0, 0.0) > 0.9);
+    }
+
+    #[test]
+    fn test_high_pass_removes_offset() {
+        let settings = FilterSettings::preset("drift").unwrap();
+
+        assert!(filtered_amplitude(settings, 10.0, 800.0) < 1.1);
+    }
+
+    #[test]
+    fn test_low_pass() {
+        let settings = FilterSettings::preset("60hz").unwrap();
+
+        assert!(filtered_amplitude(settings, 100.0, 0.0) < 0.2);
+    }
+
+    #[test]
+    fn test_off_is_unchanged() {
+        let mut filter = EegFilter::new(FilterSettings::preset("off").unwrap());
+
+        assert_eq!([1.0, 2.0, 3.0, 4.0], filter.filter(&[1.0, 2.0, 3.0, 4.0]));
+    }
+
+    #[test]
+    fn test_dropped_sample_stays_nan() {
+        let mut filter = EegFilter::new(FilterSettings::preset("50hz").unwrap());
+        let filtered = filter.filter(&[1.0, std::f32::NAN, 1.0, 1.0]);
+        let next = filter.filter(&[1.0, 1.0, 1.0, 1.0]);
+
+        assert!(filtered[1].is_nan());
+        assert!(!next[1].is_nan());
+    }
+
+    #[test]
+    fn test_low_pass_above_nyquist() {
+        let settings = FilterSettings {
+            notch: None,
+            high_pass: None,
+            low_pass: Some(200.0),
+        };
+
+        assert!(settings.check().is_err());
+    }
+}
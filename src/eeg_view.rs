@@ -1,4 +1,5 @@
-use crate::muse_model::MuseModel;
+use crate::fit_check::ContactQuality;
+use crate::muse_model::{MuseModel, AF7, AF8, N_EEG_CHANNELS, TP10, TP9};
 use crate::*;
 use core::f32::consts::PI;
 
@@ -64,7 +65,6 @@ const EEG_FREQUENCY_BAND_LABELS: [&str; N_EEG_DERIVED_VALUES] = ["A", "B", "G",
 
 const COLOR_SPIDER_GRAPH: Color = Color::WHITE; // Thin lines marking the axes and outer border
 const COLOR_SPIDER_GRAPH_OUTLINE: Color = COLOR_NOF1_TURQOISE; // Thick line connecting dots of the graph values
-const N_EEG_CHANNELS: usize = 4;
 const N_EEG_DERIVED_VALUES: usize = 5;
 
 const SPIDER_LINE_THICKNESS: f32 = 3.5; // Thickness of the line between points
@@ -75,54 +75,54 @@ const SPIDER_GRAPH_LABEL_OFFSET: Vector = Vector { x: -160., y: -160. }; // Shif
 const FREQUENCY_LABEL_OFFSET: Vector = Vector { x: 0.5, y: -1.5 }; // Shift letters up slightly to center in the circle
 const SPIDER_SCALE: f32 = 150.0; // Make alpha etc larger for display purposes
 
-const IMAGE_SET_SIZE: usize = 10;
-struct ImageSet {
-    _images: [Asset<Image>; IMAGE_SET_SIZE],
-}
-
-fn filename(filename_prefix: &str, i: usize) -> String {
-    let mut filename = String::new();
-    filename.push_str(filename_prefix);
-    filename.push_str(&format!("{}", i));
-    filename.push_str(".png");
-
-    filename
-}
-
-impl ImageSet {
-    fn new(filename_prefix: &str) -> Self {
-        let mut i: usize = 0;
-        let _images: [Asset<Image>; IMAGE_SET_SIZE] = arr![Asset::new(Image::load(filename(filename_prefix, {
-                i = i + 1;
-                i - 1
-            }))); 10];
-
-        Self { _images }
-    }
-
-    fn _draw(&mut self, image_number: usize, window: &mut Window) {
-        self._images[image_number]
-            .execute(|image| {
-                window.draw(
-                    &image
-                        .area()
-                        .with_center((SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 / 2.0)),
-                    Img(&image),
-                );
-                Ok(())
-            })
-            .expect("Can not draw image from ImageSet");
-    }
-}
+const HEAD_CENTER: Vector = Vector {
+    x: SCREEN_SIZE.0 / 2.0,
+    y: SCREEN_SIZE.1 / 2.0 - 50.0,
+};
+const HEAD_RADIUS: f32 = 300.0; // Head outline seen from above, nose up
+const HEAD_OUTLINE_THICKNESS: f32 = 6.0;
+const ELECTRODE_RADIUS: f32 = 40.0;
+const ELECTRODE_LABEL_OFFSET: Vector = Vector { x: 0.0, y: -75.0 }; // Channel name above each electrode
+const ELECTRODE_VARIANCE_OFFSET: Vector = Vector { x: 0.0, y: 70.0 }; // Raw EEG variance below each electrode
+const ELECTRODE_POSITIONS: [Vector; N_EEG_CHANNELS] = [
+    // Relative to HEAD_CENTER, in the same order as the Muse channels
+    Vector {
+        x: -0.95 * HEAD_RADIUS, // TP9, left ear
+        y: 0.2 * HEAD_RADIUS,
+    },
+    Vector {
+        x: -0.45 * HEAD_RADIUS, // AF7, left forehead
+        y: -0.8 * HEAD_RADIUS,
+    },
+    Vector {
+        x: 0.45 * HEAD_RADIUS, // AF8, right forehead
+        y: -0.8 * HEAD_RADIUS,
+    },
+    Vector {
+        x: 0.95 * HEAD_RADIUS, // TP10, right ear
+        y: 0.2 * HEAD_RADIUS,
+    },
+];
+const COLOR_CONTACT_UNKNOWN: Color = Color::WHITE;
+const COLOR_CONTACT_GOOD: Color = Color::GREEN;
+const COLOR_CONTACT_MEDIUM: Color = Color::YELLOW;
+const COLOR_CONTACT_BAD: Color = Color::RED;
 
 pub struct EegViewState {
     touching_forehead_box: LabeledBox,
     blink_box: LabeledBox,
     clench_box: LabeledBox,
+    parse_error_text: StatusText,
+    timing_text: StatusText,
+    artifact_text: StatusText,
+    rig_text: StatusText,
+    fit_rig_text: StatusText,
+    variance_texts: [StatusText; N_EEG_CHANNELS],
+    battery_text: StatusText,
+    forehead_text: StatusText,
+    fit_status_text: StatusText,
     graph_label_images: [Asset<Image>; N_EEG_CHANNELS],
     frequency_label_images: [Asset<Image>; N_EEG_DERIVED_VALUES],
-    _calm_ext: ImageSet,
-    _pos_neg: ImageSet,
     _valence_index: usize,
     _arousal_index: usize,
 }
@@ -217,16 +217,68 @@ impl EegViewState {
                 COLOR_BACKGROUND,
                 COLOR_TEXT,
             ),
+            parse_error_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, 650.),
+                FONT_EEG_LABEL_SIZE,
+                COLOR_TEXT,
+            ),
+            timing_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, 700.),
+                FONT_EEG_LABEL_SIZE,
+                COLOR_TEXT,
+            ),
+            artifact_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, 750.),
+                FONT_EEG_LABEL_SIZE,
+                COLOR_TEXT,
+            ),
+            rig_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, 800.),
+                FONT_EEG_LABEL_SIZE,
+                COLOR_TEXT,
+            ),
+            fit_rig_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, 1000.),
+                FONT_EEG_LABEL_SIZE,
+                COLOR_TEXT,
+            ),
+            variance_texts: [
+                electrode_variance_text(TP9),
+                electrode_variance_text(AF7),
+                electrode_variance_text(AF8),
+                electrode_variance_text(TP10),
+            ],
+            battery_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, 900.),
+                FONT_EEG_LABEL_SIZE,
+                COLOR_TEXT,
+            ),
+            forehead_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, 950.),
+                FONT_EEG_LABEL_SIZE,
+                COLOR_TEXT,
+            ),
+            fit_status_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, 1050.),
+                FONT_MULI_SIZE,
+                COLOR_TITLE,
+            ),
             graph_label_images,
             frequency_label_images,
-            _calm_ext: ImageSet::new("calm_ex"),
-            _pos_neg: ImageSet::new("pos_neg"),
             _valence_index: 5,
             _arousal_index: 5,
         }
     }
 }
 
+fn electrode_variance_text(chan: usize) -> StatusText {
+    StatusText::new(
+        HEAD_CENTER + ELECTRODE_POSITIONS[chan] + ELECTRODE_VARIANCE_OFFSET,
+        FONT_EEG_LABEL_SIZE,
+        COLOR_TEXT,
+    )
+}
+
 /// Render concenctric circules associated with alpha, beta, gamma..
 pub fn draw_view(muse_model: &MuseModel, window: &mut Window, eeg_view_state: &mut EegViewState) {
     match muse_model.display_type {
@@ -234,6 +286,7 @@ pub fn draw_view(muse_model: &MuseModel, window: &mut Window, eeg_view_state: &m
         DisplayType::Dowsiness => draw_drowsiness_view(muse_model, window),
         DisplayType::Emotion => draw_emotion_sun_view(muse_model, window),
         DisplayType::EegValues => draw_eeg_values_view(muse_model, window, eeg_view_state),
+        DisplayType::FitCheck => draw_fit_check_view(muse_model, window, eeg_view_state),
     }
 }
 
@@ -343,6 +396,105 @@ fn draw_eeg_values_view(
     eeg_view_state
         .clench_box
         .draw(muse_model.is_jaw_clench(), window);
+
+    // Messages from the network which could not be understood
+    eeg_view_state
+        .parse_error_text
+        .draw(&format!("{}", muse_model.parse_errors), window);
+
+    // Raw sample rate actually arriving, and any gaps in it
+    eeg_view_state
+        .timing_text
+        .draw(&muse_model.eeg_timing(), window);
+
+    // Share of the session so far left out of valence and arousal
+    eeg_view_state
+        .artifact_text
+        .draw(&format!("{}", muse_model.artifacts), window);
+
+    // Which station this is, as stamped on the logs
+    eeg_view_state
+        .rig_text
+        .draw(&rig::selected().to_string(), window);
+}
+
+/// The four electrodes on a head outline, coloured by contact quality, to help put the headset on well
+pub fn draw_fit_check_view(
+    muse_model: &MuseModel,
+    window: &mut Window,
+    eeg_view_state: &mut EegViewState,
+) {
+    let fit_check = &muse_model.fit_check;
+
+    // Head outline and nose
+    window.draw(
+        &Circle::new(HEAD_CENTER, HEAD_RADIUS + HEAD_OUTLINE_THICKNESS),
+        Col(COLOR_TITLE),
+    );
+    window.draw(
+        &Circle::new(HEAD_CENTER, HEAD_RADIUS),
+        Col(COLOR_BACKGROUND),
+    );
+    let nose_tip = HEAD_CENTER + Vector::new(0.0, -HEAD_RADIUS - 60.0);
+    for side in &[-1.0, 1.0] {
+        let nose_base = HEAD_CENTER + Vector::new(side * 50.0, -HEAD_RADIUS + 5.0);
+        window.draw(
+            &Line::new(nose_base, nose_tip).with_thickness(HEAD_OUTLINE_THICKNESS),
+            Col(COLOR_TITLE),
+        );
+    }
+
+    for chan in 0..N_EEG_CHANNELS {
+        let position = HEAD_CENTER + ELECTRODE_POSITIONS[chan];
+        let color = match fit_check.contact(chan) {
+            ContactQuality::Unknown => COLOR_CONTACT_UNKNOWN,
+            ContactQuality::Good => COLOR_CONTACT_GOOD,
+            ContactQuality::Medium => COLOR_CONTACT_MEDIUM,
+            ContactQuality::Bad => COLOR_CONTACT_BAD,
+        };
+        window.draw(&Circle::new(position, ELECTRODE_RADIUS), Col(color));
+
+        let _result = eeg_view_state.graph_label_images[chan].execute(|image| {
+            window.draw(
+                &image.area().with_center(position + ELECTRODE_LABEL_OFFSET),
+                Img(&image),
+            );
+            Ok(())
+        });
+
+        let variance = match fit_check.raw_variance(chan) {
+            Some(variance) => format!("{:.0} µV²", variance),
+            None => "-".to_string(),
+        };
+        eeg_view_state.variance_texts[chan].draw(&variance, window);
+    }
+
+    let battery = match muse_model.batt {
+        Some(percent) => format!("Battery {}%", percent),
+        None => "Battery -".to_string(),
+    };
+    eeg_view_state.battery_text.draw(&battery, window);
+
+    let forehead = match fit_check.touching_forehead() {
+        Some(true) => "Touching forehead",
+        Some(false) => "Not touching forehead",
+        None => "Forehead -",
+    };
+    eeg_view_state.forehead_text.draw(forehead, window);
+    eeg_view_state
+        .fit_rig_text
+        .draw(&rig::selected().to_string(), window);
+
+    let status = if fit_check.all_good() {
+        format!(
+            "All electrodes good for {} of {} seconds",
+            fit_check.good_duration().as_secs(),
+            fit_check.required.as_secs()
+        )
+    } else {
+        "Adjust the headset until every electrode is green".to_string()
+    };
+    eeg_view_state.fit_status_text.draw(&status, window);
 }
 
 /// Put five circles on screen in a pentagon shape, bouncing outward from the center based on EEG frequency band intensity
@@ -491,6 +643,49 @@ impl LabeledBox {
     }
 }
 
+/// A line of text which changes while the app runs. It is only rendered again when the text changes.
+pub struct StatusText {
+    center: Vector,
+    size: f32,
+    color: Color,
+    font: Asset<Font>,
+    text: String,
+    image: Option<Image>,
+}
+
+impl StatusText {
+    pub fn new(center: Vector, size: f32, color: Color) -> Self {
+        Self {
+            center,
+            size,
+            color,
+            font: Asset::new(Font::load(FONT_MULI)),
+            text: String::new(),
+            image: None,
+        }
+    }
+
+    pub fn draw(&mut self, text: &str, window: &mut Window) {
+        if self.image.is_none() || self.text != text {
+            let style = FontStyle::new(self.size, self.color);
+            let mut rendered: Option<Image> = None;
+            let _result = self.font.execute(|font| {
+                rendered = Some(font.render(text, &style)?);
+                Ok(())
+            });
+
+            if rendered.is_some() {
+                self.image = rendered;
+                self.text = text.to_string();
+            }
+        }
+
+        if let Some(image) = &self.image {
+            window.draw(&image.area().with_center(self.center), Img(image));
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,145 @@
+/// Things which happen during a session, such as a stimulus appearing, with the time they happened.
+/// Stimuli, responses, phase changes and artifacts all go in this one log, in time order.
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use crate::db_session::SampleSender;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use crate::outlet::Outlet;
+use csv::Writer;
+use std::fs::File;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const EVENT_LOG_FILE: &str = "events.csv";
+const REORDER_WINDOW: Duration = Duration::from_secs(2); // Events are held this long, so one found late in buffered EEG such as an artifact is still written in time order
+
+/// Wall clock time, on the same scale as the time of each EEG sample
+pub fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is not set correctly")
+}
+
+type EventRow = (Duration, String, String); // Time, event, detail
+
+/// Events not yet written, in time order. Events with the same time keep the order they came in.
+#[derive(Default)]
+struct PendingEvents {
+    rows: Vec<EventRow>,
+    latest: Duration, // Latest event time seen
+}
+
+impl PendingEvents {
+    fn push(&mut self, time: Duration, event: &str, detail: &str) {
+        let position = match self.rows.iter().rposition(|row| row.0 <= time) {
+            Some(i) => i + 1,
+            None => 0,
+        };
+
+        self.rows
+            .insert(position, (time, event.to_string(), detail.to_string()));
+        self.latest = self.latest.max(time);
+    }
+
+    /// Remove the events which no event still to come is expected to be earlier than
+    fn take_settled(&mut self) -> Vec<EventRow> {
+        let settled = self
+            .rows
+            .iter()
+            .take_while(|row| row.0 + REORDER_WINDOW <= self.latest)
+            .count();
+
+        self.rows.drain(..settled).collect()
+    }
+
+    fn take_all(&mut self) -> Vec<EventRow> {
+        self.rows.drain(..).collect()
+    }
+}
+
+/// One row per event: the time, what happened, and any details such as the image shown
+pub struct EventLog {
+    writer: Writer<File>,
+    pending: PendingEvents,
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub outlet: Option<Outlet>, // Each event as a marker to live data clients, if serving
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub sample_sender: Option<SampleSender>, // Each event to the database session, if any
+}
+
+impl EventLog {
+    pub fn create(filename: &str) -> Self {
+        let mut writer = crate::create_log_writer(filename);
+        writer
+            .write_record(&["epoch_us", "session_s", "Event", "Detail"])
+            .expect("Can not write events.csv header");
+
+        Self {
+            writer,
+            pending: PendingEvents::default(),
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            outlet: None,
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            sample_sender: None,
+        }
+    }
+
+    pub fn log(&mut self, time: Duration, event: &str, detail: &str) {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            if let Some(outlet) = &self.outlet {
+                outlet.marker(time, event, detail);
+            }
+            if let Some(sample_sender) = &self.sample_sender {
+                sample_sender.event(time, event, detail);
+            }
+        }
+        self.pending.push(time, event, detail);
+
+        for row in self.pending.take_settled() {
+            self.write(row);
+        }
+    }
+
+    fn write(&mut self, (time, event, detail): EventRow) {
+        let [epoch_us, session_s] = crate::session_log::time_columns(time);
+
+        self.writer
+            .write_record(&[&epoch_us, &session_s, &event, &detail])
+            .expect("Can not add row to events.csv");
+    }
+
+    /// Write all pending events to disk
+    pub fn flush(&mut self) {
+        for row in self.pending.take_all() {
+            self.write(row);
+        }
+        let _r = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_late_events_are_put_in_order() {
+        let mut pending = PendingEvents::default();
+        pending.push(Duration::from_millis(10_000), "phase_start", "Calibration");
+        pending.push(Duration::from_millis(10_000), "onset", "1.jpg");
+        pending.push(Duration::from_millis(9_500), "artifact", "blink");
+        assert!(pending.take_settled().is_empty());
+
+        pending.push(Duration::from_millis(11_600), "offset", "1.jpg");
+        let settled = pending.take_settled();
+        let events: Vec<&str> = settled.iter().map(|row| row.1.as_str()).collect();
+        assert_eq!(vec!["artifact"], events);
+
+        pending.push(Duration::from_millis(12_000), "response", "liked");
+        let events: Vec<String> = pending
+            .take_settled()
+            .into_iter()
+            .chain(pending.take_all())
+            .map(|row| row.1)
+            .collect();
+        assert_eq!(vec!["phase_start", "onset", "offset", "response"], events);
+    }
+}
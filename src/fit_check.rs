@@ -0,0 +1,220 @@
+/// How well the headset is sitting: electrode contact from the horseshoe values, forehead contact
+/// and how much the raw EEG is moving on each electrode
+use crate::muse_model::{MuseMessage, MuseMessageType, N_EEG_CHANNELS};
+use std::collections::VecDeque;
+use std::time::Duration;
+
+const VARIANCE_WINDOW: usize = 256; // Raw EEG samples, one second
+const HORSESHOE_GOOD: f32 = 1.0; // MindMonitor sends 1 = good, 2 = medium, 4 = bad
+const HORSESHOE_MEDIUM: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ContactQuality {
+    Unknown,
+    Good,
+    Medium,
+    Bad,
+}
+
+impl ContactQuality {
+    pub fn from_horseshoe(value: f32) -> Self {
+        if value.is_nan() {
+            ContactQuality::Unknown
+        } else if value <= HORSESHOE_GOOD {
+            ContactQuality::Good
+        } else if value <= HORSESHOE_MEDIUM {
+            ContactQuality::Medium
+        } else {
+            ContactQuality::Bad
+        }
+    }
+}
+
+/// Tracks headset fit, and how long every electrode has had good contact
+pub struct FitCheck {
+    pub required: Duration, // Good contact needed this long before calibration can start
+    horseshoe: Option<[f32; N_EEG_CHANNELS]>,
+    touching_forehead: Option<bool>,
+    raw_window: [VecDeque<f32>; N_EEG_CHANNELS],
+    good_since: Option<Duration>,
+    latest_time: Duration,
+}
+
+impl FitCheck {
+    pub fn new(required: Duration) -> Self {
+        Self {
+            required,
+            horseshoe: None,
+            touching_forehead: None,
+            raw_window: Default::default(),
+            good_since: None,
+            latest_time: Duration::from_secs(0),
+        }
+    }
+
+    /// Update from raw (unfiltered) messages
+    pub fn observe(&mut self, muse_message: &MuseMessage) {
+        self.latest_time = muse_message.time;
+
+        match muse_message.muse_message_type {
+            MuseMessageType::Horseshoe { a, b, c, d } => {
+                self.horseshoe = Some([a, b, c, d]);
+                self.update_good_since();
+            }
+            MuseMessageType::TouchingForehead { touch } => {
+                self.touching_forehead = Some(touch);
+                self.update_good_since();
+            }
+            MuseMessageType::Eeg { channels, .. } => {
+                for chan in 0..N_EEG_CHANNELS {
+                    if channels[chan].is_nan() {
+                        continue;
+                    }
+                    self.raw_window[chan].push_back(channels[chan]);
+                    if self.raw_window[chan].len() > VARIANCE_WINDOW {
+                        self.raw_window[chan].pop_front();
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn update_good_since(&mut self) {
+        if !self.all_good() {
+            self.good_since = None;
+        } else if self.good_since.is_none() {
+            self.good_since = Some(self.latest_time);
+        }
+    }
+
+    pub fn contact(&self, chan: usize) -> ContactQuality {
+        match self.horseshoe {
+            Some(horseshoe) => ContactQuality::from_horseshoe(horseshoe[chan]),
+            None => ContactQuality::Unknown,
+        }
+    }
+
+    /// None until MindMonitor has reported forehead contact
+    pub fn touching_forehead(&self) -> Option<bool> {
+        self.touching_forehead
+    }
+
+    /// Every electrode has good contact, and the headset is not reported off the forehead
+    pub fn all_good(&self) -> bool {
+        (0..N_EEG_CHANNELS).all(|chan| self.contact(chan) == ContactQuality::Good)
+            && self.touching_forehead != Some(false)
+    }
+
+    /// How long every electrode has had good contact
+    pub fn good_duration(&self) -> Duration {
+        match self.good_since {
+            Some(since) if self.latest_time > since => self.latest_time - since,
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    /// Good contact has lasted long enough to start calibration. Always ready if no time is required.
+    pub fn is_ready(&self) -> bool {
+        self.required == Duration::from_secs(0)
+            || (self.good_since.is_some() && self.good_duration() >= self.required)
+    }
+
+    /// Variance of the last second of raw EEG, microVolts squared. Very low suggests a flat,
+    /// disconnected electrode and very high a loose one.
+    pub fn raw_variance(&self, chan: usize) -> Option<f32> {
+        let window = &self.raw_window[chan];
+        if window.len() < 2 {
+            return None;
+        }
+        let mean = window.iter().sum::<f32>() / window.len() as f32;
+        let variance =
+            window.iter().map(|v| (v - mean) * (v - mean)).sum::<f32>() / (window.len() - 1) as f32;
+
+        Some(variance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    fn message(seconds: u64, muse_message_type: MuseMessageType) -> MuseMessage {
+        let ip_address: SocketAddr = "127.0.0.1:34254".parse().unwrap();
+
+        MuseMessage {
+            time: Duration::from_secs(seconds),
+            ip_address,
+            muse_message_type,
+        }
+    }
+
+    fn horseshoe(seconds: u64, d: f32) -> MuseMessage {
+        message(
+            seconds,
+            MuseMessageType::Horseshoe {
+                a: 1.0,
+                b: 1.0,
+                c: 1.0,
+                d,
+            },
+        )
+    }
+
+    #[test]
+    fn test_contact_quality() {
+        assert_eq!(ContactQuality::Good, ContactQuality::from_horseshoe(1.0));
+        assert_eq!(ContactQuality::Medium, ContactQuality::from_horseshoe(2.0));
+        assert_eq!(ContactQuality::Bad, ContactQuality::from_horseshoe(4.0));
+    }
+
+    #[test]
+    fn test_ready_after_good_for_required_time() {
+        let mut fit_check = FitCheck::new(Duration::from_secs(10));
+        assert!(!fit_check.is_ready());
+
+        fit_check.observe(&horseshoe(100, 1.0));
+        fit_check.observe(&horseshoe(105, 1.0));
+        assert_eq!(Duration::from_secs(5), fit_check.good_duration());
+        assert!(!fit_check.is_ready());
+
+        fit_check.observe(&horseshoe(110, 1.0));
+        assert!(fit_check.is_ready());
+    }
+
+    #[test]
+    fn test_bad_contact_restarts_count() {
+        let mut fit_check = FitCheck::new(Duration::from_secs(10));
+        fit_check.observe(&horseshoe(100, 1.0));
+        fit_check.observe(&horseshoe(108, 4.0));
+        assert_eq!(ContactQuality::Bad, fit_check.contact(3));
+        fit_check.observe(&horseshoe(112, 1.0));
+
+        assert!(!fit_check.is_ready());
+        fit_check.observe(&message(
+            113,
+            MuseMessageType::TouchingForehead { touch: false },
+        ));
+        assert!(!fit_check.all_good());
+    }
+
+    #[test]
+    fn test_raw_variance() {
+        let mut fit_check = FitCheck::new(Duration::from_secs(10));
+        assert_eq!(None, fit_check.raw_variance(0));
+
+        for v in &[1.0, 3.0, 1.0, 3.0] {
+            fit_check.observe(&message(
+                0,
+                MuseMessageType::Eeg {
+                    channels: [*v, 0.0, 0.0, std::f32::NAN],
+                    aux: [None, None],
+                },
+            ));
+        }
+
+        assert!((fit_check.raw_variance(0).unwrap() - 4.0 / 3.0).abs() < 0.001);
+        assert_eq!(None, fit_check.raw_variance(3));
+    }
+}
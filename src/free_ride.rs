@@ -0,0 +1,231 @@
+/// A neurofeedback game: steer normalized valence and arousal into a target zone and keep them
+/// there. Time in the zone and closeness to the target add to the score.
+use csv::Writer;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::time::Duration;
+
+pub const FREE_RIDE_LOG_FILE: &str = "free_ride.csv";
+const POINTS_PER_SECOND: f32 = 100.0; // Score for one second exactly on target at normal difficulty
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    /// Distance from the target, in standard deviations of normalized valence and arousal, which counts as in the zone
+    pub fn zone_radius(self) -> f32 {
+        match self {
+            Difficulty::Easy => 1.0,
+            Difficulty::Normal => 0.6,
+            Difficulty::Hard => 0.3,
+        }
+    }
+
+    /// Harder zones are worth more
+    fn score_multiplier(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.5,
+            Difficulty::Normal => 1.0,
+            Difficulty::Hard => 2.0,
+        }
+    }
+}
+
+/// Where the participant should steer to, and how close counts
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct FreeRideSettings {
+    pub target_valence: f32, // Normalized, 0.0 is the calibration mean
+    pub target_arousal: f32,
+    pub difficulty: Difficulty,
+}
+
+impl Default for FreeRideSettings {
+    /// Positive and calm
+    fn default() -> Self {
+        Self {
+            target_valence: 1.0,
+            target_arousal: -1.0,
+            difficulty: Difficulty::Normal,
+        }
+    }
+}
+
+/// Score and time in the zone so far
+pub struct FreeRideGame {
+    pub settings: FreeRideSettings,
+    pub valence: Option<f32>, // Most recent normalized values
+    pub arousal: Option<f32>,
+    pub seconds: f32, // Since the game started
+    pub seconds_in_zone: f32,
+    pub score: f32,
+    last_update: Option<f32>,
+}
+
+impl FreeRideGame {
+    pub fn new(settings: FreeRideSettings) -> Self {
+        Self {
+            settings,
+            valence: None,
+            arousal: None,
+            seconds: 0.0,
+            seconds_in_zone: 0.0,
+            score: 0.0,
+            last_update: None,
+        }
+    }
+
+    /// Add the time since the last update to the score, then take any new normalized values
+    pub fn update(&mut self, now: f32, valence: Option<f32>, arousal: Option<f32>) {
+        let elapsed = match self.last_update {
+            Some(last_update) if now > last_update => now - last_update,
+            _ => 0.0,
+        };
+        self.last_update = Some(now);
+        self.seconds = self.seconds + elapsed;
+
+        if let Some(closeness) = self.closeness() {
+            self.seconds_in_zone = self.seconds_in_zone + elapsed;
+            self.score = self.score
+                + elapsed
+                    * POINTS_PER_SECOND
+                    * closeness
+                    * self.settings.difficulty.score_multiplier();
+        }
+
+        if valence.is_some() {
+            self.valence = valence;
+        }
+        if arousal.is_some() {
+            self.arousal = arousal;
+        }
+    }
+
+    /// Distance from the target, None until both valence and arousal are known
+    pub fn distance(&self) -> Option<f32> {
+        match (self.valence, self.arousal) {
+            (Some(valence), Some(arousal)) => {
+                let dv = valence - self.settings.target_valence;
+                let da = arousal - self.settings.target_arousal;
+
+                Some((dv * dv + da * da).sqrt())
+            }
+            _ => None,
+        }
+    }
+
+    /// 1.0 on target falling to 0.0 at the edge of the zone. None if outside the zone.
+    pub fn closeness(&self) -> Option<f32> {
+        let zone_radius = self.settings.difficulty.zone_radius();
+
+        match self.distance() {
+            Some(distance) if distance <= zone_radius => Some(1.0 - distance / zone_radius),
+            _ => None,
+        }
+    }
+
+    pub fn in_zone(&self) -> bool {
+        self.closeness().is_some()
+    }
+
+    pub fn percent_in_zone(&self) -> f32 {
+        if self.seconds > 0.0 {
+            100.0 * self.seconds_in_zone / self.seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Store the result of this game as one row of the free ride log
+    pub fn write_result(&self, writer: &mut Writer<File>, time: Duration, phase: &str) {
+        let [epoch_us, session_s] = crate::session_log::time_columns(time);
+
+        writer
+            .write_record(&[
+                &epoch_us,
+                &session_s,
+                phase,
+                &format!("{}", self.settings.target_valence),
+                &format!("{}", self.settings.target_arousal),
+                &format!("{:?}", self.settings.difficulty),
+                &format!("{:.1}", self.seconds),
+                &format!("{:.1}", self.seconds_in_zone),
+                &format!("{:.0}", self.score),
+            ])
+            .expect("Can not add row to free_ride.csv");
+    }
+}
+
+impl fmt::Display for FreeRideGame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Score {:.0}, {:.1}s in the zone ({:.0}%)",
+            self.score,
+            self.seconds_in_zone,
+            self.percent_in_zone()
+        )
+    }
+}
+
+/// One row per free ride phase with its settings and result
+pub fn create_result_log(filename: &str) -> Writer<File> {
+    let mut writer = crate::create_log_writer(filename);
+    writer
+        .write_record(&[
+            "epoch_us",
+            "session_s",
+            "Phase",
+            "Target valence",
+            "Target arousal",
+            "Difficulty",
+            "Seconds",
+            "Seconds in zone",
+            "Score",
+        ])
+        .expect("Can not write free_ride.csv header");
+
+    writer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_time_in_zone_and_score() {
+        let mut game = FreeRideGame::new(FreeRideSettings::default());
+        game.update(10.0, Some(1.0), None);
+        game.update(11.0, None, None);
+        assert_eq!(0.0, game.seconds_in_zone);
+        assert_eq!(None, game.distance());
+
+        game.update(12.0, None, Some(-1.0));
+        assert!(game.in_zone());
+        game.update(14.0, None, None);
+        assert_eq!(4.0, game.seconds);
+        assert_eq!(2.0, game.seconds_in_zone);
+        assert_eq!(50.0, game.percent_in_zone());
+        assert!((game.score - 2.0 * POINTS_PER_SECOND).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_difficulty_narrows_zone() {
+        let hard = FreeRideSettings {
+            difficulty: Difficulty::Hard,
+            ..FreeRideSettings::default()
+        };
+        let mut game = FreeRideGame::new(hard);
+        game.update(0.0, Some(1.5), Some(-1.0));
+
+        assert!(!game.in_zone());
+        game.settings.difficulty = Difficulty::Easy;
+        assert!((game.closeness().unwrap() - 0.5).abs() < 0.001);
+    }
+}
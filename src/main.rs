@@ -18,15 +18,24 @@ extern crate mandala_quicksilver;
 extern crate num_traits;
 extern crate quicksilver;
 
-use arr_macro::arr;
+use breathing::{BreathStep, BreathingPacer};
 use csv::Writer;
-use eeg_view::EegViewState;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use db_session::DbSession;
+use eeg_view::{EegViewState, StatusText};
+use event_log::EventLog;
+use free_ride::FreeRideGame;
 use log::{error, info};
-use mandala_quicksilver::{Mandala, MandalaState};
+use mandala_quicksilver::Mandala;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use meme_machine_database::edf;
 use muse_model::{DisplayType, MuseModel};
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use outlet::Outlet;
+use petal_pose::PetalPose;
 use quicksilver::{
     combinators::result,
-    geom::{Line, Rectangle, Shape, Transform, Vector},
+    geom::{Circle, Line, Rectangle, Shape, Vector},
     graphics::{
         Background::Col, Background::Img, Color, Font, FontStyle, Image, Mesh, ShapeRenderer,
     },
@@ -35,16 +44,46 @@ use quicksilver::{
     sound::Sound,
     Future, Result,
 };
+use response::{InputSource, ResponseInput, ResponseRecorder, StimulusResponse};
+use session_log::Manifest;
+use session_summary::SessionSummary;
 use std::fs::File;
+use std::io::Write;
 use std::sync::mpsc::Receiver;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use stimulus::{StimulusEvent, StimulusSequence, StimulusSettings};
+use timeline::{Phase, PhaseKind, PhaseSignals, Protocol, Timeline};
 
+mod artifact;
+mod band_power;
+mod breathing;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod db_session;
+mod eeg_filter;
 mod eeg_view;
+mod event_log;
+mod fit_check;
+mod free_ride;
 mod muse_model;
 
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 mod muse_packet;
 
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod osc_recording;
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+mod outlet;
+
+mod petal_pose;
+mod response;
+mod rig;
+mod sample_timing;
+mod session_log;
+mod session_summary;
+mod stimulus;
+mod timeline;
+
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 const SCREEN_SIZE: (f32, f32) = (1920.0, 1200.0);
 #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
@@ -55,16 +94,16 @@ const MANDALA_SCALE: (f32, f32) = (3.0, 3.0); // Adjust size of Mandala vs scree
 
 const FPS: u64 = 60; // Frames per second
 const UPS: u64 = 60; // Updates per second
-const FRAME_TITLE: u64 = 4 * FPS;
-const FRAME_INTRO: u64 = FRAME_TITLE + 1 * FPS;
-const FRAME_SETTLE: u64 = FRAME_INTRO + 12000 * FPS;
-const FRAME_MEME: u64 = FRAME_SETTLE + 4 * FPS;
 
 const IMAGE_LOGO: &str = "Nof1-logo.png";
 const MANDALA_VALENCE_PETAL_SVG_NAME: &str = "mandala_valence_petal.svg";
 const MANDALA_AROUSAL_PETAL_SVG_NAME: &str = "mandala_arousal_petal.svg";
 /// The visual slew time from current value to newly set value. Keep in mind that the newly set value is already smoothed, so this number should be small to provide consinuous interpolation between new values, not large to provide an additional layer of (less carefully controlled) smoothing filter.
 const MANDALA_TRANSITION_DURATION: f32 = 0.5;
+const MANDALA_BREATH_IN: f32 = 1.0; // Mandala value at the top of each breath
+const MANDALA_BREATH_OUT: f32 = 0.0; // Mandala value at the bottom of each breath
+const MANDALA_VALENCE_PETALS: usize = 12;
+const MANDALA_AROUSAL_PETALS: usize = 20;
 
 const FONT_EXTRA_BOLD: &str = "WorkSans-ExtraBold.ttf";
 const FONT_MULI: &str = "Muli.ttf";
@@ -77,6 +116,8 @@ const SOUND_CLICK: &str = "click.ogg";
 const SOUND_BLAH: &str = "blah.ogg";
 
 const STR_TITLE: &str = "Meme Machine";
+const STR_BREATHING: &str = "Now breathe with the mandala";
+const STR_THANK_YOU: &str = "Thank You";
 const STR_HELP_TEXT: &str = "First relax and watch your mind calm\n\nYou will then be shown some images. Press the left and right images to tell us if they are\nfamiliar and how they make you feel.";
 
 const COLOR_GREY: Color = Color {
@@ -144,6 +185,54 @@ const COLOR_AROUSAL_MANDALA_OPEN: Color = Color {
     b: 0.0,
     a: 1.0,
 };
+const POSE_VALENCE_MANDALA_OPEN: PetalPose = PetalPose {
+    color: COLOR_VALENCE_MANDALA_OPEN,
+    rotate: 90.0,
+    translate: (50.0, 0.0),
+    scale: (1.0, 1.0),
+};
+const POSE_VALENCE_MANDALA_CLOSED: PetalPose = PetalPose {
+    color: COLOR_VALENCE_MANDALA_CLOSED,
+    rotate: 0.0,
+    translate: (0.0, 0.0),
+    scale: (0.1, 1.0),
+};
+const POSE_AROUSAL_MANDALA_OPEN: PetalPose = PetalPose {
+    color: COLOR_AROUSAL_MANDALA_OPEN,
+    rotate: 5.0,
+    translate: (0.0, 0.0),
+    scale: (0.4, 0.8),
+};
+const POSE_AROUSAL_MANDALA_CLOSED: PetalPose = PetalPose {
+    color: COLOR_AROUSAL_MANDALA_CLOSED,
+    rotate: 90.0,
+    translate: (0.0, 0.0),
+    scale: (0.2, 1.0),
+};
+
+const RATING_KEYS: [Key; response::RATING_MAX as usize] =
+    [Key::Key1, Key::Key2, Key::Key3, Key::Key4, Key::Key5];
+
+const FREE_RIDE_PLOT_CENTER: Vector = Vector {
+    x: SCREEN_SIZE.0 - 250.0,
+    y: 250.0,
+};
+const FREE_RIDE_PLOT_SCALE: f32 = 60.0; // Pixels per standard deviation of normalized valence and arousal
+const FREE_RIDE_PLOT_RANGE: f32 = 3.0; // Normalized values beyond this are drawn at the edge of the plot
+const FREE_RIDE_MARKER_RADIUS: f32 = 12.0;
+const COLOR_FREE_RIDE_IN_ZONE: Color = Color::GREEN;
+const COLOR_FREE_RIDE_ZONE: Color = Color {
+    r: 0.0,
+    g: 0.8,
+    b: 0.0,
+    a: 0.4,
+};
+
+const SUMMARY_PLOT: (f32, f32, f32, f32) = (160.0, 880.0, 1600.0, 220.0); // Left, top, width, height of the trajectory on the exit screen
+const SUMMARY_TEXT_LEFT: f32 = 400.0; // Centre of the per-phase averages column
+const SUMMARY_TEXT_TOP: f32 = 200.0;
+const SUMMARY_LINE_HEIGHT: f32 = 45.0;
+const SUMMARY_LINE_THICKNESS: f32 = 3.0;
 
 const BUTTON_WIDTH: f32 = 200.0;
 const BUTTON_HEIGHT: f32 = 50.0;
@@ -180,8 +269,28 @@ pub trait OscSocket: Sized {
 }
 
 struct AppState {
-    frame_count: u64,
     start_time: Instant,
+    timeline: Timeline, // Which phase of the session is on screen, and when it ends
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    db_session: Option<DbSession>, // Database record of this session, if there is a database
+    manifest: Manifest, // What produced the files in the session directory
+    event_log: EventLog, // Phase changes and stimulus onsets and offsets
+    stimuli: Option<StimulusSequence>, // The current image sequence
+    stimulus_images: Vec<Asset<Image>>, // Images of the current sequence, in presentation order
+    stimulus_images_loaded: bool, // Every image is decoded, so the sequence clock may start
+    pending_onset: Option<StimulusEvent>, // Due on screen, logged in the frame the image is first drawn
+    responses: ResponseRecorder,          // What the participant said about each image
+    breathing: Option<BreathingPacer>,    // The breath cycle, during a breathing phase
+    breathing_text: StatusText,
+    breath_prompt_text: StatusText,
+    latest_arousal: Option<f32>, // Most recent normalized arousal, to adapt the breathing pace
+    free_ride: Option<FreeRideGame>, // Score and time in the target zone, during a free ride phase
+    free_ride_phase: String,     // Label of the free ride phase, for the result log
+    free_ride_log: Writer<File>, // Result of each free ride phase, CSV
+    free_ride_text: StatusText,
+    summary: SessionSummary, // Valence and arousal over the session, for the summary card
+    summary_texts: Vec<StatusText>, // Per-phase averages on the exit screen
+    thank_you_text: StatusText,
     title_text: Asset<Image>,
     help_text: Asset<Image>,
     logo: Asset<Image>,
@@ -199,24 +308,324 @@ struct AppState {
 impl AppState {
     // Perform any shutdown actions
     // Do not call this directly to end the app. Instead call window.close();
+    /// Called once as the session ends, however the app is closed
     fn shutdown_hooks(&mut self) -> Result<()> {
-        // TODO Notify database session ended
+        if self.manifest.ended_at.is_some() {
+            return Ok(());
+        }
+        self.flush_all();
+        self.manifest.end(SystemTime::now());
+        self.manifest.save();
+
+        // Before the export, so a session created while closing is still in the EDF header
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            if let Some(db_session) = self.db_session.take() {
+                let session_id = db_session.close(&meme_machine_database::CalibrationStats {
+                    valence_mean: self.muse_model.valence.mean(),
+                    valence_deviation: self.muse_model.valence.deviation(),
+                    arousal_mean: self.muse_model.arousal.mean(),
+                    arousal_deviation: self.muse_model.arousal.deviation(),
+                    rejected_percent: Some(self.muse_model.artifacts.rejected_percent()),
+                });
+                self.set_session_id(session_id);
+            }
+        }
+        self.export_edf();
 
         Ok(())
     }
 
-    fn left_action(&mut self, _window: &mut Window) -> Result<()> {
+    /// Add the database session to the manifest if it was created after startup, so the files can
+    /// still be matched to it. The directory name keeps the startup state.
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    fn set_session_id(&mut self, session_id: Option<i32>) {
+        if self.manifest.session_id.is_none() && session_id.is_some() {
+            info!("Database session {:?} added to the manifest", session_id);
+            self.manifest.session_id = session_id;
+            self.manifest.save();
+        }
+    }
+
+    /// Write the session as EDF+ for EEGLAB, MNE and EDFbrowser
+    fn export_edf(&self) {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            let dir = session_log::dir();
+            let output = dir.join(edf::EDF_FILE);
+            match edf::export_session(&dir, &output) {
+                Ok(export) => info!("Exported {} seconds to {:?}", export.records, output),
+                Err(e) => warn!("Can not export {:?}: {}", output, e),
+            }
+        }
+    }
+
+    /// Called once as each phase begins
+    fn start_phase(&mut self) {
+        let phase = self.timeline.phase().clone();
+        let time = self.muse_model.now();
+        self.event_log.log(time, "phase_start", &phase.label());
+        self.summary.start_phase(&phase.label());
+        self.responses.finish();
+        self.finish_free_ride();
+        self.stimuli = None;
+        self.stimulus_images.clear();
+        self.stimulus_images_loaded = false;
+        self.pending_onset = None;
+        self.breathing = None;
+
+        match phase.kind {
+            PhaseKind::Title | PhaseKind::FitCheck => (),
+            PhaseKind::Instructions => {
+                // PLAY INTRO AUDIO AUTOMATICALLY WHEN THE TEXT APPEARS
+                let _result = self.sound_blah.execute(|sound| sound.play());
+            }
+            PhaseKind::ImageSequence => {
+                self.muse_model.calibration_started = true;
+                self.start_image_sequence(&phase);
+            }
+            PhaseKind::Breathing => {
+                self.muse_model.calibration_started = true;
+                let settings = phase.breathing.clone().unwrap_or_default();
+                info!("Breath cycle: {:?}", settings);
+                self.breathing = Some(BreathingPacer::new(settings));
+            }
+            PhaseKind::FreeRide => {
+                self.muse_model.calibration_started = true;
+                let settings = phase.free_ride.clone().unwrap_or_default();
+                info!("Free ride: {:?}", settings);
+                self.free_ride = Some(FreeRideGame::new(settings));
+                self.free_ride_phase = phase.label();
+            }
+            PhaseKind::Exit => {
+                self.muse_model.calibration_started = true;
+                self.save_summary();
+                self.summary_texts = self
+                    .summary
+                    .phase_average_lines()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, _)| {
+                        StatusText::new(
+                            Vector::new(
+                                SUMMARY_TEXT_LEFT,
+                                SUMMARY_TEXT_TOP + i as f32 * SUMMARY_LINE_HEIGHT,
+                            ),
+                            FONT_EEG_LABEL_SIZE,
+                            COLOR_TEXT,
+                        )
+                    })
+                    .collect();
+            }
+            _ => self.muse_model.calibration_started = true,
+        }
+    }
+
+    /// Load the images of a sequence and decide their order and timing
+    fn start_image_sequence(&mut self, phase: &Phase) {
+        let manifest = phase
+            .manifest
+            .as_ref()
+            .expect("Image sequence phase has no manifest");
+        let images = stimulus::read_manifest(manifest).expect("Can not start the image sequence");
+        let seed = match phase.seed {
+            Some(seed) => seed,
+            None => event_log::now().as_nanos() as u64,
+        };
+        let stimuli =
+            StimulusSequence::new(images, &StimulusSettings::default(), phase.shuffle, seed);
+        info!(
+            "Image sequence {}: {} images, shuffle {}, seed {}",
+            manifest,
+            stimuli.images.len(),
+            phase.shuffle,
+            stimuli.seed
+        );
+        self.event_log.log(
+            self.muse_model.now(),
+            "sequence_start",
+            &format!(
+                "{} shuffle={} seed={}",
+                manifest, phase.shuffle, stimuli.seed
+            ),
+        );
+
+        self.stimulus_images = stimuli
+            .images
+            .iter()
+            .map(|image| Asset::new(Image::load(image.clone())))
+            .collect();
+        self.stimuli = Some(stimuli);
+    }
+
+    /// Open and close the mandala in time with each breath, logging each step of the cycle
+    fn update_breathing(&mut self) {
+        let now = self.seconds_since_start();
+
+        if let Some(breathing) = &mut self.breathing {
+            for boundary in breathing.update(now, self.latest_arousal) {
+                self.event_log.log(
+                    self.muse_model.now(),
+                    "breath",
+                    &format!(
+                        "cycle {}, {}, {:.1}s",
+                        boundary.cycle, boundary.step, boundary.seconds
+                    ),
+                );
+                let target = match boundary.step {
+                    BreathStep::BreatheIn => MANDALA_BREATH_IN,
+                    BreathStep::BreatheOut => MANDALA_BREATH_OUT,
+                    _ => continue,
+                };
+                self.mandala_valence
+                    .start_transition(now, boundary.seconds, target);
+                self.mandala_arousal
+                    .start_transition(now, boundary.seconds, target);
+            }
+        }
+    }
+
+    /// Store the result of a free ride game which has just ended
+    fn finish_free_ride(&mut self) {
+        if let Some(free_ride) = self.free_ride.take() {
+            let time = self.muse_model.now();
+            let label = &self.free_ride_phase;
+            info!("{}: {}", label, free_ride);
+            free_ride.write_result(&mut self.free_ride_log, time, label);
+            self.event_log
+                .log(time, "free_ride_result", &free_ride.to_string());
+        }
+    }
+
+    /// Show the target zone and where valence and arousal are now, with the score so far
+    fn draw_free_ride(&mut self, window: &mut Window) {
+        if let Some(free_ride) = &self.free_ride {
+            let plot_position = |valence: f32, arousal: f32| {
+                let bound = |v: f32| v.max(-FREE_RIDE_PLOT_RANGE).min(FREE_RIDE_PLOT_RANGE);
+
+                FREE_RIDE_PLOT_CENTER
+                    + Vector::new(bound(valence), -bound(arousal)) * FREE_RIDE_PLOT_SCALE
+            };
+            let half_width = FREE_RIDE_PLOT_RANGE * FREE_RIDE_PLOT_SCALE;
+
+            // AXES, VALENCE ACROSS AND AROUSAL UP
+            window.draw(
+                &Line::new(
+                    FREE_RIDE_PLOT_CENTER - Vector::new(half_width, 0.0),
+                    FREE_RIDE_PLOT_CENTER + Vector::new(half_width, 0.0),
+                ),
+                Col(COLOR_TEXT),
+            );
+            window.draw(
+                &Line::new(
+                    FREE_RIDE_PLOT_CENTER - Vector::new(0.0, half_width),
+                    FREE_RIDE_PLOT_CENTER + Vector::new(0.0, half_width),
+                ),
+                Col(COLOR_TEXT),
+            );
+
+            // TARGET ZONE
+            let settings = &free_ride.settings;
+            window.draw(
+                &Circle::new(
+                    plot_position(settings.target_valence, settings.target_arousal),
+                    settings.difficulty.zone_radius() * FREE_RIDE_PLOT_SCALE,
+                ),
+                Col(COLOR_FREE_RIDE_ZONE),
+            );
+
+            // CURRENT VALENCE AND AROUSAL
+            if let (Some(valence), Some(arousal)) = (free_ride.valence, free_ride.arousal) {
+                let marker_color = match free_ride.in_zone() {
+                    true => COLOR_FREE_RIDE_IN_ZONE,
+                    false => COLOR_EMOTION,
+                };
+                window.draw(
+                    &Circle::new(plot_position(valence, arousal), FREE_RIDE_MARKER_RADIUS),
+                    Col(marker_color),
+                );
+            }
+
+            self.free_ride_text.draw(&free_ride.to_string(), window);
+        }
+    }
+
+    /// Write the summary card for the session so far
+    fn save_summary(&mut self) {
+        let path = session_log::path(session_summary::SUMMARY_CARD_FILE);
+        match self.summary.save_svg(&path) {
+            Ok(()) => info!("Summary card saved to {:?}", path),
+            Err(e) => warn!("Can not save summary card: {}", e),
+        }
+    }
+
+    /// Valence and arousal over the session, and the average of each phase
+    fn draw_summary(&mut self, window: &mut Window) {
+        self.thank_you_text.draw(STR_THANK_YOU, window);
+
+        let lines = self.summary.phase_average_lines();
+        for (text, line) in self.summary_texts.iter_mut().zip(lines.iter()) {
+            text.draw(line, window);
+        }
+
+        let (left, top, width, height) = SUMMARY_PLOT;
+        let samples = self.summary.plot_samples();
+        for i in 1..samples.len() {
+            let x0 = session_summary::plot_x(&samples, i - 1, left, width);
+            let x1 = session_summary::plot_x(&samples, i, left, width);
+            for (value, color) in &[
+                (
+                    (samples[i - 1].valence, samples[i].valence),
+                    COLOR_VALENCE_MANDALA_OPEN,
+                ),
+                (
+                    (samples[i - 1].arousal, samples[i].arousal),
+                    COLOR_AROUSAL_MANDALA_OPEN,
+                ),
+            ] {
+                let y0 = session_summary::plot_y(value.0, top, height);
+                let y1 = session_summary::plot_y(value.1, top, height);
+                window.draw(
+                    &Line::new((x0, y0), (x1, y1)).with_thickness(SUMMARY_LINE_THICKNESS),
+                    Col(*color),
+                );
+            }
+        }
+    }
+
+    /// Write any pending logs to disk
+    fn flush_all(&mut self) {
+        self.save_summary();
+        self.finish_free_ride();
+        self.muse_model.flush_all();
+        self.responses.flush();
+        self.event_log.flush();
+    }
+
+    fn left_action(&mut self, _window: &mut Window, source: InputSource) -> Result<()> {
         self.left_button_color = COLOR_BUTTON_PRESSED;
+        self.respond(ResponseInput::Liked(false), source);
         self.sound_click
             .execute(|sound| sound.play())
             .expect("Could not play left button sound");
         Ok(())
     }
 
-    fn right_action(&mut self, _window: &mut Window) -> Result<()> {
+    fn right_action(&mut self, _window: &mut Window, source: InputSource) -> Result<()> {
         self.right_button_color = COLOR_BUTTON_PRESSED;
+        self.respond(ResponseInput::Liked(true), source);
         self.sound_click.execute(|sound| sound.play())
     }
+
+    /// Record an answer about the image on screen, if there is one
+    fn respond(&mut self, input: ResponseInput, source: InputSource) {
+        let time = self.muse_model.now();
+
+        if self.responses.record(input, source, time) {
+            self.event_log
+                .log(time, "response", &format!("{}, {}", input, source));
+        }
+    }
 }
 
 impl AppState {
@@ -224,6 +633,97 @@ impl AppState {
         self.start_time.elapsed().as_nanos() as f32 / 1000000000.0
     }
 
+    /// Advance the image sequence, logging onsets and offsets as close as possible to the frame which shows them
+    fn draw_stimulus(&mut self, window: &mut Window) -> Result<()> {
+        if self.stimuli.is_none() || !self.preload_stimulus_images()? {
+            return Ok(());
+        }
+        let now = self.seconds_since_start();
+
+        if let Some(stimuli) = &mut self.stimuli {
+            for event in stimuli.update(now) {
+                match (event, &self.pending_onset) {
+                    (onset @ StimulusEvent::Onset { .. }, _) => self.pending_onset = Some(onset),
+                    (
+                        StimulusEvent::Offset { index, image },
+                        Some(StimulusEvent::Onset {
+                            index: pending_index,
+                            ..
+                        }),
+                    ) if index == *pending_index => {
+                        // A frame hitch: the image was due and gone again before it was drawn
+                        self.pending_onset = None;
+                        let time = self.muse_model.now();
+                        self.event_log.log(
+                            time,
+                            "stimulus_skipped",
+                            &format!("{} {}", index, image),
+                        );
+                    }
+                    (offset, _) => {
+                        let time = self.muse_model.now();
+                        self.event_log.log(time, offset.name(), &offset.to_string());
+                    }
+                }
+            }
+            if let Some(index) = stimuli.current() {
+                let mut drawn = false;
+                self.stimulus_images[index].execute(|image| {
+                    window.draw(
+                        &image
+                            .area()
+                            .with_center((SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 / 2.0)),
+                        Img(&image),
+                    );
+                    drawn = true;
+                    Ok(())
+                })?;
+                if drawn {
+                    if let Some(onset) = self.pending_onset.take() {
+                        self.log_onset(onset);
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Poll each image of the sequence until all are decoded. True once they are.
+    fn preload_stimulus_images(&mut self) -> Result<bool> {
+        if !self.stimulus_images_loaded {
+            let mut loaded = 0;
+            for asset in &mut self.stimulus_images {
+                asset.execute(|_image| {
+                    loaded = loaded + 1;
+                    Ok(())
+                })?;
+            }
+            self.stimulus_images_loaded = loaded == self.stimulus_images.len();
+        }
+
+        Ok(self.stimulus_images_loaded)
+    }
+
+    /// The image is on screen from this frame
+    fn log_onset(&mut self, onset: StimulusEvent) {
+        let time = self.muse_model.now();
+        self.event_log.log(time, onset.name(), &onset.to_string());
+        if let StimulusEvent::Onset { index, image } = onset {
+            let sequence = self.timeline.phase().label();
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            {
+                if let Some(db_session) = &self.db_session {
+                    db_session
+                        .sample_sender()
+                        .stimulus(time, &sequence, index, &image);
+                }
+            }
+            self.responses
+                .start(StimulusResponse::new(&sequence, index, &image, time));
+        }
+    }
+
     fn draw_mandala(&mut self, window: &mut Window) {
         let mut mesh = Mesh::new();
 
@@ -242,16 +742,42 @@ fn bound_normalized_value(normalized: f32) -> f32 {
     normalized.max(3.0).min(-3.0)
 }
 
-/// Create a log of values and events collected during a session
+/// Create a log of values and events collected during a session, in the session directory. The
+/// first line names the rig and the second gives the layout version.
 fn create_log_writer(filename: &str) -> Writer<File> {
-    let writer: Writer<File> =
-        Writer::from_path(filename).expect("Could not open CSV file for writing");
+    let mut file =
+        File::create(session_log::path(filename)).expect("Could not open CSV file for writing");
+    writeln!(file, "{}", rig::selected().csv_comment()).expect("Could not write CSV rig comment");
+    writeln!(file, "{}", session_log::schema_comment())
+        .expect("Could not write CSV schema version");
+    let writer: Writer<File> = Writer::from_writer(file);
 
     writer
 }
 
 impl State for AppState {
     fn new() -> Result<AppState> {
+        // The session directory is named by the database session, and must exist before any log
+        let protocol = Protocol::from_env();
+        let started_at = SystemTime::now();
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        let mut db_session = DbSession::open(&protocol.version, &rig::selected(), started_at);
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        let session_id = db_session
+            .as_mut()
+            .and_then(|db_session| db_session.session_id());
+        #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+        let session_id = None;
+        session_log::open(started_at, session_id);
+        let manifest = Manifest::new(
+            session_id,
+            started_at,
+            &rig::selected(),
+            &Protocol::file_from_env(),
+            &protocol,
+        );
+        manifest.save();
+
         let title_font = Font::load(FONT_EXTRA_BOLD);
         let help_font = Font::load(FONT_MULI);
         let title_text = Asset::new(title_font.and_then(|font| {
@@ -268,58 +794,74 @@ impl State for AppState {
         let sound_click = Asset::new(Sound::load(SOUND_CLICK));
         let sound_blah = Asset::new(Sound::load(SOUND_BLAH));
         let (rx_eeg, muse_model) = muse_model::MuseModel::new();
-        let mandala_valence_state_open = MandalaState::new(
-            COLOR_VALENCE_MANDALA_OPEN,
-            Transform::rotate(90),
-            Transform::translate((50.0, 0.0)),
-            Transform::scale((1.0, 1.0)),
-        );
-        let mandala_valence_state_closed = MandalaState::new(
-            COLOR_VALENCE_MANDALA_CLOSED,
-            Transform::rotate(0.0),
-            Transform::translate((0.0, 0.0)),
-            Transform::scale((0.1, 1.0)),
-        );
+        session_log::start_clock(muse_model.now());
         let mut mandala_valence = Mandala::new(
             MANDALA_VALENCE_PETAL_SVG_NAME,
             MANDALA_CENTER,
             MANDALA_SCALE,
-            12,
-            mandala_valence_state_open,
-            mandala_valence_state_closed,
+            MANDALA_VALENCE_PETALS,
+            POSE_VALENCE_MANDALA_OPEN.state(),
+            POSE_VALENCE_MANDALA_CLOSED.state(),
             1.0,
         );
-        let mandala_arousal_state_open = MandalaState::new(
-            COLOR_AROUSAL_MANDALA_OPEN,
-            Transform::rotate(5),
-            Transform::translate((0.0, 0.0)),
-            Transform::scale((0.4, 0.8)),
-        );
-        let mandala_arousal_state_closed = MandalaState::new(
-            COLOR_AROUSAL_MANDALA_CLOSED,
-            Transform::rotate(90.0),
-            Transform::translate((0.0, 0.0)),
-            Transform::scale((0.2, 1.0)),
-        );
         let mut mandala_arousal = Mandala::new(
             MANDALA_AROUSAL_PETAL_SVG_NAME,
             MANDALA_CENTER,
             MANDALA_SCALE,
-            20,
-            mandala_arousal_state_open,
-            mandala_arousal_state_closed,
+            MANDALA_AROUSAL_PETALS,
+            POSE_AROUSAL_MANDALA_OPEN.state(),
+            POSE_AROUSAL_MANDALA_CLOSED.state(),
             0.0,
         );
         mandala_valence.start_transition(0.0, 3.0, 0.0);
         mandala_arousal.start_transition(0.0, 3.0, 1.0);
 
         let eeg_view_state = EegViewState::new();
+        let timeline = Timeline::new(protocol, 0.0);
+        let event_log = EventLog::create(event_log::EVENT_LOG_FILE);
+        let responses = ResponseRecorder::create(response::RESPONSE_LOG_FILE);
         let start_time = Instant::now();
         println!("Start instant: {:?}", start_time);
 
-        Ok(AppState {
-            frame_count: 0,
+        let mut app_state = AppState {
             start_time,
+            timeline,
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            db_session,
+            manifest,
+            event_log,
+            stimuli: None,
+            stimulus_images: Vec::new(),
+            stimulus_images_loaded: false,
+            pending_onset: None,
+            responses,
+            breathing: None,
+            breathing_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, TITLE_V_MARGIN),
+                FONT_MULI_SIZE,
+                COLOR_TITLE,
+            ),
+            breath_prompt_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 - TEXT_V_MARGIN),
+                FONT_EXTRA_BOLD_SIZE,
+                COLOR_TITLE,
+            ),
+            latest_arousal: None,
+            free_ride: None,
+            free_ride_phase: String::new(),
+            free_ride_log: free_ride::create_result_log(free_ride::FREE_RIDE_LOG_FILE),
+            free_ride_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, TITLE_V_MARGIN),
+                FONT_MULI_SIZE,
+                COLOR_TITLE,
+            ),
+            summary: SessionSummary::new(),
+            summary_texts: Vec::new(),
+            thank_you_text: StatusText::new(
+                Vector::new(SCREEN_SIZE.0 / 2.0, TITLE_V_MARGIN),
+                FONT_EXTRA_BOLD_SIZE,
+                COLOR_TITLE,
+            ),
             title_text,
             help_text,
             logo,
@@ -332,11 +874,30 @@ impl State for AppState {
             eeg_view_state,
             _rx_eeg: rx_eeg,
             muse_model,
-        })
+        };
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            let sample_sender = app_state
+                .db_session
+                .as_ref()
+                .map(|db_session| db_session.sample_sender());
+            app_state.muse_model.sample_sender = sample_sender.clone();
+            app_state.event_log.sample_sender = sample_sender.clone();
+            app_state.responses.sample_sender = sample_sender;
+            let outlet = Outlet::from_env(&rig::selected(), session_id);
+            app_state.muse_model.outlet = outlet.clone();
+            app_state.event_log.outlet = outlet;
+        }
+        app_state.start_phase();
+
+        Ok(app_state)
     }
 
     // This is called UPS times per second
     fn update(&mut self, window: &mut Window) -> Result<()> {
+        let mut button_pressed = window.keyboard()[Key::Return] == ButtonState::Pressed
+            || window.keyboard()[Key::Space] == ButtonState::Pressed;
+
         // EXIT APP
         #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
         {
@@ -346,51 +907,87 @@ impl State for AppState {
                     .iter()
                     .any(|pad| pad[GamepadButton::FaceLeft].is_down())
             {
-                self.muse_model.flush_all();
+                self.shutdown_hooks()?;
                 window.close();
             }
         }
 
-        // LEFT SHIFT OR GAMEPAD ACTION
-        if window.keyboard()[Key::LShift] == ButtonState::Pressed
-            || window
-                .gamepads()
-                .iter()
-                .any(|pad| pad[GamepadButton::TriggerLeft].is_down())
-            || window
-                .gamepads()
-                .iter()
-                .any(|pad| pad[GamepadButton::ShoulderLeft].is_down())
-        {
-            self.left_action(window)?;
-        }
-
-        // RIGHT SHIFT OR GAMEPAD ACTION
-        if window.keyboard()[Key::RShift] == ButtonState::Pressed
-            || window
-                .gamepads()
-                .iter()
-                .any(|pad| pad[GamepadButton::TriggerRight].is_down())
-            || window
-                .gamepads()
-                .iter()
-                .any(|pad| pad[GamepadButton::ShoulderRight].is_down())
-        {
-            self.right_action(window)?;
+        // LEFT SHIFT
+        if window.keyboard()[Key::LShift] == ButtonState::Pressed {
+            self.left_action(window, InputSource::Keyboard)?;
+            button_pressed = true;
+        }
+
+        // LEFT GAMEPAD ACTION
+        if window.gamepads().iter().any(|pad| {
+            pad[GamepadButton::TriggerLeft] == ButtonState::Pressed
+                || pad[GamepadButton::ShoulderLeft] == ButtonState::Pressed
+        }) {
+            self.left_action(window, InputSource::Gamepad)?;
+            button_pressed = true;
+        }
+
+        // RIGHT SHIFT
+        if window.keyboard()[Key::RShift] == ButtonState::Pressed {
+            self.right_action(window, InputSource::Keyboard)?;
+            button_pressed = true;
+        }
+
+        // RIGHT GAMEPAD ACTION
+        if window.gamepads().iter().any(|pad| {
+            pad[GamepadButton::TriggerRight] == ButtonState::Pressed
+                || pad[GamepadButton::ShoulderRight] == ButtonState::Pressed
+        }) {
+            self.right_action(window, InputSource::Gamepad)?;
+            button_pressed = true;
         }
 
         // LEFT SCREEN BUTTON PRESS
         if window.mouse()[MouseButton::Left] == ButtonState::Pressed
             && RECT_LEFT_BUTTON.contains(window.mouse().pos())
         {
-            self.left_action(window)?;
+            self.left_action(window, InputSource::ScreenButton)?;
+            button_pressed = true;
         }
 
         // RIGHT SCREEN BUTTON PRESS
         if window.mouse()[MouseButton::Left] == ButtonState::Pressed
             && RECT_RIGHT_BUTTON.contains(window.mouse().pos())
         {
-            self.right_action(window)?;
+            self.right_action(window, InputSource::ScreenButton)?;
+            button_pressed = true;
+        }
+
+        // FAMILIAR OR NOT: UP AND DOWN
+        if window.keyboard()[Key::Up] == ButtonState::Pressed {
+            self.respond(ResponseInput::Familiar(true), InputSource::Keyboard);
+        }
+        if window.keyboard()[Key::Down] == ButtonState::Pressed {
+            self.respond(ResponseInput::Familiar(false), InputSource::Keyboard);
+        }
+        if window
+            .gamepads()
+            .iter()
+            .any(|pad| pad[GamepadButton::DpadUp] == ButtonState::Pressed)
+        {
+            self.respond(ResponseInput::Familiar(true), InputSource::Gamepad);
+        }
+        if window
+            .gamepads()
+            .iter()
+            .any(|pad| pad[GamepadButton::DpadDown] == ButtonState::Pressed)
+        {
+            self.respond(ResponseInput::Familiar(false), InputSource::Gamepad);
+        }
+
+        // RATING 1 TO 5
+        for (rating, key) in RATING_KEYS.iter().enumerate() {
+            if window.keyboard()[*key] == ButtonState::Pressed {
+                self.respond(
+                    ResponseInput::Rating(rating as u8 + 1),
+                    InputSource::Keyboard,
+                );
+            }
         }
 
         // TODO NANO SEEED BUTTON PRESS
@@ -415,9 +1012,55 @@ impl State for AppState {
             self.muse_model.display_type = DisplayType::EegValues;
         }
 
+        // F5
+        if window.keyboard()[Key::F5] == ButtonState::Pressed {
+            self.muse_model.display_type = DisplayType::FitCheck;
+        }
+
         let (normalized_valence_option, normalized_arousal_option) =
             self.muse_model.receive_packets();
-        if self.frame_count > FRAME_TITLE {
+        for (time, artifact) in self.muse_model.take_artifacts() {
+            self.event_log.log(time, "artifact", &artifact.to_string());
+        }
+        if let Some(normalized_arousal) = normalized_arousal_option {
+            if normalized_arousal.is_finite() {
+                self.latest_arousal = Some(normalized_arousal);
+            }
+        }
+
+        // NEXT PHASE
+        let signals = PhaseSignals {
+            button: button_pressed,
+            fit_check: self.muse_model.fit_check.is_ready(),
+            calibrated: self.muse_model.is_calibrated(),
+            sequence_done: match &self.stimuli {
+                Some(stimuli) => stimuli.is_finished(),
+                None => false,
+            },
+        };
+        if self.timeline.update(self.seconds_since_start(), &signals) {
+            self.start_phase();
+        }
+        if self.timeline.is_finished() {
+            self.shutdown_hooks()?;
+            window.close();
+        }
+
+        let phase_kind = self.timeline.phase().kind;
+        let now = self.seconds_since_start();
+        self.summary
+            .add(now, normalized_valence_option, normalized_arousal_option);
+        if let Some(free_ride) = &mut self.free_ride {
+            free_ride.update(
+                now,
+                normalized_valence_option.filter(|v| v.is_finite()),
+                normalized_arousal_option.filter(|a| a.is_finite()),
+            );
+        }
+        if phase_kind == PhaseKind::Breathing {
+            // The mandala follows the breath cycle instead of valence and arousal
+            self.update_breathing();
+        } else if phase_kind != PhaseKind::Title {
             let current_time = self.seconds_since_start();
             // println!("Time: {}", current_time);
             if let Some(normalized_valence) = normalized_valence_option {
@@ -444,6 +1087,16 @@ impl State for AppState {
             }
         }
         self.muse_model.count_down();
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            if self.manifest.session_id.is_none() {
+                let session_id = self
+                    .db_session
+                    .as_mut()
+                    .and_then(|db_session| db_session.session_id());
+                self.set_session_id(session_id);
+            }
+        }
 
         Ok(())
     }
@@ -458,97 +1111,104 @@ impl State for AppState {
 
     // This is called FPS times per second
     fn draw(&mut self, window: &mut Window) -> Result<()> {
-        let background_color = match self.frame_count < FRAME_TITLE {
-            true => Color::BLACK,
-            false => COLOR_BACKGROUND,
+        let phase_kind = self.timeline.phase().kind;
+        let background_color = match phase_kind {
+            PhaseKind::Title => Color::BLACK,
+            _ => COLOR_BACKGROUND,
         };
         window.clear(background_color)?;
 
-        if self.frame_count == FRAME_INTRO {
-            // PLAY INTRO AUDIO AUTOMATICALLY WHEN THE TEXT APPEARS
-            let _result = self.sound_blah.execute(|sound| sound.play());
-        }
-
-        if self.frame_count < FRAME_TITLE {
-            self.draw_mandala(window);
-
-            // LOGO
-            self.logo.execute(|image| {
-                window.draw(
-                    &image
-                        .area()
-                        .with_center((SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 / 4.0)),
-                    Img(&image),
-                );
-                Ok(())
-            })?;
-        } else if self.frame_count < FRAME_INTRO {
-            // TITLE
-            self.title_text.execute(|image| {
-                window.draw(
-                    &image
-                        .area()
-                        .with_center((SCREEN_SIZE.0 / 2.0, TITLE_V_MARGIN)),
-                    Img(&image),
-                );
-                Ok(())
-            })?;
-
-            // TEXT
-            self.help_text.execute(|image| {
-                window.draw(
-                    &image
-                        .area()
-                        .with_center((SCREEN_SIZE.0 / 2.0, TEXT_V_MARGIN)),
-                    Img(&image),
-                );
-                Ok(())
-            })?;
-
-        // RIGHT BUTTON
-        // let right_color = self.right_button_color;
-        // self.sound_click.execute(|_| {
-        //     window.draw(&RECT_RIGHT_BUTTON, Col(right_color));
-        //     Ok(())
-        // })?;
-        // self.right_button_color = COLOR_BUTTON;
-        } else if self.frame_count < FRAME_SETTLE {
-            match self.muse_model.display_type {
+        match phase_kind {
+            PhaseKind::Title => {
+                self.draw_mandala(window);
+
+                // LOGO
+                self.logo.execute(|image| {
+                    window.draw(
+                        &image
+                            .area()
+                            .with_center((SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 / 4.0)),
+                        Img(&image),
+                    );
+                    Ok(())
+                })?;
+            }
+            PhaseKind::Instructions => {
+                // TITLE
+                self.title_text.execute(|image| {
+                    window.draw(
+                        &image
+                            .area()
+                            .with_center((SCREEN_SIZE.0 / 2.0, TITLE_V_MARGIN)),
+                        Img(&image),
+                    );
+                    Ok(())
+                })?;
+
+                // TEXT
+                self.help_text.execute(|image| {
+                    window.draw(
+                        &image
+                            .area()
+                            .with_center((SCREEN_SIZE.0 / 2.0, TEXT_V_MARGIN)),
+                        Img(&image),
+                    );
+                    Ok(())
+                })?;
+            }
+            PhaseKind::FitCheck => {
+                eeg_view::draw_fit_check_view(&self.muse_model, window, &mut self.eeg_view_state)
+            }
+            PhaseKind::FreeRide => {
+                self.draw_mandala(window);
+                self.draw_free_ride(window);
+            }
+            PhaseKind::Calibration => match self.muse_model.display_type {
                 DisplayType::Mandala => self.draw_mandala(window),
                 _ => eeg_view::draw_view(&self.muse_model, window, &mut self.eeg_view_state),
+            },
+            PhaseKind::Breathing => {
+                self.draw_mandala(window);
+                self.breathing_text.draw(STR_BREATHING, window);
+                if let Some(breathing) = &self.breathing {
+                    self.breath_prompt_text
+                        .draw(breathing.step().prompt(), window);
+                }
+            }
+            PhaseKind::ImageSequence => {
+                self.draw_stimulus(window)?;
+
+                // LEFT BUTTON
+                let left_color = self.left_button_color;
+                self.sound_click.execute(|_| {
+                    window.draw(&RECT_LEFT_BUTTON, Col(left_color));
+                    Ok(())
+                })?;
+                self.left_button_color = COLOR_BUTTON;
+
+                // RIGHT BUTTON
+                let right_color = self.right_button_color;
+                self.sound_click.execute(|_| {
+                    window.draw(&RECT_RIGHT_BUTTON, Col(right_color));
+                    Ok(())
+                })?;
+                self.right_button_color = COLOR_BUTTON;
+            }
+            PhaseKind::Exit => {
+                self.draw_mandala(window);
+                self.draw_summary(window);
+
+                // LOGO
+                self.logo.execute(|image| {
+                    window.draw(
+                        &image
+                            .area()
+                            .with_center((SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 - TITLE_V_MARGIN)),
+                        Img(&image),
+                    );
+                    Ok(())
+                })?;
             }
-        } else if self.frame_count < FRAME_MEME {
-            // LEFT BUTTON
-            let left_color = self.left_button_color;
-            self.sound_click.execute(|_| {
-                window.draw(&RECT_LEFT_BUTTON, Col(left_color));
-                Ok(())
-            })?;
-            self.left_button_color = COLOR_BUTTON;
-
-            // RIGHT BUTTON
-            let right_color = self.right_button_color;
-            self.sound_click.execute(|_| {
-                window.draw(&RECT_RIGHT_BUTTON, Col(right_color));
-                Ok(())
-            })?;
-            self.right_button_color = COLOR_BUTTON;
-        } else {
-            // LOGO
-            self.logo.execute(|image| {
-                window.draw(
-                    &image
-                        .area()
-                        .with_center((SCREEN_SIZE.0 / 2.0, SCREEN_SIZE.1 / 2.0)),
-                    Img(&image),
-                );
-                Ok(())
-            })?;
-        }
-
-        self.frame_count = self.frame_count + 1;
-        if self.frame_count == std::u64::MAX {
-            self.frame_count = 1;
         }
 
         Ok(())
@@ -574,6 +1234,7 @@ fn main() {
     }
 
     info!("meme_quicksilver start");
+    rig::select();
     let draw_rate: f64 = 1000. / FPS as f64;
     let update_rate: f64 = 1000. / UPS as f64;
 
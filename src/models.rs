@@ -1,16 +1,338 @@
-use super::schema::rigs;
+use super::schema::{
+    band_powers, eeg_samples, events, participants, responses, rigs, sessions, stimuli,
+    valence_arousal,
+};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::time::SystemTime;
 
-#[derive(Queryable)]
+#[derive(Debug, Queryable, Serialize)]
 pub struct Rig {
     pub id: i32,
     pub title: String,
     pub body: String,
     pub active: bool,
+    pub headset_serial: Option<String>, // The hardware in use, so each session can be traced to it
+    pub screen: Option<String>,
+    pub input_devices: Option<String>, // Keyboard, gamepad, touch screen..
+    pub location: Option<String>,
 }
 
-#[derive(Insertable)]
+#[derive(Default, Insertable)]
 #[table_name = "rigs"]
 pub struct NewRig<'a> {
     pub title: &'a str,
     pub body: &'a str,
+    pub headset_serial: Option<&'a str>,
+    pub screen: Option<&'a str>,
+    pub input_devices: Option<&'a str>,
+    pub location: Option<&'a str>,
+}
+
+/// Fields to change on a rig, None leaves a field as it is
+#[derive(AsChangeset, Default)]
+#[table_name = "rigs"]
+pub struct RigChanges<'a> {
+    pub title: Option<&'a str>,
+    pub body: Option<&'a str>,
+    pub active: Option<bool>,
+    pub headset_serial: Option<&'a str>,
+    pub screen: Option<&'a str>,
+    pub input_devices: Option<&'a str>,
+    pub location: Option<&'a str>,
+}
+
+#[derive(Queryable)]
+pub struct Participant {
+    pub id: i32,
+    pub code: String, // Anonymous code, never a name
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "participants"]
+pub struct NewParticipant<'a> {
+    pub code: &'a str,
+}
+
+#[derive(Debug, Queryable)]
+pub struct Session {
+    pub id: i32,
+    pub rig_id: Option<i32>,
+    pub participant_id: Option<i32>,
+    pub started_at: NaiveDateTime,
+    pub ended_at: Option<NaiveDateTime>,
+    pub protocol_version: String,
+    pub valence_mean: Option<f32>,
+    pub valence_deviation: Option<f32>,
+    pub arousal_mean: Option<f32>,
+    pub arousal_deviation: Option<f32>,
+    pub synced_session_id: Option<i32>, // Id in the central database, once copied there by sync
+    pub rejected_percent: Option<f32>,  // Of raw EEG samples, rejected for artifacts
+}
+
+#[derive(Insertable)]
+#[table_name = "sessions"]
+pub struct NewSession<'a> {
+    pub rig_id: Option<i32>,
+    pub participant_id: Option<i32>,
+    pub started_at: NaiveDateTime,
+    pub protocol_version: &'a str,
+}
+
+/// Set when a session closes
+#[derive(AsChangeset)]
+#[table_name = "sessions"]
+pub struct SessionEnd {
+    pub ended_at: Option<NaiveDateTime>,
+    pub valence_mean: Option<f32>,
+    pub valence_deviation: Option<f32>,
+    pub arousal_mean: Option<f32>,
+    pub arousal_deviation: Option<f32>,
+    pub rejected_percent: Option<f32>,
+}
+
+#[derive(Queryable)]
+pub struct Stimulus {
+    pub id: i32,
+    pub session_id: i32,
+    pub sequence: String,
+    pub position: i32,
+    pub image: String,
+    pub onset: NaiveDateTime,
+}
+
+#[derive(Insertable)]
+#[table_name = "stimuli"]
+pub struct NewStimulus {
+    pub session_id: i32,
+    pub sequence: String,
+    pub position: i32,
+    pub image: String,
+    pub onset: NaiveDateTime,
+}
+
+#[derive(Queryable)]
+pub struct Event {
+    pub id: i32,
+    pub session_id: i32,
+    pub time: NaiveDateTime,
+    pub event: String,
+    pub detail: String,
+}
+
+#[derive(Insertable)]
+#[table_name = "events"]
+pub struct NewEvent {
+    pub session_id: i32,
+    pub time: NaiveDateTime,
+    pub event: String,
+    pub detail: String,
+}
+
+#[derive(Queryable)]
+pub struct Response {
+    pub id: i32,
+    pub session_id: i32,
+    pub stimulus_id: Option<i32>,
+    pub familiar: Option<bool>,
+    pub liked: Option<bool>,
+    pub rating: Option<i16>,
+    pub reaction_time_ms: Option<i32>,
+    pub input: Option<String>,
+}
+
+#[derive(Insertable)]
+#[table_name = "responses"]
+pub struct NewResponse {
+    pub session_id: i32,
+    pub stimulus_id: Option<i32>,
+    pub familiar: Option<bool>,
+    pub liked: Option<bool>,
+    pub rating: Option<i16>,
+    pub reaction_time_ms: Option<i32>,
+    pub input: Option<String>,
+}
+
+#[derive(Queryable)]
+pub struct EegSample {
+    pub id: i64,
+    pub session_id: i32,
+    pub time: NaiveDateTime,
+    pub tp9: f32, // NaN if the headset dropped the sample, NULL in SQLite
+    pub af7: f32,
+    pub af8: f32,
+    pub tp10: f32,
+    pub aux_right: Option<f32>,
+    pub aux_left: Option<f32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "eeg_samples"]
+pub struct NewEegSample {
+    pub session_id: i32,
+    pub time: NaiveDateTime,
+    pub tp9: f32,
+    pub af7: f32,
+    pub af8: f32,
+    pub tp10: f32,
+    pub aux_right: Option<f32>,
+    pub aux_left: Option<f32>,
+}
+
+#[derive(Queryable)]
+pub struct BandPower {
+    pub id: i64,
+    pub session_id: i32,
+    pub time: NaiveDateTime,
+    pub band: String, // alpha, beta, gamma, delta or theta
+    pub tp9: f32,
+    pub af7: f32,
+    pub af8: f32,
+    pub tp10: f32,
+}
+
+#[derive(Insertable)]
+#[table_name = "band_powers"]
+pub struct NewBandPower<'a> {
+    pub session_id: i32,
+    pub time: NaiveDateTime,
+    pub band: &'a str,
+    pub tp9: f32,
+    pub af7: f32,
+    pub af8: f32,
+    pub tp10: f32,
+}
+
+#[derive(Queryable)]
+pub struct ValenceArousal {
+    pub id: i64,
+    pub session_id: i32,
+    pub time: NaiveDateTime,
+    pub valence: Option<f32>,
+    pub arousal: Option<f32>,
+    pub normalized_valence: Option<f32>,
+    pub normalized_arousal: Option<f32>,
+}
+
+#[derive(Insertable)]
+#[table_name = "valence_arousal"]
+pub struct NewValenceArousal {
+    pub session_id: i32,
+    pub time: NaiveDateTime,
+    pub valence: Option<f32>,
+    pub arousal: Option<f32>,
+    pub normalized_valence: Option<f32>,
+    pub normalized_arousal: Option<f32>,
+}
+
+/// One raw EEG sample, before it belongs to a stored session
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EegRow {
+    pub time: SystemTime,
+    #[serde(with = "channels_with_nan")]
+    pub channels: [f32; 4], // TP9, AF7, AF8, TP10
+    pub aux: [Option<f32>; 2], // Right, left
+}
+
+/// Power in one band on each electrode
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BandPowerRow {
+    pub time: SystemTime,
+    pub band: String,
+    #[serde(with = "channels_with_nan")]
+    pub channels: [f32; 4],
+}
+
+/// Valence and arousal, absolute and normalized against calibration
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ValenceArousalRow {
+    pub time: SystemTime,
+    pub valence: Option<f32>,
+    pub arousal: Option<f32>,
+    pub normalized_valence: Option<f32>,
+    pub normalized_arousal: Option<f32>,
+}
+
+/// An image shown during a sequence phase
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct StimulusRow {
+    pub onset: SystemTime,
+    pub sequence: String,
+    pub position: i32,
+    pub image: String,
+}
+
+/// Something which happened during the session, as in events.csv
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EventRow {
+    pub time: SystemTime,
+    pub event: String,
+    pub detail: String,
+}
+
+/// What was answered about one stimulus, which is found by its sequence and position
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ResponseRow {
+    pub sequence: String,
+    pub position: i32,
+    pub familiar: Option<bool>,
+    pub liked: Option<bool>,
+    pub rating: Option<i16>,
+    pub reaction_time_ms: Option<i32>,
+    pub input: Option<String>,
+}
+
+/// Rows collected for one bulk insert. Serializable so they can wait on disk while the database
+/// is unreachable.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct SampleBatch {
+    pub eeg: Vec<EegRow>,
+    pub band_powers: Vec<BandPowerRow>,
+    pub valence_arousal: Vec<ValenceArousalRow>,
+    #[serde(default)]
+    pub stimuli: Vec<StimulusRow>, // Not in spool files written before stimuli were stored
+    #[serde(default)]
+    pub events: Vec<EventRow>,
+    #[serde(default)]
+    pub responses: Vec<ResponseRow>,
+}
+
+impl SampleBatch {
+    pub fn len(&self) -> usize {
+        self.eeg.len()
+            + self.band_powers.len()
+            + self.valence_arousal.len()
+            + self.stimuli.len()
+            + self.events.len()
+            + self.responses.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// JSON has no NaN, so a dropped sample is written as null
+mod channels_with_nan {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(channels: &[f32; 4], serializer: S) -> Result<S::Ok, S::Error> {
+        let values: Vec<Option<f32>> = channels
+            .iter()
+            .map(|value| if value.is_nan() { None } else { Some(*value) })
+            .collect();
+
+        values.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[f32; 4], D::Error> {
+        let values: [Option<f32>; 4] = Deserialize::deserialize(deserializer)?;
+        let mut channels = [std::f32::NAN; 4];
+        for (channel, value) in channels.iter_mut().zip(values.iter()) {
+            *channel = value.unwrap_or(std::f32::NAN);
+        }
+
+        Ok(channels)
+    }
 }
@@ -1,5 +1,18 @@
+use crate::artifact::{ArtifactDetector, ArtifactKind, ArtifactThresholds};
+use crate::band_power::{
+    BandPowerCalculator, BandPowerSettings, BandPowers, BandSource, ALPHA, BETA, DELTA, GAMMA,
+    THETA,
+};
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use crate::db_session::SampleSender;
+use crate::eeg_filter::{EegFilter, FilterSettings};
+use crate::fit_check::FitCheck;
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 use crate::muse_packet::*;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use crate::outlet::Outlet;
+use crate::sample_timing::SampleTiming;
+use crate::session_log;
 
 /// Muse data model and associated message handling from muse_packet
 // #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
@@ -11,16 +24,28 @@ use num_traits::float::Float;
 use std::net::SocketAddr;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
-use std::{convert::From, fs::File, time::Duration};
+use std::{convert::From, env, fmt, fs::File, time::Duration};
 
 const FOREHEAD_COUNTDOWN: i32 = 5; // 60th of a second counts
 const BLINK_COUNTDOWN: i32 = 5;
 const CLENCH_COUNTDOWN: i32 = 5;
 const HISTORY_LENGTH: usize = 120; // Used to trunacte ArousalHistory and ValenceHistory length - this is the number of samples in the normalization phase
-const TP9: usize = 0; // Muse measurment array index for first electrode
-const AF7: usize = 1; // Muse measurment array index for second electrode
-const AF8: usize = 2; // Muse measurment array index for third electrode
-const TP10: usize = 3; // Muse measurment array index for fourth electrode
+pub const TP9: usize = 0; // Muse measurment array index for first electrode
+pub const AF7: usize = 1; // Muse measurment array index for second electrode
+pub const AF8: usize = 2; // Muse measurment array index for third electrode
+pub const TP10: usize = 3; // Muse measurment array index for fourth electrode
+pub const N_EEG_CHANNELS: usize = 4;
+pub const AUX_RIGHT: usize = 0; // Aux measurement array index for the optional Right AUX electrode
+pub const AUX_LEFT: usize = 1; // Aux measurement array index for the optional Left AUX electrode
+pub const N_AUX_CHANNELS: usize = 2;
+const ENV_BAND_SOURCE: &str = "MEME_BAND_SOURCE"; // "computed" to calculate alpha, beta.. from raw EEG instead of using MindMonitor's values
+const ENV_BAND_WINDOW: &str = "MEME_BAND_WINDOW"; // Raw EEG samples analysed for each computed band power update
+const ENV_BAND_SEGMENT: &str = "MEME_BAND_SEGMENT"; // FFT length, a power of two; segments overlap by half
+const ENV_FILTER: &str = "MEME_FILTER"; // Raw EEG filter preset: "50hz" (default), "60hz", "drift" or "off"
+const ENV_HIGH_PASS: &str = "MEME_HIGH_PASS"; // Override the preset high-pass cutoff, Hz
+const DEFAULT_FILTER: &str = "50hz";
+const ENV_FIT_CHECK_SECONDS: &str = "MEME_FIT_CHECK_SECONDS"; // Good electrode contact needed before calibration, 0 to skip the fit check
+const DEFAULT_FIT_CHECK_SECONDS: u64 = 10;
 
 /// Make it easier to print out the message receiver object for debug purposes
 // struct ReceiverDebug<T> {
@@ -40,23 +65,161 @@ pub enum DisplayType {
     Dowsiness,
     Emotion,
     EegValues,
+    FitCheck,
 }
 
 #[derive(Clone, Debug)]
 pub enum MuseMessageType {
-    Eeg { a: f32, b: f32, c: f32, d: f32 }, // microVolts
-    Accelerometer { x: f32, y: f32, z: f32 },
-    Gyro { x: f32, y: f32, z: f32 },
-    Alpha { a: f32, b: f32, c: f32, d: f32 }, // microVolts
-    Beta { a: f32, b: f32, c: f32, d: f32 },  // microVolts
-    Gamma { a: f32, b: f32, c: f32, d: f32 }, // microVolts
-    Delta { a: f32, b: f32, c: f32, d: f32 }, // microVolts
-    Theta { a: f32, b: f32, c: f32, d: f32 }, // microVolts
-    Batt { batt: i32 },
-    Horseshoe { a: f32, b: f32, c: f32, d: f32 },
-    TouchingForehead { touch: bool },
-    Blink { blink: bool },
-    JawClench { clench: bool },
+    Eeg {
+        channels: [f32; N_EEG_CHANNELS], // microVolts, NaN if MindMonitor reports a dropped sample
+        aux: [Option<f32>; N_AUX_CHANNELS], // microVolts, None if AUX is not sent
+    },
+    Accelerometer {
+        x: f32,
+        y: f32,
+        z: f32,
+    },
+    Gyro {
+        x: f32,
+        y: f32,
+        z: f32,
+    },
+    Alpha {
+        a: f32,
+        b: f32,
+        c: f32,
+        d: f32,
+    }, // microVolts
+    Beta {
+        a: f32,
+        b: f32,
+        c: f32,
+        d: f32,
+    }, // microVolts
+    Gamma {
+        a: f32,
+        b: f32,
+        c: f32,
+        d: f32,
+    }, // microVolts
+    Delta {
+        a: f32,
+        b: f32,
+        c: f32,
+        d: f32,
+    }, // microVolts
+    Theta {
+        a: f32,
+        b: f32,
+        c: f32,
+        d: f32,
+    }, // microVolts
+    Batt {
+        batt: i32, // Percent charge
+    },
+    Horseshoe {
+        a: f32,
+        b: f32,
+        c: f32,
+        d: f32,
+    },
+    TouchingForehead {
+        touch: bool,
+    },
+    Blink {
+        blink: bool,
+    },
+    JawClench {
+        clench: bool,
+    },
+}
+
+/// Reasons a received OSC message could not be understood as a Muse message
+#[derive(Clone, Debug, PartialEq)]
+pub enum MuseParseError {
+    MissingArgument {
+        index: usize,
+    },
+    WrongType {
+        index: usize,
+        expected: &'static str,
+    },
+    UnknownAddress {
+        address: String,
+    },
+    WrongArity {
+        expected: usize,
+        found: usize,
+    },
+    UnreadableArgument {
+        index: usize, // Not recorded or not readable in an OSC recording, the message is skipped
+    },
+}
+
+impl fmt::Display for MuseParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MuseParseError::MissingArgument { index } => write!(f, "Missing argument {}", index),
+            MuseParseError::WrongType { index, expected } => {
+                write!(f, "Argument {} is not {}", index, expected)
+            }
+            MuseParseError::UnknownAddress { address } => write!(f, "Unknown address {}", address),
+            MuseParseError::WrongArity { expected, found } => {
+                write!(f, "Expected {} arguments, found {}", expected, found)
+            }
+            MuseParseError::UnreadableArgument { index } => {
+                write!(f, "Argument {} can not be read from the recording", index)
+            }
+        }
+    }
+}
+
+/// Number of received messages which could not be parsed, by kind of error
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ParseErrorCounts {
+    pub missing_argument: u64,
+    pub wrong_type: u64,
+    pub unknown_address: u64,
+    pub wrong_arity: u64,
+    pub unreadable_argument: u64,
+}
+
+impl ParseErrorCounts {
+    /// Count one more error, returning the new count for this kind of error
+    pub fn add(&mut self, error: &MuseParseError) -> u64 {
+        let count = match error {
+            MuseParseError::MissingArgument { .. } => &mut self.missing_argument,
+            MuseParseError::WrongType { .. } => &mut self.wrong_type,
+            MuseParseError::UnknownAddress { .. } => &mut self.unknown_address,
+            MuseParseError::WrongArity { .. } => &mut self.wrong_arity,
+            MuseParseError::UnreadableArgument { .. } => &mut self.unreadable_argument,
+        };
+        *count = *count + 1;
+
+        *count
+    }
+
+    pub fn total(&self) -> u64 {
+        self.missing_argument
+            + self.wrong_type
+            + self.unknown_address
+            + self.wrong_arity
+            + self.unreadable_argument
+    }
+}
+
+impl fmt::Display for ParseErrorCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Bad OSC: {} missing, {} type, {} address, {} arity, {} unreadable",
+            self.missing_argument,
+            self.wrong_type,
+            self.unknown_address,
+            self.wrong_arity,
+            self.unreadable_argument
+        )
+    }
 }
 
 pub type TimedMuseMessage = (Duration, MuseMessageType);
@@ -68,69 +231,129 @@ pub struct MuseMessage {
     pub muse_message_type: MuseMessageType,
 }
 
-/// Receive messages of EEG data from some source (OSC or websockets)
-trait EegMessageReceiver {
-    fn new() -> inner_receiver::InnerMessageReceiver;
-    fn receive_packets(&self) -> Vec<MuseMessage>;
+/// Receive messages of EEG data from some source (OSC, websockets or a recording)
+pub trait EegMessageReceiver {
+    fn receive_packets(&mut self) -> Vec<Result<MuseMessage, MuseParseError>>;
+
+    /// Write any pending activity to disk
+    fn flush(&mut self) {}
+
+    /// The time now on the clock of the received messages, so events line up with the EEG
+    fn now(&self) -> Duration {
+        crate::event_log::now()
+    }
 }
 
 /// An OSC USB packet receiver for all platforms except WASM
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 mod inner_receiver {
-    use super::{EegMessageReceiver, MuseMessage};
+    use super::{EegMessageReceiver, MuseMessage, MuseParseError};
+    use crate::osc_recording::{OscRecorder, ReplayMessageReceiver, ReplaySpeed};
     use nannou_osc;
+    use std::env;
+    use std::time::{SystemTime, UNIX_EPOCH};
 
     const OSC_PORT: u16 = 34254;
+    const ENV_RECORD: &str = "MEME_RECORD"; // Save every received OSC packet to this file
+    const ENV_REPLAY: &str = "MEME_REPLAY"; // Play back this recording instead of binding OSC_PORT
+    const ENV_REPLAY_SPEED: &str = "MEME_REPLAY_SPEED"; // "1" for real time, "4" for 4x faster, "step" for one packet per update
+
+    /// Listen to MindMonitor, or play back a previous recording if MEME_REPLAY is set
+    pub fn create_message_receiver() -> Box<dyn EegMessageReceiver> {
+        match env::var(ENV_REPLAY) {
+            Ok(filename) => {
+                let speed = match env::var(ENV_REPLAY_SPEED) {
+                    Ok(speed) => ReplaySpeed::parse(&speed).expect(
+                        "MEME_REPLAY_SPEED should be 'step' or a positive multiple such as '4'",
+                    ),
+                    Err(_) => ReplaySpeed::RealTime,
+                };
+                let replay_receiver = ReplayMessageReceiver::open(&filename, speed)
+                    .expect("Can not read the OSC recording to replay");
+
+                Box::new(replay_receiver)
+            }
+            Err(_) => Box::new(InnerMessageReceiver::new()),
+        }
+    }
 
     pub struct InnerMessageReceiver {
         receiver: nannou_osc::Receiver,
+        recorder: Option<OscRecorder>,
     }
 
-    impl EegMessageReceiver for InnerMessageReceiver {
-        fn new() -> InnerMessageReceiver {
+    impl InnerMessageReceiver {
+        pub fn new() -> InnerMessageReceiver {
             info!("Connecting to EEG");
 
             let receiver = nannou_osc::receiver(OSC_PORT)
                 .expect("Can not bind to port- is another copy of this app already running?");
-
-            InnerMessageReceiver { receiver }
+            let recorder = match env::var(ENV_RECORD) {
+                Ok(filename) => Some(
+                    OscRecorder::create(&filename)
+                        .expect("Can not open the OSC recording file for writing"),
+                ),
+                Err(_) => None,
+            };
+
+            InnerMessageReceiver { receiver, recorder }
         }
+    }
 
+    impl EegMessageReceiver for InnerMessageReceiver {
         /// Receive any pending osc packets.
-        fn receive_packets(&self) -> Vec<MuseMessage> {
+        fn receive_packets(&mut self) -> Vec<Result<MuseMessage, MuseParseError>> {
             let receivables: Vec<(nannou_osc::Packet, std::net::SocketAddr)> =
                 self.receiver.try_iter().collect();
+            let time = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("System clock is not set correctly");
 
-            let mut muse_messages: Vec<MuseMessage> = Vec::new();
+            let mut muse_messages: Vec<Result<MuseMessage, MuseParseError>> = Vec::new();
 
             for (packet, addr) in receivables {
-                let mut additional_messages: Vec<MuseMessage> =
-                    super::parse_muse_packet(addr, &packet);
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.record(time, addr, &packet);
+                }
+                let mut additional_messages: Vec<Result<MuseMessage, MuseParseError>> =
+                    super::parse_muse_packet(time, addr, &packet);
                 muse_messages.append(&mut additional_messages);
             }
 
             muse_messages
         }
+
+        fn flush(&mut self) {
+            if let Some(recorder) = &mut self.recorder {
+                recorder.flush();
+            }
+        }
     }
 }
 
 /// A placeholder structure for WASM to avoid dependency on non-existing package issues
 #[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
 mod inner_receiver {
-    use super::{EegMessageReceiver, MuseMessage};
+    use super::{EegMessageReceiver, MuseMessage, MuseParseError};
+
+    pub fn create_message_receiver() -> Box<dyn EegMessageReceiver> {
+        Box::new(InnerMessageReceiver::new())
+    }
 
     /// TODO Receive messages from the server in the web implementation
     pub struct InnerMessageReceiver {}
 
-    impl EegMessageReceiver for InnerMessageReceiver {
-        fn new() -> InnerMessageReceiver {
+    impl InnerMessageReceiver {
+        pub fn new() -> InnerMessageReceiver {
             info!("PLACEHOLDER: Will be indirectly connecting to EEG");
 
             InnerMessageReceiver {}
         }
+    }
 
+    impl EegMessageReceiver for InnerMessageReceiver {
         /// Receive any pending osc packets.
-        fn receive_packets(&self) -> Vec<MuseMessage> {
+        fn receive_packets(&mut self) -> Vec<Result<MuseMessage, MuseParseError>> {
             Vec::new()
         }
     }
@@ -254,7 +477,7 @@ where
 /// Snapshot of the most recently collected values from Muse EEG headset
 pub struct MuseModel {
     most_recent_message_receive_time: Duration,
-    pub inner_receiver: inner_receiver::InnerMessageReceiver,
+    pub inner_receiver: Box<dyn EegMessageReceiver>,
     tx_eeg: Sender<TimedMuseMessage>,
     accelerometer: [f32; 3],
     gyro: [f32; 3],
@@ -263,7 +486,11 @@ pub struct MuseModel {
     pub gamma: [f32; 4],
     pub delta: [f32; 4],
     pub theta: [f32; 4],
-    batt: i32,
+    pub eeg: [f32; N_EEG_CHANNELS], // Most recent filtered sample, NaN if it was dropped
+    pub eeg_raw: [f32; N_EEG_CHANNELS], // Most recent sample before filtering
+    pub eeg_aux: [Option<f32>; N_AUX_CHANNELS],
+    pub dropped_eeg_samples: [u64; N_EEG_CHANNELS], // Count of NaN samples on each electrode
+    pub batt: Option<i32>, // Percent charge, None until the headset reports it
     horseshoe: [f32; 4],
     blink_countdown: i32,
     touching_forehead_countdown: i32,
@@ -272,13 +499,78 @@ pub struct MuseModel {
     pub display_type: DisplayType,
     pub arousal: NormalizedValue<f32>,
     pub valence: NormalizedValue<f32>,
+    pub parse_errors: ParseErrorCounts, // Received messages which were not understood and skipped
+    pub sample_timing: SampleTiming, // When each sample was taken, gaps and effective sample rates
+    pub band_source: BandSource, // Whether alpha, beta.. come from MindMonitor or are computed here
+    band_power_calculator: BandPowerCalculator,
+    pub eeg_filter: EegFilter, // Notch, high-pass and low-pass applied to raw EEG before use
+    pub fit_check: FitCheck, // Electrode contact and raw EEG variance, to know when the headset is on well
+    pub calibration_started: bool, // Valence and arousal history is only collected from the calibration phase on
+    pub artifacts: ArtifactDetector, // Blinks, clenches, motion and poor contact which make the EEG untrustworthy
+    new_artifacts: Vec<(Duration, ArtifactKind)>, // Found since the last take_artifacts(), with their time
+    pub band_powers: Option<BandPowers>, // Most recent computed absolute and relative band power
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub sample_sender: Option<SampleSender>, // Raw EEG, band powers, valence and arousal to the database session, if any
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub outlet: Option<Outlet>, // Raw EEG, band powers, valence and arousal to live data clients, if serving
     eeg_log_writer: Writer<File>, // Raw EEG values every time they arrive, CSV
+    eeg_filtered_log_writer: Writer<File>, // EEG values after the filter chain, CSV
     alpha_log_writer: Writer<File>, // Processed EEG values every time they arrive, CSV
     beta_log_writer: Writer<File>, // Processed EEG values every time they arrive, CSV
     gamma_log_writer: Writer<File>, // Processed EEG values every time they arrive, CSV
     delta_log_writer: Writer<File>, // Processed EEG values every time they arrive, CSV
     theta_log_writer: Writer<File>, // Processed EEG values every time they arrive, CSV
-    other_log_writer: Writer<File>, // Other values every time they arrive, CSV
+    accelerometer_log: SensorLog, // Head motion in g, CSV
+    gyro_log: SensorLog,          // Head rotation in degrees per second, CSV
+    horseshoe_log: SensorLog,     // Electrode fit: 1 good, 2 medium, 4 bad, CSV
+    battery_log: SensorLog,       // Percent charge, CSV
+    blink_log: SensorLog,         // 1 when MindMonitor reports a blink, CSV
+    jaw_clench_log: SensorLog,    // 1 when MindMonitor reports a jaw clench, CSV
+    touching_forehead_log: SensorLog, // 1 while the headband touches the forehead, 0 when it does not, CSV
+}
+
+/// One CSV log of a single kind of headset reading, each row starting with its time
+struct SensorLog {
+    filename: &'static str,
+    writer: Writer<File>,
+}
+
+impl SensorLog {
+    fn create(filename: &'static str, columns: &[&str]) -> Self {
+        let mut writer = crate::create_log_writer(filename);
+        let header: Vec<&str> = ["epoch_us", "session_s"]
+            .iter()
+            .chain(columns)
+            .cloned()
+            .collect();
+        writer
+            .write_record(&header)
+            .unwrap_or_else(|e| panic!("Can not write {} header: {}", filename, e));
+
+        Self { filename, writer }
+    }
+
+    fn log(&mut self, time: Duration, values: &[String]) {
+        let [epoch_us, session_s] = session_log::time_columns(time);
+        let record: Vec<&str> = [epoch_us.as_str(), session_s.as_str()]
+            .iter()
+            .cloned()
+            .chain(values.iter().map(|value| value.as_str()))
+            .collect();
+
+        self.writer
+            .write_record(&record)
+            .unwrap_or_else(|e| panic!("Can not add row to {}: {}", self.filename, e));
+    }
+
+    fn flush(&mut self) {
+        let _r = self.writer.flush();
+    }
+}
+
+/// 1 for true and 0 for false, so flags load as numbers
+fn flag(value: bool) -> String {
+    if value { "1" } else { "0" }.to_string()
 }
 
 fn std_deviation<T>(data: &Vec<T>, mean: Option<T>) -> Option<T>
@@ -336,41 +628,160 @@ pub fn average_from_front_electrodes(x: &[f32; 4]) -> f32 {
     (x[1] + x[2]) / 2.0
 }
 
+/// Default band power settings, with the window and FFT lengths optionally set from the environment
+fn band_power_settings_from_env() -> BandPowerSettings {
+    let mut settings = BandPowerSettings::default();
+
+    if let Ok(window) = env::var(ENV_BAND_WINDOW) {
+        settings.window_length = window
+            .parse()
+            .expect("MEME_BAND_WINDOW should be a number of samples");
+    }
+    if let Ok(segment) = env::var(ENV_BAND_SEGMENT) {
+        settings.segment_length = segment
+            .parse()
+            .expect("MEME_BAND_SEGMENT should be a power of two such as 256");
+        settings.segment_overlap = settings.segment_length / 2;
+    }
+
+    settings
+}
+
+/// Filter preset from the environment, optionally with a different high-pass cutoff
+fn filter_settings_from_env() -> FilterSettings {
+    let preset = env::var(ENV_FILTER).unwrap_or(DEFAULT_FILTER.to_string());
+    let mut settings = FilterSettings::preset(&preset)
+        .expect("MEME_FILTER should be '50hz', '60hz', 'drift' or 'off'");
+
+    if let Ok(high_pass) = env::var(ENV_HIGH_PASS) {
+        settings.high_pass = Some(
+            high_pass
+                .parse()
+                .expect("MEME_HIGH_PASS should be a frequency in Hz such as 0.5"),
+        );
+    }
+
+    settings
+}
+
 impl MuseModel {
     /// Create a new model for storing received values
     pub fn new() -> (Receiver<TimedMuseMessage>, MuseModel) {
         let (tx_eeg, rx_eeg): (Sender<TimedMuseMessage>, Receiver<TimedMuseMessage>) =
             mpsc::channel();
 
-        let inner_receiver = inner_receiver::InnerMessageReceiver::new();
+        let inner_receiver = inner_receiver::create_message_receiver();
+        let band_source = match env::var(ENV_BAND_SOURCE) {
+            Ok(name) => BandSource::parse(&name)
+                .expect("MEME_BAND_SOURCE should be 'mindmonitor' or 'computed'"),
+            Err(_) => BandSource::MindMonitor,
+        };
+        let band_power_calculator = BandPowerCalculator::new(band_power_settings_from_env());
+        let eeg_filter = EegFilter::new(filter_settings_from_env());
+        let fit_check_seconds = match env::var(ENV_FIT_CHECK_SECONDS) {
+            Ok(seconds) => seconds
+                .parse()
+                .expect("MEME_FIT_CHECK_SECONDS should be a whole number of seconds"),
+            Err(_) => DEFAULT_FIT_CHECK_SECONDS,
+        };
+        info!("Raw EEG filter: {:?}", eeg_filter.settings);
         let mut eeg_log_writer = crate::create_log_writer("eeg.csv");
         eeg_log_writer
-            .write_record(&["Time", "TP9", "AF7", "AF8", "TP10"])
+            .write_record(&[
+                "epoch_us",
+                "session_s",
+                "TP9",
+                "AF7",
+                "AF8",
+                "TP10",
+                "Right AUX",
+                "Left AUX",
+            ])
             .expect("Can not write EEG");
+        let mut eeg_filtered_log_writer = crate::create_log_writer("eeg_filtered.csv");
+        eeg_filtered_log_writer
+            .write_record(&[
+                "epoch_us",
+                "session_s",
+                "TP9",
+                "AF7",
+                "AF8",
+                "TP10",
+                "Right AUX",
+                "Left AUX",
+            ])
+            .expect("Can not write eeg_filtered.csv header");
         let mut alpha_log_writer = crate::create_log_writer("alpha.csv");
         alpha_log_writer
-            .write_record(&["Time", "Alpha TP9", "Alpha AF7", "Alpha AF8", "Alpha TP10"])
+            .write_record(&[
+                "epoch_us",
+                "session_s",
+                "Alpha TP9",
+                "Alpha AF7",
+                "Alpha AF8",
+                "Alpha TP10",
+            ])
             .expect("Can not write alpha.csv header");
         let mut beta_log_writer = crate::create_log_writer("beta.csv");
         beta_log_writer
-            .write_record(&["Time", "Beta TP9", "Beta AF7", "Beta AF8", "Beta TP10"])
+            .write_record(&[
+                "epoch_us",
+                "session_s",
+                "Beta TP9",
+                "Beta AF7",
+                "Beta AF8",
+                "Beta TP10",
+            ])
             .expect("Can not write beta.csv header");
         let mut gamma_log_writer = crate::create_log_writer("gamma.csv");
         gamma_log_writer
-            .write_record(&["Time", "Gamma TP9", "Gamma AF7", "Gamma AF8", "Gamma TP10"])
+            .write_record(&[
+                "epoch_us",
+                "session_s",
+                "Gamma TP9",
+                "Gamma AF7",
+                "Gamma AF8",
+                "Gamma TP10",
+            ])
             .expect("Can not write gamma.csv header");
         let mut delta_log_writer = crate::create_log_writer("delta.csv");
         delta_log_writer
-            .write_record(&["Time", "Delta TP9", "Delta AF7", "Delta AF8", "Delta TP10"])
+            .write_record(&[
+                "epoch_us",
+                "session_s",
+                "Delta TP9",
+                "Delta AF7",
+                "Delta AF8",
+                "Delta TP10",
+            ])
             .expect("Can not write delta.csv header");
         let mut theta_log_writer = crate::create_log_writer("theta.csv");
         theta_log_writer
-            .write_record(&["Time", "Theta TP9", "Theta AF7", "Theta AF8", "Theta TP10"])
+            .write_record(&[
+                "epoch_us",
+                "session_s",
+                "Theta TP9",
+                "Theta AF7",
+                "Theta AF8",
+                "Theta TP10",
+            ])
             .expect("Can not write theta.csv header");
-        let mut other_log_writer = crate::create_log_writer("other.csv");
-        other_log_writer
-            .write_record(&["Time", "Record"])
-            .expect("Can not write other.csv header");
+        let accelerometer_log = SensorLog::create("accelerometer.csv", &["x_g", "y_g", "z_g"]);
+        let gyro_log = SensorLog::create("gyro.csv", &["x_deg_s", "y_deg_s", "z_deg_s"]);
+        let horseshoe_log = SensorLog::create(
+            "horseshoe.csv",
+            &[
+                "Horseshoe TP9",
+                "Horseshoe AF7",
+                "Horseshoe AF8",
+                "Horseshoe TP10",
+            ],
+        );
+        let battery_log = SensorLog::create("battery.csv", &["percent"]);
+        let blink_log = SensorLog::create("blink.csv", &["blink"]);
+        let jaw_clench_log = SensorLog::create("jaw_clench.csv", &["jaw_clench"]);
+        let touching_forehead_log =
+            SensorLog::create("touching_forehead.csv", &["touching_forehead"]);
 
         (
             rx_eeg,
@@ -385,7 +796,11 @@ impl MuseModel {
                 gamma: [0.0, 0.0, 0.0, 0.0], // 30-44Hz
                 delta: [0.0, 0.0, 0.0, 0.0], // 1-4Hz
                 theta: [0.0, 0.0, 0.0, 0.0], // 4-8Hz
-                batt: 0,
+                eeg: [0.0; N_EEG_CHANNELS],
+                eeg_raw: [0.0; N_EEG_CHANNELS],
+                eeg_aux: [None; N_AUX_CHANNELS],
+                dropped_eeg_samples: [0; N_EEG_CHANNELS],
+                batt: None,
                 horseshoe: [0.0, 0.0, 0.0, 0.0],
                 blink_countdown: 0,
                 touching_forehead_countdown: 0,
@@ -394,13 +809,34 @@ impl MuseModel {
                 display_type: DisplayType::Mandala, // Current drawing mode
                 arousal: NormalizedValue::new(),
                 valence: NormalizedValue::new(),
+                parse_errors: ParseErrorCounts::default(),
+                sample_timing: SampleTiming::new(),
+                band_source,
+                band_power_calculator,
+                band_powers: None,
+                eeg_filter,
+                fit_check: FitCheck::new(Duration::from_secs(fit_check_seconds)),
+                calibration_started: false,
+                artifacts: ArtifactDetector::new(ArtifactThresholds::default()),
+                new_artifacts: Vec::new(),
+                #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+                sample_sender: None,
+                #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+                outlet: None,
                 eeg_log_writer,
+                eeg_filtered_log_writer,
                 alpha_log_writer,
                 beta_log_writer,
                 gamma_log_writer,
                 delta_log_writer,
                 theta_log_writer,
-                other_log_writer,
+                accelerometer_log,
+                gyro_log,
+                horseshoe_log,
+                battery_log,
+                blink_log,
+                jaw_clench_log,
+                touching_forehead_log,
             },
         )
     }
@@ -412,88 +848,177 @@ impl MuseModel {
         _r = self.gamma_log_writer.flush();
         _r = self.theta_log_writer.flush();
         _r = self.delta_log_writer.flush();
-        _r = self.other_log_writer.flush();
         _r = self.eeg_log_writer.flush();
+        _r = self.eeg_filtered_log_writer.flush();
+        self.accelerometer_log.flush();
+        self.gyro_log.flush();
+        self.horseshoe_log.flush();
+        self.battery_log.flush();
+        self.blink_log.flush();
+        self.jaw_clench_log.flush();
+        self.touching_forehead_log.flush();
+        self.inner_receiver.flush();
+
+        if self.parse_errors.total() > 0 {
+            info!("{}", self.parse_errors);
+        }
+        info!("{}", self.artifacts);
+    }
+
+    /// One line summary of the raw EEG sample rate and timing problems
+    pub fn eeg_timing(&self) -> String {
+        match self.sample_timing.clock("eeg") {
+            Some(clock) => format!("EEG {}", clock),
+            None => "EEG -".to_string(),
+        }
     }
 
     fn log_alpha(&mut self, receive_time: Duration) {
-        let time = format!("{:?}", receive_time);
+        let [epoch_us, session_s] = session_log::time_columns(receive_time);
         let tp9 = format!("{:?}", self.alpha[TP9]);
         let af7 = format!("{:?}", self.alpha[AF7]);
         let af8 = format!("{:?}", self.alpha[AF8]);
         let tp10 = format!("{:?}", self.alpha[TP10]);
 
+        self.store_band(receive_time, "alpha", self.alpha);
         self.alpha_log_writer
-            .write_record(&[&time, &tp9, &af7, &af8, &tp10])
+            .write_record(&[&epoch_us, &session_s, &tp9, &af7, &af8, &tp10])
             .expect("Can not add row to alpha.csv");
     }
 
     fn log_beta(&mut self, receive_time: Duration) {
-        let time = format!("{:?}", receive_time);
+        let [epoch_us, session_s] = session_log::time_columns(receive_time);
         let tp9 = format!("{:?}", self.beta[TP9]);
         let af7 = format!("{:?}", self.beta[AF7]);
         let af8 = format!("{:?}", self.beta[AF8]);
         let tp10 = format!("{:?}", self.beta[TP10]);
 
+        self.store_band(receive_time, "beta", self.beta);
         self.beta_log_writer
-            .write_record(&[&time, &tp9, &af7, &af8, &tp10])
+            .write_record(&[&epoch_us, &session_s, &tp9, &af7, &af8, &tp10])
             .expect("Can not add row to beta.csv");
     }
 
     fn log_gamma(&mut self, receive_time: Duration) {
-        let time = format!("{:?}", receive_time);
+        let [epoch_us, session_s] = session_log::time_columns(receive_time);
         let tp9 = format!("{:?}", self.gamma[TP9]);
         let af7 = format!("{:?}", self.gamma[AF7]);
         let af8 = format!("{:?}", self.gamma[AF8]);
         let tp10 = format!("{:?}", self.gamma[TP10]);
 
+        self.store_band(receive_time, "gamma", self.gamma);
         self.gamma_log_writer
-            .write_record(&[&time, &tp9, &af7, &af8, &tp10])
+            .write_record(&[&epoch_us, &session_s, &tp9, &af7, &af8, &tp10])
             .expect("Can not add row to gamma.csv");
     }
 
     fn log_delta(&mut self, receive_time: Duration) {
-        let time = format!("{:?}", receive_time);
+        let [epoch_us, session_s] = session_log::time_columns(receive_time);
         let tp9 = format!("{:?}", self.delta[TP9]);
         let af7 = format!("{:?}", self.delta[AF7]);
         let af8 = format!("{:?}", self.delta[AF8]);
         let tp10 = format!("{:?}", self.delta[TP10]);
 
+        self.store_band(receive_time, "delta", self.delta);
         self.delta_log_writer
-            .write_record(&[&time, &tp9, &af7, &af8, &tp10])
+            .write_record(&[&epoch_us, &session_s, &tp9, &af7, &af8, &tp10])
             .expect("Can not add row to delta.csv");
     }
 
     fn log_theta(&mut self, receive_time: Duration) {
-        let time = format!("{:?}", receive_time);
+        let [epoch_us, session_s] = session_log::time_columns(receive_time);
         let tp9 = format!("{:?}", self.theta[TP9]);
         let af7 = format!("{:?}", self.theta[AF7]);
         let af8 = format!("{:?}", self.theta[AF8]);
         let tp10 = format!("{:?}", self.theta[TP10]);
 
+        self.store_band(receive_time, "theta", self.theta);
         self.theta_log_writer
-            .write_record(&[&time, &tp9, &af7, &af8, &tp10])
+            .write_record(&[&epoch_us, &session_s, &tp9, &af7, &af8, &tp10])
             .expect("Can not add row to theta.csv");
     }
 
-    fn log_eeg(&mut self, receive_time: Duration, eeg_values: &[f32; 4]) {
-        let time = format!("{:?}", receive_time);
+    /// Dropped samples are written as NaN, and missing AUX channels as an empty value
+    fn log_eeg(
+        &mut self,
+        receive_time: Duration,
+        eeg_values: &[f32; N_EEG_CHANNELS],
+        aux_values: &[Option<f32>; N_AUX_CHANNELS],
+        filtered: bool,
+    ) {
+        let [epoch_us, session_s] = session_log::time_columns(receive_time);
         let tp9 = format!("{:?}", eeg_values[TP9]);
         let af7 = format!("{:?}", eeg_values[AF7]);
         let af8 = format!("{:?}", eeg_values[AF8]);
         let tp10 = format!("{:?}", eeg_values[TP10]);
+        let aux = |value: Option<f32>| match value {
+            Some(v) => format!("{:?}", v),
+            None => String::new(),
+        };
+        let aux_right = aux(aux_values[AUX_RIGHT]);
+        let aux_left = aux(aux_values[AUX_LEFT]);
+
+        let record = [
+            &epoch_us, &session_s, &tp9, &af7, &af8, &tp10, &aux_right, &aux_left,
+        ];
+
+        if filtered {
+            self.eeg_filtered_log_writer
+                .write_record(&record)
+                .expect("Can not add row to eeg_filtered.csv");
+        } else {
+            self.eeg_log_writer
+                .write_record(&record)
+                .expect("Can not add row to eeg.csv");
+            self.store_eeg(receive_time, eeg_values, aux_values);
+        }
+    }
 
-        self.eeg_log_writer
-            .write_record(&[&time, &tp9, &af7, &af8, &tp10])
-            .expect("Can not add row to eeg.csv");
+    /// Send raw EEG to the database session and live data clients, if any
+    fn store_eeg(
+        &self,
+        receive_time: Duration,
+        eeg_values: &[f32; N_EEG_CHANNELS],
+        aux_values: &[Option<f32>; N_AUX_CHANNELS],
+    ) {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            if let Some(sample_sender) = &self.sample_sender {
+                sample_sender.eeg(receive_time, *eeg_values, *aux_values);
+            }
+            if let Some(outlet) = &self.outlet {
+                outlet.eeg(receive_time, *eeg_values, *aux_values);
+            }
+        }
     }
 
-    fn log_other(&mut self, receive_time: Duration, other: &str) {
-        let time = format!("{:?}", receive_time);
+    /// Send one band on each electrode to the database session and live data clients, if any
+    fn store_band(&self, receive_time: Duration, band: &str, values: [f32; 4]) {
+        #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+        {
+            if let Some(sample_sender) = &self.sample_sender {
+                sample_sender.band_power(receive_time, band, values);
+            }
+            if let Some(outlet) = &self.outlet {
+                outlet.band_power(receive_time, band, values);
+            }
+        }
+    }
 
-        self.other_log_writer
-            .write_record(&[&time, other])
-            .expect("Can not add row to other.csv");
+    /// Artifacts found since the last call, for the event log
+    pub fn take_artifacts(&mut self) -> Vec<(Duration, ArtifactKind)> {
+        std::mem::replace(&mut self.new_artifacts, Vec::new())
+    }
+
+    /// The time now on the same clock as the EEG, which is the recorded time during a replay
+    pub fn now(&self) -> Duration {
+        self.inner_receiver.now()
+    }
+
+    /// The valence and arousal history is full, so normalized values are meaningful
+    pub fn is_calibrated(&self) -> bool {
+        self.valence._percent_normalization_complete() >= 1.0
+            && self.arousal._percent_normalization_complete() >= 1.0
     }
 
     /// User has recently clamped their teeth, creating myoelectric interference so interrupting the EEG signal
@@ -527,20 +1052,41 @@ impl MuseModel {
     }
 
     pub fn receive_packets(&mut self) -> (Option<f32>, Option<f32>) {
-        let muse_messages = self.inner_receiver.receive_packets();
+        let muse_message_results = self.inner_receiver.receive_packets();
+        let mut muse_messages = Vec::with_capacity(muse_message_results.len());
         let mut updated_numeric_values = false;
         let mut normalized_valence_option = None;
         let mut normalized_arousal_option = None;
 
+        for muse_message_result in muse_message_results {
+            match muse_message_result {
+                Ok(muse_message) => muse_messages.push(muse_message),
+                Err(parse_error) => self.count_parse_error(&parse_error),
+            }
+        }
+        self.sample_timing.stamp_messages(&mut muse_messages);
+
         for muse_message in muse_messages {
-            updated_numeric_values = updated_numeric_values
-                || self
-                    .handle_muse_message(&muse_message)
-                    .expect("Could not receive OSC message");
+            self.fit_check.observe(&muse_message);
+            let muse_message = self.filter_eeg(muse_message);
+            if let Some(artifact) = self.artifacts.observe(&muse_message) {
+                self.new_artifacts.push((muse_message.time, artifact));
+            }
+            let updated = self
+                .handle_muse_message(&muse_message)
+                .expect("Could not receive OSC message");
+            updated_numeric_values = updated_numeric_values || updated;
             self.most_recent_message_receive_time = muse_message.time;
         }
 
-        if updated_numeric_values {
+        // Contaminated band values are kept out of the calibration history and moving average
+        if updated_numeric_values
+            && self.calibration_started
+            && self.valence_arousal_inputs_finite()
+            && self
+                .artifacts
+                .is_clean(self.most_recent_message_receive_time)
+        {
             let _valence_updated = self.update_valence();
             let _arousal_updated = self.update_arousal();
             let vma = self.valence.moving_average();
@@ -548,17 +1094,74 @@ impl MuseModel {
 
             normalized_valence_option = self.valence.normalize(vma);
             normalized_arousal_option = self.arousal.normalize(ama);
+
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            {
+                if let Some(sample_sender) = &self.sample_sender {
+                    sample_sender.valence_arousal(
+                        self.most_recent_message_receive_time,
+                        Some(self.calc_absolute_valence()),
+                        Some(self.calc_abolute_arousal()),
+                        normalized_valence_option,
+                        normalized_arousal_option,
+                    );
+                }
+                if let Some(outlet) = &self.outlet {
+                    outlet.valence_arousal(
+                        self.most_recent_message_receive_time,
+                        normalized_valence_option,
+                        normalized_arousal_option,
+                    );
+                }
+            }
         }
 
         (normalized_valence_option, normalized_arousal_option)
     }
 
+    /// Log raw EEG, then replace it with the output of the filter chain. Other messages pass unchanged.
+    fn filter_eeg(&mut self, muse_message: MuseMessage) -> MuseMessage {
+        match muse_message.muse_message_type {
+            MuseMessageType::Eeg { channels, aux } => {
+                self.eeg_raw = channels;
+                self.log_eeg(muse_message.time, &channels, &aux, false);
+
+                MuseMessage {
+                    muse_message_type: MuseMessageType::Eeg {
+                        channels: self.eeg_filter.filter(&channels),
+                        aux,
+                    },
+                    ..muse_message
+                }
+            }
+            _ => muse_message,
+        }
+    }
+
+    /// Skip a bad message, logging the first of each kind and then every 100th
+    fn count_parse_error(&mut self, parse_error: &MuseParseError) {
+        let count = self.parse_errors.add(parse_error);
+
+        if count == 1 || count % 100 == 0 {
+            warn!("Skipped OSC message ({} so far): {}", count, parse_error);
+        }
+    }
+
     /// Front assymetry- higher values mean more positive mood
     fn front_assymetry(&self) -> f32 {
         let base = std::f32::consts::E;
         base.powf(self.alpha[AF7] - self.alpha[AF8])
     }
 
+    /// Every band value valence and arousal are calculated from is known. A computed band is NaN
+    /// while its electrode has no window without dropped samples.
+    fn valence_arousal_inputs_finite(&self) -> bool {
+        self.alpha.iter().all(|v| v.is_finite())
+            && [TP9, AF7, AF8]
+                .iter()
+                .all(|&chan| self.theta[chan].is_finite())
+    }
+
     /// Positive-negative balance of emotion
     pub fn calc_absolute_valence(&self) -> f32 {
         self.front_assymetry() / average_from_front_electrodes(&self.theta)
@@ -597,6 +1200,27 @@ impl MuseModel {
     //     assert!(!success.is_err(), "Can not send message to local receiver");
     // }
 
+    /// Keep the latest computed band powers, and use them in place of MindMonitor's if selected
+    fn set_computed_band_powers(&mut self, time: Duration, band_powers: BandPowers) -> bool {
+        let use_computed = self.band_source == BandSource::Computed;
+
+        if use_computed {
+            self.alpha = band_powers.absolute[ALPHA];
+            self.beta = band_powers.absolute[BETA];
+            self.gamma = band_powers.absolute[GAMMA];
+            self.delta = band_powers.absolute[DELTA];
+            self.theta = band_powers.absolute[THETA];
+            self.log_alpha(time);
+            self.log_beta(time);
+            self.log_gamma(time);
+            self.log_delta(time);
+            self.log_theta(time);
+        }
+        self.band_powers = Some(band_powers);
+
+        use_computed
+    }
+
     /// Update state based on an incoming message
     fn handle_muse_message(
         &mut self,
@@ -607,6 +1231,10 @@ impl MuseModel {
         match muse_message.muse_message_type {
             MuseMessageType::Accelerometer { x, y, z } => {
                 self.accelerometer = [x, y, z];
+                self.accelerometer_log.log(
+                    time,
+                    &[format!("{:?}", x), format!("{:?}", y), format!("{:?}", z)],
+                );
                 let _success = self
                     .tx_eeg
                     .send((time, MuseMessageType::Accelerometer { x, y, z }));
@@ -614,22 +1242,43 @@ impl MuseModel {
             }
             MuseMessageType::Gyro { x, y, z } => {
                 self.gyro = [x, y, z];
-                self.log_other(time, &format!("Gyro, {:?}, {:?}, {:?}", x, y, z));
+                self.gyro_log.log(
+                    time,
+                    &[format!("{:?}", x), format!("{:?}", y), format!("{:?}", z)],
+                );
                 // self.send((time, MuseMessageType::Gyro { x, y, z }));
                 Ok(false)
             }
             MuseMessageType::Horseshoe { a, b, c, d } => {
                 self.horseshoe = [a, b, c, d];
-                self.log_other(
-                    time,
-                    &format!("Horseshoe, {:?}, {:?}, {:?}, {:?}", a, b, c, d),
-                );
+                let values: Vec<String> = [a, b, c, d].iter().map(|v| format!("{:?}", v)).collect();
+                self.horseshoe_log.log(time, &values);
                 // self.send((time, MuseMessageType::Horseshoe { a, b, c, d }));
                 Ok(false)
             }
-            MuseMessageType::Eeg { a, b, c, d } => {
-                self.log_eeg(time, &[a, b, c, d]);
-                // self.send((time, MuseMessageType::Eeg { a, b, c, d }));
+            MuseMessageType::Eeg { channels, aux } => {
+                for chan in 0..N_EEG_CHANNELS {
+                    if channels[chan].is_nan() {
+                        self.dropped_eeg_samples[chan] = self.dropped_eeg_samples[chan] + 1;
+                    }
+                }
+                self.eeg = channels;
+                self.eeg_aux = aux;
+                self.log_eeg(time, &channels, &aux, true);
+                // self.send((time, MuseMessageType::Eeg { channels, aux }));
+                match self.band_power_calculator.push(&channels) {
+                    Some(band_powers) => Ok(self.set_computed_band_powers(time, band_powers)),
+                    None => Ok(false),
+                }
+            }
+            MuseMessageType::Alpha { .. }
+            | MuseMessageType::Beta { .. }
+            | MuseMessageType::Gamma { .. }
+            | MuseMessageType::Delta { .. }
+            | MuseMessageType::Theta { .. }
+                if self.band_source == BandSource::Computed =>
+            {
+                // MindMonitor's values are ignored when computing our own
                 Ok(false)
             }
             MuseMessageType::Alpha { a, b, c, d } => {
@@ -666,39 +1315,32 @@ impl MuseModel {
                 Ok(true)
             }
             MuseMessageType::Batt { batt } => {
-                self.batt = batt;
-                self.log_other(time, &format!("Battery, {:?}", batt));
+                self.batt = Some(batt);
+                self.battery_log.log(time, &[batt.to_string()]);
                 // self.send((muse_message.time, MuseMessageType::Batt { batt }));
                 Ok(false)
             }
             MuseMessageType::TouchingForehead { touch } => {
-                let mut i = 0;
-                if touch {
-                    i = 1;
-                } else {
+                if !touch {
                     self.touching_forehead_countdown = FOREHEAD_COUNTDOWN;
                 };
-                self.log_other(time, &format!("Battery, {:?}", i));
+                self.touching_forehead_log.log(time, &[flag(touch)]);
                 //                self.send((time, MuseMessageType::TouchingForehead { touch }));
                 Ok(false)
             }
             MuseMessageType::Blink { blink } => {
-                let mut i = 0;
                 if blink {
                     self.blink_countdown = BLINK_COUNTDOWN;
-                    i = 1;
                 };
-                self.log_other(time, &format!("Blink, {:?}", i));
+                self.blink_log.log(time, &[flag(blink)]);
                 //                self.send((time, MuseMessageType::Blink { blink }));
                 Ok(false)
             }
             MuseMessageType::JawClench { clench } => {
-                let mut i = 0;
                 if clench {
                     self.jaw_clench_countdown = CLENCH_COUNTDOWN;
-                    i = 1;
                 };
-                self.log_other(time, &format!("Clench, {:?}", i));
+                self.jaw_clench_log.log(time, &[flag(clench)]);
                 // self.send((time, MuseMessageType::JawClench { clench }));
                 Ok(false)
             }
@@ -1,246 +1,258 @@
-use crate::muse_model::{MuseMessage, MuseMessageType};
+use crate::muse_model::{
+    MuseMessage, MuseMessageType, MuseParseError, N_AUX_CHANNELS, N_EEG_CHANNELS,
+};
 /// Muse packets are received over an OSC protol USP socket from MindMonitor app
 /// running on Android on the same WIFI
 use log::*;
 use std::net::SocketAddr;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
 #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
 use nannou_osc::*;
 
-pub fn parse_muse_packet(addr: SocketAddr, packet: &Packet) -> Vec<MuseMessage> {
-    let mut raw_messages = Vec::new();
-    let time = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("System clock is not set correctly");
-
-    packet.clone().unfold(&mut raw_messages);
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800; // Seconds from the OSC timetag epoch 1900 to 1970
+pub const OSC_IMMEDIATELY: (u32, u32) = (0, 1); // Timetag for a bundle with no particular time
+
+/// Split a packet into messages stamped with the timetag of the innermost bundle holding each one,
+/// otherwise the time the packet arrived. A message which can not be parsed is returned as an error without affecting
+/// the other messages in the packet.
+pub fn parse_muse_packet(
+    arrival_time: Duration,
+    addr: SocketAddr,
+    packet: &Packet,
+) -> Vec<Result<MuseMessage, MuseParseError>> {
+    let raw_messages = unfold_timed(packet);
     let mut muse_messages = Vec::with_capacity(raw_messages.len());
 
-    for raw_message in raw_messages {
-        if let Some(muse_message_type) = parse_muse_message_type(raw_message) {
-            muse_messages.push(MuseMessage {
+    for (timetag, raw_message) in raw_messages {
+        let time = timetag
+            .and_then(timetag_to_duration)
+            .unwrap_or(arrival_time);
+        muse_messages.push(
+            parse_muse_message_type(raw_message).map(|muse_message_type| MuseMessage {
                 time,
                 ip_address: addr,
                 muse_message_type,
-            });
-        }
+            }),
+        );
     }
 
     muse_messages
 }
 
-pub fn parse_muse_message_type(raw_message: Message) -> Option<MuseMessageType> {
-    let service = raw_message.addr.as_ref();
-    let args = raw_message
-        .clone()
-        .args
-        .expect("Expected value was not sent by Muse");
+/// Every message in a packet with the timetag of the innermost bundle holding it, None for a plain
+/// message. Packet::unfold() would give the messages of nested bundles without their timetags.
+pub fn unfold_timed(packet: &Packet) -> Vec<(Option<(u32, u32)>, Message)> {
+    let mut messages = Vec::new();
+    unfold_into(packet, None, &mut messages);
 
-    match (match service {
-        "/muse/eeg" => {
-            let a = get_float_from_args(0, &args);
-            let b = get_float_from_args(0, &args);
-            let c = get_float_from_args(0, &args);
-            let d = get_float_from_args(0, &args);
+    messages
+}
 
-            Some(MuseMessageType::Eeg { a, b, c, d })
+fn unfold_into(
+    packet: &Packet,
+    timetag: Option<(u32, u32)>,
+    messages: &mut Vec<(Option<(u32, u32)>, Message)>,
+) {
+    match packet {
+        Packet::Message(message) => messages.push((timetag, message.clone())),
+        Packet::Bundle(bundle) => {
+            for inner in &bundle.content {
+                unfold_into(inner, Some(bundle.timetag), messages);
+            }
         }
+    }
+}
 
-        "/muse/acc" => Some(MuseMessageType::Accelerometer {
-            x: get_float_from_args(0, &args),
-            y: get_float_from_args(1, &args),
-            z: get_float_from_args(2, &args),
-        }),
-
-        "/muse/gyro" => Some(MuseMessageType::Gyro {
-            x: get_float_from_args(0, &args),
-            y: get_float_from_args(1, &args),
-            z: get_float_from_args(2, &args),
-        }),
-
-        "/muse/elements/touching_forehead" => Some(MuseMessageType::TouchingForehead {
-            touch: get_int_from_args(0, &args) != 0,
-        }),
-
-        "/muse/elements/horseshoe" => Some(MuseMessageType::Horseshoe {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
-
-        "/muse/elements/alpha_absolute" => Some(MuseMessageType::Alpha {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
-
-        "/muse/elements/beta_absolute" => Some(MuseMessageType::Beta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
-
-        "/muse/elements/gamma_absolute" => Some(MuseMessageType::Gamma {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
-
-        "/muse/elements/delta_absolute" => Some(MuseMessageType::Delta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+/// Time since the UNIX epoch of an OSC timetag, or None if it does not name a time
+fn timetag_to_duration(timetag: (u32, u32)) -> Option<Duration> {
+    let (seconds, fraction) = timetag;
+    let seconds = seconds as u64;
+    if timetag == OSC_IMMEDIATELY || seconds < NTP_UNIX_OFFSET {
+        return None;
+    }
+    let nanos = (fraction as u64 * 1_000_000_000) >> 32;
 
-        "/muse/elements/theta_absolute" => Some(MuseMessageType::Theta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+    Some(Duration::from_secs(seconds - NTP_UNIX_OFFSET) + Duration::from_nanos(nanos))
+}
 
-        "/muse/elements/blink" => {
-            let blink = get_int_from_args(0, &args);
-            info!("Blink: {:#?}", blink);
+pub fn parse_muse_message_type(raw_message: Message) -> Result<MuseMessageType, MuseParseError> {
+    let service: &str = raw_message.addr.as_ref();
+    let no_args = Vec::new();
+    let args = raw_message.args.as_ref().unwrap_or(&no_args);
 
-            Some(MuseMessageType::Blink { blink: blink != 0 })
+    let muse_message_type = match service {
+        "/muse/eeg" => {
+            // TP9, AF7, AF8, TP10 then optional Right AUX and Left AUX
+            check_arity(N_EEG_CHANNELS, N_EEG_CHANNELS + N_AUX_CHANNELS, args)?;
+            let mut channels = [0.0; N_EEG_CHANNELS];
+            for chan in 0..N_EEG_CHANNELS {
+                channels[chan] = get_float_from_args(chan, args)?;
+            }
+            let mut aux = [None; N_AUX_CHANNELS];
+            for aux_chan in 0..N_AUX_CHANNELS {
+                let i = N_EEG_CHANNELS + aux_chan;
+                if i < args.len() {
+                    aux[aux_chan] = Some(get_float_from_args(i, args)?);
+                }
+            }
+
+            MuseMessageType::Eeg { channels, aux }
         }
 
-        "/muse/batt" => Some(MuseMessageType::Batt {
-            batt: (get_int_from_args(1, &args) as f32 / get_int_from_args(0, &args) as f32) as i32,
-        }),
-
-        "/muse/elements/jaw_clench" => Some(MuseMessageType::JawClench {
-            clench: get_int_from_args(0, &args) != 0,
-        }),
-
-        _ => {
-            error!("Unparsed message type: {:#?} {:#?}", service, raw_message);
-            None
+        "/muse/acc" => {
+            check_arity(3, 3, args)?;
+            MuseMessageType::Accelerometer {
+                x: get_float_from_args(0, args)?,
+                y: get_float_from_args(1, args)?,
+                z: get_float_from_args(2, args)?,
+            }
         }
-    })
-    .clone()
-    {
-        Some(m) => warn!("OSC message: {:?}", m),
-        _ => warn!("Unparsed OSC message"),
-    }
 
-    match service {
-        "/muse/eeg" => {
-            let a = get_float_from_args(0, &args);
-            let b = get_float_from_args(0, &args);
-            let c = get_float_from_args(0, &args);
-            let d = get_float_from_args(0, &args);
-
-            // println!("EEG: [{:#?}, {:#?}, {:#?}, {:#?}]", a, b, c, d);
-
-            Some(MuseMessageType::Eeg { a, b, c, d })
+        "/muse/gyro" => {
+            check_arity(3, 3, args)?;
+            MuseMessageType::Gyro {
+                x: get_float_from_args(0, args)?,
+                y: get_float_from_args(1, args)?,
+                z: get_float_from_args(2, args)?,
+            }
         }
 
-        "/muse/acc" => Some(MuseMessageType::Accelerometer {
-            x: get_float_from_args(0, &args),
-            y: get_float_from_args(1, &args),
-            z: get_float_from_args(2, &args),
-        }),
-
-        "/muse/gyro" => Some(MuseMessageType::Gyro {
-            x: get_float_from_args(0, &args),
-            y: get_float_from_args(1, &args),
-            z: get_float_from_args(2, &args),
-        }),
-
-        "/muse/elements/touching_forehead" => Some(MuseMessageType::TouchingForehead {
-            touch: get_int_from_args(0, &args) != 0,
-        }),
+        "/muse/elements/touching_forehead" => {
+            check_arity(1, 1, args)?;
+            MuseMessageType::TouchingForehead {
+                touch: get_int_from_args(0, args)? != 0,
+            }
+        }
 
-        "/muse/elements/horseshoe" => Some(MuseMessageType::Horseshoe {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/elements/horseshoe" => {
+            check_arity(4, 4, args)?;
+            MuseMessageType::Horseshoe {
+                a: get_float_from_args(0, args)?,
+                b: get_float_from_args(1, args)?,
+                c: get_float_from_args(2, args)?,
+                d: get_float_from_args(3, args)?,
+            }
+        }
 
         "/muse/elements/alpha_absolute" => {
-            let a = get_float_from_args(0, &args);
-            let b = get_float_from_args(1, &args);
-            let c = get_float_from_args(2, &args);
-            let d = get_float_from_args(3, &args);
-
-            // println!("Raw Alpha: [{:#?}, {:#?}, {:#?}, {:#?}]", a, b, c, d);
-
-            Some(MuseMessageType::Alpha { a, b, c, d })
+            check_arity(4, 4, args)?;
+            MuseMessageType::Alpha {
+                a: get_float_from_args(0, args)?,
+                b: get_float_from_args(1, args)?,
+                c: get_float_from_args(2, args)?,
+                d: get_float_from_args(3, args)?,
+            }
         }
 
-        "/muse/elements/beta_absolute" => Some(MuseMessageType::Beta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/elements/beta_absolute" => {
+            check_arity(4, 4, args)?;
+            MuseMessageType::Beta {
+                a: get_float_from_args(0, args)?,
+                b: get_float_from_args(1, args)?,
+                c: get_float_from_args(2, args)?,
+                d: get_float_from_args(3, args)?,
+            }
+        }
 
-        "/muse/elements/gamma_absolute" => Some(MuseMessageType::Gamma {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/elements/gamma_absolute" => {
+            check_arity(4, 4, args)?;
+            MuseMessageType::Gamma {
+                a: get_float_from_args(0, args)?,
+                b: get_float_from_args(1, args)?,
+                c: get_float_from_args(2, args)?,
+                d: get_float_from_args(3, args)?,
+            }
+        }
 
-        "/muse/elements/delta_absolute" => Some(MuseMessageType::Delta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/elements/delta_absolute" => {
+            check_arity(4, 4, args)?;
+            MuseMessageType::Delta {
+                a: get_float_from_args(0, args)?,
+                b: get_float_from_args(1, args)?,
+                c: get_float_from_args(2, args)?,
+                d: get_float_from_args(3, args)?,
+            }
+        }
 
-        "/muse/elements/theta_absolute" => Some(MuseMessageType::Theta {
-            a: get_float_from_args(0, &args),
-            b: get_float_from_args(1, &args),
-            c: get_float_from_args(2, &args),
-            d: get_float_from_args(3, &args),
-        }),
+        "/muse/elements/theta_absolute" => {
+            check_arity(4, 4, args)?;
+            MuseMessageType::Theta {
+                a: get_float_from_args(0, args)?,
+                b: get_float_from_args(1, args)?,
+                c: get_float_from_args(2, args)?,
+                d: get_float_from_args(3, args)?,
+            }
+        }
 
         "/muse/elements/blink" => {
-            let blink = get_int_from_args(0, &args);
+            check_arity(1, 1, args)?;
+            let blink = get_int_from_args(0, args)?;
             info!("Blink: {:#?}", blink);
 
-            Some(MuseMessageType::Blink { blink: blink != 0 })
+            MuseMessageType::Blink { blink: blink != 0 }
         }
 
-        "/muse/batt" => Some(MuseMessageType::Batt {
-            batt: (get_int_from_args(1, &args) as f32 / get_int_from_args(0, &args) as f32) as i32,
-        }),
+        "/muse/batt" => {
+            // Charge percentage x100, fuel gauge mV, ADC mV, temperature
+            check_arity(2, 4, args)?;
+            MuseMessageType::Batt {
+                batt: get_int_from_args(0, args)? / 100,
+            }
+        }
 
-        "/muse/elements/jaw_clench" => Some(MuseMessageType::JawClench {
-            clench: get_int_from_args(0, &args) != 0,
-        }),
+        "/muse/elements/jaw_clench" => {
+            check_arity(1, 1, args)?;
+            MuseMessageType::JawClench {
+                clench: get_int_from_args(0, args)? != 0,
+            }
+        }
 
         _ => {
-            error!("Unparsed message type: {:#?} {:#?}", service, raw_message);
-            None
+            return Err(MuseParseError::UnknownAddress {
+                address: service.to_string(),
+            })
         }
-    }
+    };
+    trace!("OSC message: {:?}", muse_message_type);
+
+    Ok(muse_message_type)
 }
 
-fn get_float_from_args(i: usize, args: &Vec<Type>) -> f32 {
-    let f = args.get(i).expect("Float was not provided");
+/// The number of arguments must be in the range min..=max for this message address
+fn check_arity(min: usize, max: usize, args: &Vec<Type>) -> Result<(), MuseParseError> {
+    match args.len() {
+        found if found < min => Err(MuseParseError::WrongArity {
+            expected: min,
+            found,
+        }),
+        found if found > max => Err(MuseParseError::WrongArity {
+            expected: max,
+            found,
+        }),
+        _ => Ok(()),
+    }
+}
 
-    match f {
-        Type::Float(value) => *value,
-        _ => panic!("Muse value was not a float"),
+fn get_float_from_args(i: usize, args: &Vec<Type>) -> Result<f32, MuseParseError> {
+    match args.get(i) {
+        Some(Type::Float(value)) => Ok(*value),
+        Some(_) => Err(MuseParseError::WrongType {
+            index: i,
+            expected: "float",
+        }),
+        None => Err(MuseParseError::MissingArgument { index: i }),
     }
 }
 
-fn get_int_from_args(i: usize, args: &Vec<Type>) -> i32 {
-    let j = args.get(i).expect("Int was not provided");
-    match j {
-        Type::Int(value) => *value,
-        _ => panic!("Muse value was not an int"),
+fn get_int_from_args(i: usize, args: &Vec<Type>) -> Result<i32, MuseParseError> {
+    match args.get(i) {
+        Some(Type::Int(value)) => Ok(*value),
+        Some(_) => Err(MuseParseError::WrongType {
+            index: i,
+            expected: "int",
+        }),
+        None => Err(MuseParseError::MissingArgument { index: i }),
     }
 }
 
@@ -248,13 +260,20 @@ fn get_int_from_args(i: usize, args: &Vec<Type>) -> i32 {
 mod tests {
     use crate::muse_packet::*;
 
+    fn message(addr: &str, args: Vec<Type>) -> Message {
+        Message {
+            addr: addr.to_string(),
+            args: Some(args),
+        }
+    }
+
     #[test]
     fn test_int_from_args() {
         let i = 32;
         let mut args: Vec<Type> = Vec::new();
         args.push(Type::Int(i));
 
-        assert_eq!(i, get_int_from_args(0, &args));
+        assert_eq!(Ok(i), get_int_from_args(0, &args));
     }
 
     #[test]
@@ -263,6 +282,181 @@ mod tests {
         let mut args: Vec<Type> = Vec::new();
         args.push(Type::Float(f));
 
-        assert_eq!(f, get_float_from_args(0, &args));
+        assert_eq!(Ok(f), get_float_from_args(0, &args));
+    }
+
+    #[test]
+    fn test_missing_argument() {
+        let args: Vec<Type> = Vec::new();
+
+        assert_eq!(
+            Err(MuseParseError::MissingArgument { index: 0 }),
+            get_float_from_args(0, &args)
+        );
+    }
+
+    #[test]
+    fn test_wrong_type() {
+        let args = vec![Type::Float(1.0)];
+
+        assert_eq!(
+            Err(MuseParseError::WrongType {
+                index: 0,
+                expected: "int"
+            }),
+            get_int_from_args(0, &args)
+        );
+    }
+
+    #[test]
+    fn test_unknown_address() {
+        let result = parse_muse_message_type(message("/some/other/app", vec![Type::Int(1)]));
+
+        assert_eq!(
+            Err(MuseParseError::UnknownAddress {
+                address: "/some/other/app".to_string()
+            }),
+            result.map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_wrong_arity() {
+        let result = parse_muse_message_type(message("/muse/acc", vec![Type::Float(1.0)]));
+
+        assert_eq!(
+            Err(MuseParseError::WrongArity {
+                expected: 3,
+                found: 1
+            }),
+            result.map(|_| ())
+        );
+    }
+
+    #[test]
+    fn test_eeg_channels() {
+        let args = vec![
+            Type::Float(1.0),
+            Type::Float(2.0),
+            Type::Float(3.0),
+            Type::Float(4.0),
+        ];
+
+        match parse_muse_message_type(message("/muse/eeg", args)) {
+            Ok(MuseMessageType::Eeg { channels, aux }) => {
+                assert_eq!([1.0, 2.0, 3.0, 4.0], channels);
+                assert_eq!([None, None], aux);
+            }
+            other => panic!("Expected EEG, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_eeg_aux_and_dropped_sample() {
+        let args = vec![
+            Type::Float(1.0),
+            Type::Float(std::f32::NAN),
+            Type::Float(3.0),
+            Type::Float(4.0),
+            Type::Float(5.0),
+            Type::Float(6.0),
+        ];
+
+        match parse_muse_message_type(message("/muse/eeg", args)) {
+            Ok(MuseMessageType::Eeg { channels, aux }) => {
+                assert_eq!(1.0, channels[0]);
+                assert!(channels[1].is_nan());
+                assert_eq!(4.0, channels[3]);
+                assert_eq!([Some(5.0), Some(6.0)], aux);
+            }
+            other => panic!("Expected EEG, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_timetag_to_duration() {
+        assert_eq!(None, timetag_to_duration(OSC_IMMEDIATELY));
+        assert_eq!(
+            Some(Duration::from_millis(1_500_000_000_500)),
+            timetag_to_duration((1_500_000_000 + NTP_UNIX_OFFSET as u32, 1 << 31))
+        );
+    }
+
+    #[test]
+    fn test_bundle_timetag_stamps_messages() {
+        let arrival = Duration::from_secs(1_600_000_000);
+        let addr: SocketAddr = "127.0.0.1:34254".parse().unwrap();
+        let blink = message("/muse/elements/blink", vec![Type::Int(1)]);
+        let bundle = Packet::Bundle(Bundle {
+            timetag: (1_500_000_000 + NTP_UNIX_OFFSET as u32, 0),
+            content: vec![Packet::Message(blink.clone())],
+        });
+
+        let from_bundle = parse_muse_packet(arrival, addr, &bundle);
+        let from_message = parse_muse_packet(arrival, addr, &Packet::Message(blink));
+
+        assert_eq!(
+            Duration::from_secs(1_500_000_000),
+            from_bundle[0].as_ref().unwrap().time
+        );
+        assert_eq!(arrival, from_message[0].as_ref().unwrap().time);
+    }
+
+    #[test]
+    fn test_nested_bundle_timetag_stamps_messages() {
+        let arrival = Duration::from_secs(1_600_000_000);
+        let addr: SocketAddr = "127.0.0.1:34254".parse().unwrap();
+        let blink = message("/muse/elements/blink", vec![Type::Int(1)]);
+        let inner = Packet::Bundle(Bundle {
+            timetag: (1_500_000_002 + NTP_UNIX_OFFSET as u32, 0),
+            content: vec![Packet::Message(blink.clone())],
+        });
+        let bundle = Packet::Bundle(Bundle {
+            timetag: (1_500_000_000 + NTP_UNIX_OFFSET as u32, 0),
+            content: vec![Packet::Message(blink), inner],
+        });
+
+        let parsed = parse_muse_packet(arrival, addr, &bundle);
+
+        assert_eq!(2, parsed.len());
+        assert_eq!(
+            Duration::from_secs(1_500_000_000),
+            parsed[0].as_ref().unwrap().time
+        );
+        assert_eq!(
+            Duration::from_secs(1_500_000_002),
+            parsed[1].as_ref().unwrap().time
+        );
+    }
+
+    #[test]
+    fn test_battery_percent() {
+        let args = vec![
+            Type::Int(8550),
+            Type::Int(3900),
+            Type::Int(3900),
+            Type::Int(32),
+        ];
+
+        match parse_muse_message_type(message("/muse/batt", args)) {
+            Ok(MuseMessageType::Batt { batt }) => assert_eq!(85, batt),
+            other => panic!("Expected battery, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_message_without_args() {
+        let result = parse_muse_message_type(Message {
+            addr: "/muse/elements/blink".to_string(),
+            args: None,
+        });
+
+        assert_eq!(
+            Err(MuseParseError::WrongArity {
+                expected: 1,
+                found: 0
+            }),
+            result.map(|_| ())
+        );
     }
 }
@@ -0,0 +1,568 @@
+/// Record raw OSC packets exactly as they arrive from MindMonitor, and play them back later in
+/// place of a live Muse headset. This allows UI development without anyone wearing the headset.
+use crate::muse_model::{EegMessageReceiver, MuseMessage, MuseParseError};
+use crate::muse_packet::{parse_muse_packet, unfold_timed, OSC_IMMEDIATELY};
+use csv::{ReaderBuilder, StringRecord, Writer, WriterBuilder};
+use log::*;
+use nannou_osc::{Bundle, Message, Packet, Type};
+use std::fs::File;
+use std::io::Write;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+const COLUMN_PACKET: usize = 0; // Index of the packet a message belongs to, so bundles can be rebuilt
+const COLUMN_TIME: usize = 1; // Arrival time, microseconds since UNIX_EPOCH
+const COLUMN_SOURCE: usize = 2; // IP address and port of the sender
+const COLUMN_TIMETAG_SECONDS: usize = 3; // Timetag of the innermost bundle, empty for a plain message
+const COLUMN_TIMETAG_FRACTION: usize = 4;
+const COLUMN_ADDRESS: usize = 5; // OSC address such as "/muse/eeg"
+const COLUMN_FIRST_ARG: usize = 6; // Each argument is in its own column, prefixed by type
+const ARG_NOT_RECORDED: &str = "?"; // In place of an argument type which can not be recorded
+
+/// How fast to play back a recording
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReplaySpeed {
+    RealTime,
+    Accelerated(f32), // Multiple of real time, 2.0 is twice as fast
+    Stepped, // One packet each time packets are requested, regardless of the recorded timing
+}
+
+impl ReplaySpeed {
+    /// Parse "step", or a speed multiple such as "1" or "4.0"
+    pub fn parse(speed: &str) -> Option<ReplaySpeed> {
+        match speed.trim() {
+            "step" | "stepped" => Some(ReplaySpeed::Stepped),
+            multiple => match multiple.parse::<f32>() {
+                Ok(m) if m == 1.0 => Some(ReplaySpeed::RealTime),
+                Ok(m) if m.is_finite() && m > 0.0 => Some(ReplaySpeed::Accelerated(m)),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// One packet as it was received from the network
+#[derive(Clone, Debug)]
+pub struct RecordedPacket {
+    pub time: Duration, // Since UNIX_EPOCH, the beginning of 1970
+    pub source: SocketAddr,
+    pub packet: Packet,
+    pub errors: Vec<MuseParseError>, // Messages of this packet which could not be read back
+}
+
+/// Write every received OSC packet to a CSV file
+pub struct OscRecorder {
+    writer: Writer<File>,
+    packet_count: u64,
+}
+
+impl OscRecorder {
+    pub fn create(filename: &str) -> csv::Result<OscRecorder> {
+        let mut file = File::create(filename)?;
+        writeln!(file, "{}", crate::rig::selected().csv_comment())?;
+        let mut writer = WriterBuilder::new().flexible(true).from_writer(file);
+        writer.write_record(&[
+            "Packet",
+            "Time",
+            "Source",
+            "Timetag Seconds",
+            "Timetag Fraction",
+            "Address",
+            "Arguments",
+        ])?;
+        info!("Recording OSC packets to {}", filename);
+
+        Ok(OscRecorder {
+            writer,
+            packet_count: 0,
+        })
+    }
+
+    /// Add one packet. A bundle is written as one row per message sharing the same packet number,
+    /// each with the timetag of the innermost bundle holding it.
+    pub fn record(&mut self, time: Duration, source: SocketAddr, packet: &Packet) {
+        let packet_number = self.packet_count.to_string();
+        let time = time.as_micros().to_string();
+        let source = source.to_string();
+
+        for (timetag, message) in unfold_timed(packet) {
+            let (timetag_seconds, timetag_fraction) = match timetag {
+                Some((seconds, fraction)) => (seconds.to_string(), fraction.to_string()),
+                None => (String::new(), String::new()),
+            };
+            let mut record = vec![
+                packet_number.clone(),
+                time.clone(),
+                source.clone(),
+                timetag_seconds,
+                timetag_fraction,
+                message.addr.clone(),
+            ];
+            if let Some(args) = &message.args {
+                record.extend(args.iter().map(encode_arg));
+            }
+
+            if let Err(e) = self.writer.write_record(&record) {
+                error!("Can not record OSC packet: {:?}", e);
+            }
+        }
+
+        self.packet_count = self.packet_count + 1;
+    }
+
+    pub fn flush(&mut self) {
+        let _r = self.writer.flush();
+    }
+}
+
+/// Write one OSC argument as a type prefix and value, for example "f:0.5". A type which can not be
+/// recorded is written as ARG_NOT_RECORDED, so the arguments after it keep their positions.
+fn encode_arg(arg: &Type) -> String {
+    match arg {
+        Type::Int(i) => format!("i:{}", i),
+        Type::Float(f) => format!("f:{:?}", f),
+        Type::Long(h) => format!("h:{}", h),
+        Type::Double(d) => format!("d:{:?}", d),
+        Type::String(s) => format!("s:{}", s),
+        Type::Char(c) => format!("c:{}", c),
+        Type::Time(seconds, fraction) => format!("t:{}:{}", seconds, fraction),
+        Type::Bool(true) => "T".to_string(),
+        Type::Bool(false) => "F".to_string(),
+        Type::Nil => "N".to_string(),
+        Type::Inf => "I".to_string(),
+        _ => {
+            warn!("OSC argument type can not be recorded: {:?}", arg);
+            ARG_NOT_RECORDED.to_string()
+        }
+    }
+}
+
+/// Read back one OSC argument written by encode_arg(). None if it was not recorded or is malformed.
+fn decode_arg(field: &str) -> Option<Type> {
+    let (type_tag, value) = match field.find(':') {
+        Some(i) => (&field[..i], &field[i + 1..]),
+        None => (field, ""),
+    };
+
+    match type_tag {
+        "i" => value.parse().ok().map(Type::Int),
+        "f" => value.parse().ok().map(Type::Float),
+        "h" => value.parse().ok().map(Type::Long),
+        "d" => value.parse().ok().map(Type::Double),
+        "s" => Some(Type::String(value.to_string())),
+        "c" => value.chars().next().map(Type::Char),
+        "t" => {
+            let mut parts = value.split(':').map(|part| part.parse::<u32>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(seconds)), Some(Ok(fraction))) => Some(Type::Time(seconds, fraction)),
+                _ => None,
+            }
+        }
+        "T" => Some(Type::Bool(true)),
+        "F" => Some(Type::Bool(false)),
+        "N" => Some(Type::Nil),
+        "I" => Some(Type::Inf),
+        _ => None,
+    }
+}
+
+/// Load all packets from a file written by OscRecorder. A message with an argument which can not be
+/// read back is not replayed, it is kept as a parse error of its packet.
+pub fn read_recording(filename: &str) -> csv::Result<Vec<RecordedPacket>> {
+    let mut reader = ReaderBuilder::new()
+        .flexible(true)
+        .comment(Some(b'#'))
+        .from_path(filename)?;
+    let mut recorded_packets: Vec<RecordedPacket> = Vec::new();
+    let mut current_packet_number: Option<String> = None;
+
+    for result in reader.records() {
+        let record = result?;
+        let (addr, time, source) =
+            match (record.get(COLUMN_ADDRESS), decode_time_and_source(&record)) {
+                (Some(addr), Some((time, source))) => (addr.to_string(), time, source),
+                _ => {
+                    warn!("Skipping unreadable OSC recording row: {:?}", record);
+                    continue;
+                }
+            };
+        let timetag = decode_timetag(&record);
+        let packet_number = record.get(COLUMN_PACKET).map(|s| s.to_string());
+        let same_packet = packet_number == current_packet_number;
+        current_packet_number = packet_number;
+
+        match decode_args(&record) {
+            Ok(args) => {
+                let message = Message {
+                    addr,
+                    args: Some(args),
+                };
+                match (timetag, recorded_packets.last_mut()) {
+                    (
+                        Some(timetag),
+                        Some(RecordedPacket {
+                            packet: Packet::Bundle(bundle),
+                            ..
+                        }),
+                    ) if same_packet => add_to_bundle(bundle, timetag, message),
+                    _ => {
+                        let packet = match timetag {
+                            Some(timetag) => Packet::Bundle(Bundle {
+                                timetag,
+                                content: vec![Packet::Message(message)],
+                            }),
+                            None => Packet::Message(message),
+                        };
+                        recorded_packets.push(RecordedPacket {
+                            time,
+                            source,
+                            packet,
+                            errors: Vec::new(),
+                        });
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("Can not read back {} in OSC recording: {}", addr, e);
+                match recorded_packets.last_mut() {
+                    Some(recorded) if same_packet => recorded.errors.push(e),
+                    _ => recorded_packets.push(RecordedPacket {
+                        time,
+                        source,
+                        packet: Packet::Bundle(Bundle {
+                            timetag: timetag.unwrap_or(OSC_IMMEDIATELY),
+                            content: Vec::new(),
+                        }),
+                        errors: vec![e],
+                    }),
+                }
+            }
+        }
+    }
+    info!(
+        "Loaded {} OSC packets from {}",
+        recorded_packets.len(),
+        filename
+    );
+
+    Ok(recorded_packets)
+}
+
+fn decode_time_and_source(record: &StringRecord) -> Option<(Duration, SocketAddr)> {
+    let time = record.get(COLUMN_TIME)?.parse::<u64>().ok()?;
+    let source = record.get(COLUMN_SOURCE)?.parse::<SocketAddr>().ok()?;
+
+    Some((Duration::from_micros(time), source))
+}
+
+fn decode_timetag(record: &StringRecord) -> Option<(u32, u32)> {
+    let seconds = record.get(COLUMN_TIMETAG_SECONDS)?.parse::<u32>().ok()?;
+    let fraction = record.get(COLUMN_TIMETAG_FRACTION)?.parse::<u32>().ok()?;
+
+    Some((seconds, fraction))
+}
+
+/// All arguments of a message, or an error for the first which can not be read back
+fn decode_args(record: &StringRecord) -> Result<Vec<Type>, MuseParseError> {
+    record
+        .iter()
+        .skip(COLUMN_FIRST_ARG)
+        .enumerate()
+        .map(|(index, field)| decode_arg(field).ok_or(MuseParseError::UnreadableArgument { index }))
+        .collect()
+}
+
+/// A message of the same packet joins the bundle, or a bundle inside it if it has its own timetag
+fn add_to_bundle(bundle: &mut Bundle, timetag: (u32, u32), message: Message) {
+    if bundle.timetag == timetag {
+        bundle.content.push(Packet::Message(message));
+        return;
+    }
+
+    match bundle.content.last_mut() {
+        Some(Packet::Bundle(inner)) if inner.timetag == timetag => {
+            inner.content.push(Packet::Message(message))
+        }
+        _ => bundle.content.push(Packet::Bundle(Bundle {
+            timetag,
+            content: vec![Packet::Message(message)],
+        })),
+    }
+}
+
+/// Play back a recording as if the packets were arriving live from MindMonitor
+pub struct ReplayMessageReceiver {
+    recorded_packets: Vec<RecordedPacket>,
+    next_packet: usize,
+    speed: ReplaySpeed,
+    replay_start: Option<Instant>, // Set when the first packet is requested
+    end_reported: bool,
+}
+
+impl ReplayMessageReceiver {
+    pub fn open(filename: &str, speed: ReplaySpeed) -> csv::Result<ReplayMessageReceiver> {
+        info!("Replaying OSC packets from {} at {:?}", filename, speed);
+
+        Ok(ReplayMessageReceiver::new(read_recording(filename)?, speed))
+    }
+
+    pub fn new(recorded_packets: Vec<RecordedPacket>, speed: ReplaySpeed) -> Self {
+        Self {
+            recorded_packets,
+            next_packet: 0,
+            speed,
+            replay_start: None,
+            end_reported: false,
+        }
+    }
+
+    /// All packets have been replayed
+    pub fn is_finished(&self) -> bool {
+        self.next_packet >= self.recorded_packets.len()
+    }
+
+    /// Recorded time offset from the first packet which should have been delivered by now
+    fn replay_position(&mut self) -> Duration {
+        let replay_start = *self.replay_start.get_or_insert_with(Instant::now);
+
+        self.position_since(replay_start)
+    }
+
+    fn position_since(&self, replay_start: Instant) -> Duration {
+        let elapsed = replay_start.elapsed();
+
+        match self.speed {
+            ReplaySpeed::RealTime => elapsed,
+            ReplaySpeed::Accelerated(multiple) => elapsed.mul_f32(multiple),
+            ReplaySpeed::Stepped => Duration::from_secs(0),
+        }
+    }
+
+    /// The packets which are now due, based on replay speed
+    fn due_packets(&mut self) -> &[RecordedPacket] {
+        let first = self.next_packet;
+
+        if self.is_finished() {
+            return &[];
+        }
+
+        match self.speed {
+            ReplaySpeed::Stepped => self.next_packet = first + 1,
+            _ => {
+                let first_time = self.recorded_packets[0].time;
+                let due_time = first_time + self.replay_position();
+                while self.next_packet < self.recorded_packets.len()
+                    && self.recorded_packets[self.next_packet].time <= due_time
+                {
+                    self.next_packet = self.next_packet + 1;
+                }
+            }
+        }
+
+        &self.recorded_packets[first..self.next_packet]
+    }
+}
+
+impl EegMessageReceiver for ReplayMessageReceiver {
+    /// Receive the recorded packets which are due for replay
+    fn receive_packets(&mut self) -> Vec<Result<MuseMessage, MuseParseError>> {
+        let mut muse_messages: Vec<Result<MuseMessage, MuseParseError>> = Vec::new();
+
+        for recorded in self.due_packets() {
+            let mut additional_messages =
+                parse_muse_packet(recorded.time, recorded.source, &recorded.packet);
+            muse_messages.append(&mut additional_messages);
+            muse_messages.extend(recorded.errors.iter().cloned().map(Err));
+        }
+
+        if self.is_finished() && !self.end_reported {
+            info!("OSC replay finished");
+            self.end_reported = true;
+        }
+
+        muse_messages
+    }
+
+    /// The recorded time being replayed now
+    fn now(&self) -> Duration {
+        let first_time = match self.recorded_packets.first() {
+            Some(first) => first.time,
+            None => return crate::event_log::now(),
+        };
+
+        match (self.speed, self.replay_start) {
+            (ReplaySpeed::Stepped, _) | (_, None) => match self.next_packet {
+                0 => first_time,
+                n => self.recorded_packets[n - 1].time,
+            },
+            (_, Some(replay_start)) => first_time + self.position_since(replay_start),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eeg_message(value: f32) -> Message {
+        Message {
+            addr: "/muse/eeg".to_string(),
+            args: Some(vec![
+                Type::Float(value),
+                Type::Float(value + 1.0),
+                Type::Float(value + 2.0),
+                Type::Float(value + 3.0),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_arg_round_trip() {
+        let args = vec![
+            Type::Int(-3),
+            Type::Float(0.125),
+            Type::Long(1 << 40),
+            Type::Double(2.5),
+            Type::String("a: b, c".to_string()),
+            Type::Time(7, 9),
+            Type::Bool(true),
+            Type::Nil,
+        ];
+
+        for arg in args {
+            let encoded = encode_arg(&arg);
+            assert_eq!(
+                format!("{:?}", Some(arg)),
+                format!("{:?}", decode_arg(&encoded))
+            );
+        }
+    }
+
+    #[test]
+    fn test_record_and_read_back() {
+        let filename = std::env::temp_dir().join("meme_osc_recording_test.csv");
+        let filename = filename.to_str().unwrap();
+        let source: SocketAddr = "192.168.1.5:5000".parse().unwrap();
+        let bundle = Packet::Bundle(Bundle {
+            timetag: (3_791_000_000, 12345),
+            content: vec![
+                Packet::Message(eeg_message(1.0)),
+                Packet::Message(eeg_message(5.0)),
+            ],
+        });
+        let message = Packet::Message(eeg_message(9.0));
+
+        let mut recorder = OscRecorder::create(filename).unwrap();
+        recorder.record(Duration::from_micros(1_000_000), source, &bundle);
+        recorder.record(Duration::from_micros(1_500_000), source, &message);
+        recorder.flush();
+
+        let recorded_packets = read_recording(filename).unwrap();
+        assert_eq!(2, recorded_packets.len());
+        assert_eq!(Duration::from_micros(1_000_000), recorded_packets[0].time);
+        assert_eq!(source, recorded_packets[0].source);
+        assert_eq!(
+            format!("{:?}", bundle),
+            format!("{:?}", recorded_packets[0].packet)
+        );
+        assert_eq!(
+            format!("{:?}", message),
+            format!("{:?}", recorded_packets[1].packet)
+        );
+    }
+
+    #[test]
+    fn test_unrecorded_arg_skips_message() {
+        let filename = std::env::temp_dir().join("meme_osc_recording_unrecorded_test.csv");
+        let filename = filename.to_str().unwrap();
+        let source: SocketAddr = "192.168.1.5:5000".parse().unwrap();
+        let mut message = eeg_message(1.0);
+        message.args.as_mut().unwrap()[1] = Type::Blob(vec![1, 2]);
+        let bundle = Packet::Bundle(Bundle {
+            timetag: (3_791_000_000, 12345),
+            content: vec![Packet::Message(message), Packet::Message(eeg_message(5.0))],
+        });
+
+        let mut recorder = OscRecorder::create(filename).unwrap();
+        recorder.record(Duration::from_micros(1_000_000), source, &bundle);
+        recorder.flush();
+
+        let recorded_packets = read_recording(filename).unwrap();
+        assert_eq!(1, recorded_packets.len());
+        assert_eq!(
+            vec![MuseParseError::UnreadableArgument { index: 1 }],
+            recorded_packets[0].errors
+        );
+        match &recorded_packets[0].packet {
+            Packet::Bundle(bundle) => assert_eq!(
+                format!("{:?}", vec![Packet::Message(eeg_message(5.0))]),
+                format!("{:?}", bundle.content)
+            ),
+            packet => panic!("Expected a bundle, got {:?}", packet),
+        }
+        assert_eq!(None, decode_arg("f:abc").map(|arg| format!("{:?}", arg)));
+    }
+
+    #[test]
+    fn test_nested_bundle_keeps_timetags() {
+        let filename = std::env::temp_dir().join("meme_osc_recording_nested_test.csv");
+        let filename = filename.to_str().unwrap();
+        let source: SocketAddr = "192.168.1.5:5000".parse().unwrap();
+        let bundle = Packet::Bundle(Bundle {
+            timetag: (3_791_000_000, 0),
+            content: vec![
+                Packet::Message(eeg_message(1.0)),
+                Packet::Bundle(Bundle {
+                    timetag: (3_791_000_001, 0),
+                    content: vec![Packet::Message(eeg_message(5.0))],
+                }),
+            ],
+        });
+
+        let mut recorder = OscRecorder::create(filename).unwrap();
+        recorder.record(Duration::from_micros(1_000_000), source, &bundle);
+        recorder.flush();
+
+        let recorded_packets = read_recording(filename).unwrap();
+        assert_eq!(1, recorded_packets.len());
+        assert_eq!(
+            format!("{:?}", unfold_timed(&bundle)),
+            format!("{:?}", unfold_timed(&recorded_packets[0].packet))
+        );
+    }
+
+    #[test]
+    fn test_stepped_replay() {
+        let source: SocketAddr = "127.0.0.1:5000".parse().unwrap();
+        let recorded_packets = (0..3)
+            .map(|i| RecordedPacket {
+                time: Duration::from_secs(i * 100),
+                source,
+                packet: Packet::Message(eeg_message(i as f32)),
+                errors: Vec::new(),
+            })
+            .collect();
+        let mut receiver = ReplayMessageReceiver::new(recorded_packets, ReplaySpeed::Stepped);
+
+        assert_eq!(Duration::from_secs(0), receiver.now());
+        assert_eq!(1, receiver.due_packets().len());
+        assert_eq!(1, receiver.due_packets().len());
+        assert_eq!(Duration::from_secs(100), receiver.now());
+        assert!(!receiver.is_finished());
+        assert_eq!(1, receiver.due_packets().len());
+        assert!(receiver.is_finished());
+        assert_eq!(0, receiver.due_packets().len());
+    }
+
+    #[test]
+    fn test_parse_replay_speed() {
+        assert_eq!(Some(ReplaySpeed::Stepped), ReplaySpeed::parse("step"));
+        assert_eq!(Some(ReplaySpeed::RealTime), ReplaySpeed::parse("1"));
+        assert_eq!(
+            Some(ReplaySpeed::Accelerated(4.0)),
+            ReplaySpeed::parse("4.0")
+        );
+        assert_eq!(None, ReplaySpeed::parse("-2"));
+        assert_eq!(None, ReplaySpeed::parse("fast"));
+    }
+}
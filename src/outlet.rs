@@ -0,0 +1,312 @@
+/// Live data for other lab software such as stimulus tools and recorders, in the spirit of a Lab
+/// Streaming Layer outlet. Raw EEG, band powers, normalized valence and arousal and event markers
+/// are broadcast as JSON text messages to every WebSocket client connected to MEME_OUTLET. Each
+/// client is first sent an "info" message describing every stream, then one message per sample
+/// or marker with its time on the clock of the EEG. Missing values, such as a dropped EEG
+/// sample, are null.
+use crate::rig::RigIdentity;
+use crate::session_log;
+use serde::Serialize;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub const ENV_OUTLET: &str = "MEME_OUTLET"; // Address to serve live data on, for example 127.0.0.1:8765. No outlet if not set
+pub const OUTLET_VERSION: u32 = 1; // Layout of the JSON messages
+const MAX_CLIENTS: usize = 16;
+const QUEUE_SIZE: usize = 1024; // Messages waiting to be sent, per client, before new ones are dropped
+
+/// A stream of samples or markers, as described to each client when it connects
+#[derive(Debug, Serialize)]
+struct StreamInfo {
+    name: &'static str,
+    kind: &'static str,
+    channels: Vec<&'static str>,
+    unit: Option<&'static str>,
+    nominal_rate: Option<f32>, // Samples per second, None if irregular
+}
+
+/// Every message sent to a client
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutletMessage<'a> {
+    Info {
+        version: u32,
+        rig: &'a str,
+        session_id: Option<i32>,
+        streams: Vec<StreamInfo>,
+    },
+    Sample {
+        stream: &'a str,
+        epoch_us: u64,
+        session_s: f64,
+        values: &'a [Option<f32>],
+    },
+    Marker {
+        epoch_us: u64,
+        session_s: f64,
+        event: &'a str,
+        detail: &'a str,
+    },
+}
+
+fn streams() -> Vec<StreamInfo> {
+    let electrodes = vec!["TP9", "AF7", "AF8", "TP10"];
+    let band = |name| StreamInfo {
+        name,
+        kind: "band_power",
+        channels: electrodes.clone(),
+        unit: None,
+        nominal_rate: None,
+    };
+
+    vec![
+        StreamInfo {
+            name: "eeg",
+            kind: "EEG",
+            channels: vec!["TP9", "AF7", "AF8", "TP10", "Right AUX", "Left AUX"],
+            unit: Some("microvolts"),
+            nominal_rate: crate::sample_timing::nominal_rate("eeg"),
+        },
+        band("alpha"),
+        band("beta"),
+        band("gamma"),
+        band("delta"),
+        band("theta"),
+        StreamInfo {
+            name: "valence_arousal",
+            kind: "valence_arousal",
+            channels: vec!["normalized_valence", "normalized_arousal"],
+            unit: Some("standard deviations from the calibration mean"),
+            nominal_rate: None,
+        },
+        StreamInfo {
+            name: "markers",
+            kind: "markers",
+            channels: vec!["event", "detail"],
+            unit: None,
+            nominal_rate: None,
+        },
+    ]
+}
+
+/// Hands messages to the WebSocket thread without waiting. Clones share the same clients.
+#[derive(Clone)]
+pub struct Outlet {
+    pub address: SocketAddr, // Where clients connect, the port is chosen if 0 was given
+    broadcaster: ws::Sender,
+    clients: Arc<AtomicUsize>, // Connected now, nothing is serialized while this is 0
+}
+
+impl Outlet {
+    /// Serve live data if MEME_OUTLET is set
+    pub fn from_env(rig: &RigIdentity, session_id: Option<i32>) -> Option<Outlet> {
+        let address = env::var(ENV_OUTLET).ok()?;
+
+        match Outlet::listen(&address, rig, session_id) {
+            Ok(outlet) => {
+                info!("Serving live data on ws://{}", outlet.address);
+                Some(outlet)
+            }
+            Err(e) => {
+                error!("Can not serve live data on {}: {}", address, e);
+                None
+            }
+        }
+    }
+
+    pub fn listen(address: &str, rig: &RigIdentity, session_id: Option<i32>) -> ws::Result<Outlet> {
+        let info = serde_json::to_string(&OutletMessage::Info {
+            version: OUTLET_VERSION,
+            rig: &rig.title,
+            session_id,
+            streams: streams(),
+        })
+        .expect("Can not serialize the outlet streams");
+        let clients = Arc::new(AtomicUsize::new(0));
+        let factory_clients = clients.clone();
+        let socket = ws::Builder::new()
+            .with_settings(ws::Settings {
+                max_connections: MAX_CLIENTS,
+                queue_size: QUEUE_SIZE,
+                ..ws::Settings::default()
+            })
+            .build(move |out| Client {
+                out,
+                info: info.clone(),
+                clients: factory_clients.clone(),
+                counted: false,
+            })?
+            .bind(address)?;
+        let broadcaster = socket.broadcaster();
+        let address = socket.local_addr()?;
+
+        thread::spawn(move || {
+            if let Err(e) = socket.run() {
+                error!("Live data outlet stopped: {}", e);
+            }
+        });
+
+        Ok(Outlet {
+            address,
+            broadcaster,
+            clients,
+        })
+    }
+
+    /// Raw EEG, before the filter chain
+    pub fn eeg(&self, time: Duration, channels: [f32; 4], aux: [Option<f32>; 2]) {
+        let mut values: Vec<Option<f32>> = channels.iter().map(|v| Some(*v)).collect();
+        values.extend(aux.iter());
+
+        self.sample(time, "eeg", &values);
+    }
+
+    pub fn band_power(&self, time: Duration, band: &str, channels: [f32; 4]) {
+        let values: Vec<Option<f32>> = channels.iter().map(|v| Some(*v)).collect();
+
+        self.sample(time, band, &values);
+    }
+
+    pub fn valence_arousal(
+        &self,
+        time: Duration,
+        normalized_valence: Option<f32>,
+        normalized_arousal: Option<f32>,
+    ) {
+        self.sample(
+            time,
+            "valence_arousal",
+            &[normalized_valence, normalized_arousal],
+        );
+    }
+
+    /// An event such as a stimulus onset, sent as it happens
+    pub fn marker(&self, time: Duration, event: &str, detail: &str) {
+        let (epoch_us, session_s) = timestamps(time);
+
+        self.send(&OutletMessage::Marker {
+            epoch_us,
+            session_s,
+            event,
+            detail,
+        });
+    }
+
+    fn sample(&self, time: Duration, stream: &str, values: &[Option<f32>]) {
+        let (epoch_us, session_s) = timestamps(time);
+
+        self.send(&OutletMessage::Sample {
+            stream,
+            epoch_us,
+            session_s,
+            values,
+        });
+    }
+
+    /// If the queue is full the message is dropped, the CSV logs still have it
+    fn send(&self, message: &OutletMessage) {
+        if self.clients.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+
+        match serde_json::to_string(message) {
+            Ok(json) => {
+                let _result = self.broadcaster.broadcast(json);
+            }
+            Err(e) => warn!("Can not serialize {:?}: {}", message, e),
+        }
+    }
+}
+
+fn timestamps(time: Duration) -> (u64, f64) {
+    (time.as_micros() as u64, session_log::session_seconds(time))
+}
+
+/// One connected client
+struct Client {
+    out: ws::Sender,
+    info: String, // Description of the streams, sent first
+    clients: Arc<AtomicUsize>,
+    counted: bool, // In 'clients', from when the connection opens until it is dropped
+}
+
+impl ws::Handler for Client {
+    fn on_open(&mut self, shake: ws::Handshake) -> ws::Result<()> {
+        info!(
+            "Live data client connected from {}",
+            shake.remote_addr()?.unwrap_or_default()
+        );
+        self.clients.fetch_add(1, Ordering::Relaxed);
+        self.counted = true;
+
+        self.out.send(self.info.as_str())
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.counted {
+            info!("Live data client disconnected");
+            self.clients.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_json() {
+        let values = [Some(812.5), None];
+        let json = serde_json::to_string(&OutletMessage::Sample {
+            stream: "eeg",
+            epoch_us: 1_588_413_601_750_001,
+            session_s: 1.5,
+            values: &values,
+        })
+        .unwrap();
+
+        assert_eq!(
+            r#"{"type":"sample","stream":"eeg","epoch_us":1588413601750001,"session_s":1.5,"values":[812.5,null]}"#,
+            json
+        );
+    }
+
+    #[test]
+    fn test_client_gets_info_then_markers() {
+        let rig = RigIdentity {
+            id: Some(3),
+            title: "Lab rig 1".to_string(),
+        };
+        let outlet = Outlet::listen("127.0.0.1:0", &rig, Some(17)).unwrap();
+        let url = format!("ws://{}", outlet.address);
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            ws::connect(url, |_out| {
+                let tx = tx.clone();
+                move |message: ws::Message| {
+                    let _result = tx.send(message.to_string());
+                    Ok(())
+                }
+            })
+        });
+        let received = || rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let info: serde_json::Value = serde_json::from_str(&received()).unwrap();
+        assert_eq!("info", info["type"]);
+        assert_eq!("Lab rig 1", info["rig"]);
+        assert_eq!(17, info["session_id"]);
+        assert_eq!("eeg", info["streams"][0]["name"]);
+
+        outlet.marker(Duration::from_secs(1_588_413_600), "onset", "1.jpg");
+        let marker: serde_json::Value = serde_json::from_str(&received()).unwrap();
+        assert_eq!("marker", marker["type"]);
+        assert_eq!(1_588_413_600_000_000u64, marker["epoch_us"]);
+        assert_eq!("1.jpg", marker["detail"]);
+    }
+}
@@ -0,0 +1,111 @@
+/// Where each petal of a mandala sits at either end of its transition. The live mandalas are built
+/// from these, and the summary card draws its final mandala from the same numbers.
+use mandala_quicksilver::MandalaState;
+use quicksilver::geom::Transform;
+use quicksilver::graphics::Color;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PetalPose {
+    pub color: Color,
+    pub rotate: f32, // Degrees
+    pub translate: (f32, f32),
+    pub scale: (f32, f32),
+}
+
+impl PetalPose {
+    /// The same pose for the live mandala
+    pub fn state(&self) -> MandalaState {
+        MandalaState::new(
+            self.color,
+            Transform::rotate(self.rotate),
+            Transform::translate(self.translate),
+            Transform::scale(self.scale),
+        )
+    }
+
+    /// The pose at 'value' as given to Mandala::start_transition(), 0 closed and 1 open. The
+    /// normalized value is not clamped, so a strong response goes beyond the open pose.
+    pub fn between(closed: &PetalPose, open: &PetalPose, value: f32) -> PetalPose {
+        let mix = |a: f32, b: f32| a + (b - a) * value;
+
+        PetalPose {
+            color: Color {
+                r: mix(closed.color.r, open.color.r),
+                g: mix(closed.color.g, open.color.g),
+                b: mix(closed.color.b, open.color.b),
+                a: mix(closed.color.a, open.color.a),
+            },
+            rotate: mix(closed.rotate, open.rotate),
+            translate: (
+                mix(closed.translate.0, open.translate.0),
+                mix(closed.translate.1, open.translate.1),
+            ),
+            scale: (
+                mix(closed.scale.0, open.scale.0),
+                mix(closed.scale.1, open.scale.1),
+            ),
+        }
+    }
+
+    /// SVG transform placing the petal path, applied after it is turned to its place in the ring
+    pub fn svg_transform(&self) -> String {
+        format!(
+            "translate({} {}) rotate({}) scale({} {})",
+            self.translate.0, self.translate.1, self.rotate, self.scale.0, self.scale.1
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOSED: PetalPose = PetalPose {
+        color: Color {
+            r: 0.0,
+            g: 0.0,
+            b: 0.0,
+            a: 0.5,
+        },
+        rotate: 0.0,
+        translate: (0.0, 0.0),
+        scale: (0.1, 1.0),
+    };
+    const OPEN: PetalPose = PetalPose {
+        color: Color {
+            r: 1.0,
+            g: 0.0,
+            b: 0.0,
+            a: 1.0,
+        },
+        rotate: 90.0,
+        translate: (50.0, 0.0),
+        scale: (1.0, 1.0),
+    };
+
+    #[test]
+    fn test_between_ends() {
+        assert_eq!(CLOSED, PetalPose::between(&CLOSED, &OPEN, 0.0));
+        assert_eq!(OPEN, PetalPose::between(&CLOSED, &OPEN, 1.0));
+    }
+
+    #[test]
+    fn test_between_is_not_clamped() {
+        let beyond = PetalPose::between(&CLOSED, &OPEN, 2.0);
+        assert_eq!(180.0, beyond.rotate);
+        assert_eq!((100.0, 0.0), beyond.translate);
+        assert_eq!(2.0, beyond.color.r);
+
+        let below = PetalPose::between(&CLOSED, &OPEN, -1.0);
+        assert_eq!(-90.0, below.rotate);
+        assert_eq!((-50.0, 0.0), below.translate);
+    }
+
+    #[test]
+    fn test_svg_transform() {
+        assert_eq!(
+            "translate(50 0) rotate(90) scale(1 1)",
+            OPEN.svg_transform()
+        );
+    }
+}
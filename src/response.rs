@@ -0,0 +1,247 @@
+/// What the participant says about each image: familiar or not, liked or not, a rating, and how
+/// quickly they first answered
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use crate::db_session::SampleSender;
+use csv::Writer;
+use std::fmt;
+use std::fs::File;
+use std::time::Duration;
+
+pub const RESPONSE_LOG_FILE: &str = "responses.csv";
+pub const RATING_MAX: u8 = 5; // Rating keys 1 (very negative) to 5 (very positive)
+
+/// Where an answer came from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputSource {
+    ScreenButton,
+    Keyboard,
+    Gamepad,
+}
+
+impl fmt::Display for InputSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            InputSource::ScreenButton => "screen button",
+            InputSource::Keyboard => "keyboard",
+            InputSource::Gamepad => "gamepad",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// One answer about the image on screen
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResponseInput {
+    Liked(bool),    // Right is liked, left is disliked
+    Familiar(bool), // Up is familiar, down is not
+    Rating(u8),     // 1..=RATING_MAX
+}
+
+impl fmt::Display for ResponseInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResponseInput::Liked(true) => write!(f, "liked"),
+            ResponseInput::Liked(false) => write!(f, "disliked"),
+            ResponseInput::Familiar(true) => write!(f, "familiar"),
+            ResponseInput::Familiar(false) => write!(f, "unfamiliar"),
+            ResponseInput::Rating(rating) => write!(f, "rating {}", rating),
+        }
+    }
+}
+
+/// Everything answered about one stimulus, from its onset until the next onset
+#[derive(Clone, Debug, PartialEq)]
+pub struct StimulusResponse {
+    pub sequence: String, // Name of the image sequence phase
+    pub index: usize,     // Position in the sequence
+    pub image: String,
+    pub onset: Duration, // Since UNIX_EPOCH, same scale as the EEG logs
+    pub familiar: Option<bool>,
+    pub liked: Option<bool>,
+    pub rating: Option<u8>,
+    pub reaction_time: Option<Duration>, // From onset to the first answer of any kind
+    pub source: Option<InputSource>,     // Of the first answer
+}
+
+impl StimulusResponse {
+    pub fn new(sequence: &str, index: usize, image: &str, onset: Duration) -> Self {
+        Self {
+            sequence: sequence.to_string(),
+            index,
+            image: image.to_string(),
+            onset,
+            familiar: None,
+            liked: None,
+            rating: None,
+            reaction_time: None,
+            source: None,
+        }
+    }
+
+    /// Later answers replace earlier ones of the same kind, reaction time is kept from the first
+    pub fn record(&mut self, input: ResponseInput, source: InputSource, time: Duration) {
+        match input {
+            ResponseInput::Liked(liked) => self.liked = Some(liked),
+            ResponseInput::Familiar(familiar) => self.familiar = Some(familiar),
+            ResponseInput::Rating(rating) => self.rating = Some(rating.max(1).min(RATING_MAX)),
+        }
+
+        if self.reaction_time.is_none() {
+            self.reaction_time = Some(if time > self.onset {
+                time - self.onset
+            } else {
+                Duration::from_secs(0)
+            });
+            self.source = Some(source);
+        }
+    }
+}
+
+fn optional<T: fmt::Display>(value: Option<T>) -> String {
+    match value {
+        Some(value) => value.to_string(),
+        None => String::new(),
+    }
+}
+
+/// Collects answers for the stimulus on screen, writing one row per stimulus to responses.csv and
+/// the database session
+pub struct ResponseRecorder {
+    writer: Writer<File>,
+    current: Option<StimulusResponse>,
+    #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+    pub sample_sender: Option<SampleSender>, // Each response to the database session, if any
+}
+
+impl ResponseRecorder {
+    pub fn create(filename: &str) -> Self {
+        let mut writer = crate::create_log_writer(filename);
+        writer
+            .write_record(&[
+                "onset_epoch_us",
+                "onset_session_s",
+                "Sequence",
+                "Index",
+                "Image",
+                "Familiar",
+                "Liked",
+                "Rating",
+                "Reaction time ms",
+                "Input",
+            ])
+            .expect("Can not write responses.csv header");
+
+        Self {
+            writer,
+            current: None,
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            sample_sender: None,
+        }
+    }
+
+    /// A new stimulus is on screen. Answers about the previous one are written.
+    pub fn start(&mut self, response: StimulusResponse) {
+        self.finish();
+        self.current = Some(response);
+    }
+
+    /// Record an answer about the current stimulus. Returns false if no stimulus has been shown.
+    pub fn record(&mut self, input: ResponseInput, source: InputSource, time: Duration) -> bool {
+        match &mut self.current {
+            Some(response) => {
+                response.record(input, source, time);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Write the current stimulus, answered or not
+    pub fn finish(&mut self) {
+        if let Some(response) = self.current.take() {
+            let [onset_epoch_us, onset_session_s] =
+                crate::session_log::time_columns(response.onset);
+            let reaction_time_ms = optional(
+                response
+                    .reaction_time
+                    .map(|reaction_time| reaction_time.as_millis()),
+            );
+
+            self.writer
+                .write_record(&[
+                    &onset_epoch_us,
+                    &onset_session_s,
+                    &response.sequence,
+                    &response.index.to_string(),
+                    &response.image,
+                    &optional(response.familiar),
+                    &optional(response.liked),
+                    &optional(response.rating),
+                    &reaction_time_ms,
+                    &optional(response.source),
+                ])
+                .expect("Can not add row to responses.csv");
+
+            #[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+            {
+                if let Some(sample_sender) = &self.sample_sender {
+                    sample_sender.response(&response);
+                }
+            }
+        }
+    }
+
+    /// Write any pending responses to disk
+    pub fn flush(&mut self) {
+        self.finish();
+        let _r = self.writer.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response() -> StimulusResponse {
+        StimulusResponse::new(
+            "negative",
+            3,
+            "negative-images/n4.png",
+            Duration::from_secs(100),
+        )
+    }
+
+    #[test]
+    fn test_reaction_time_from_first_answer() {
+        let mut response = response();
+        response.record(
+            ResponseInput::Familiar(true),
+            InputSource::Gamepad,
+            Duration::from_millis(100_750),
+        );
+        response.record(
+            ResponseInput::Liked(false),
+            InputSource::Keyboard,
+            Duration::from_millis(101_500),
+        );
+
+        assert_eq!(Some(Duration::from_millis(750)), response.reaction_time);
+        assert_eq!(Some(InputSource::Gamepad), response.source);
+        assert_eq!(Some(true), response.familiar);
+        assert_eq!(Some(false), response.liked);
+        assert_eq!(None, response.rating);
+    }
+
+    #[test]
+    fn test_later_answer_replaces_earlier() {
+        let mut response = response();
+        let time = Duration::from_secs(101);
+        response.record(ResponseInput::Liked(false), InputSource::ScreenButton, time);
+        response.record(ResponseInput::Liked(true), InputSource::ScreenButton, time);
+        response.record(ResponseInput::Rating(9), InputSource::Keyboard, time);
+
+        assert_eq!(Some(true), response.liked);
+        assert_eq!(Some(RATING_MAX), response.rating);
+    }
+}
@@ -0,0 +1,214 @@
+/// Which rig this app is running on. Chosen once at startup, before the window opens, from
+/// MEME_RIG (in the environment or .env) or by picking one of the active rigs in the database.
+/// The rig is written at the top of every CSV log, stored with the database session and shown on
+/// the operator screens, so data from several stations at one event can be told apart.
+use std::env;
+use std::fmt;
+use std::sync::OnceLock;
+
+pub const ENV_RIG: &str = "MEME_RIG"; // Exact title or id of the rig this app runs on, see examples/rig.rs
+
+static SELECTED_RIG: OnceLock<RigIdentity> = OnceLock::new();
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RigIdentity {
+    pub id: Option<i32>, // None if the rig is not in the database
+    pub title: String,
+}
+
+impl RigIdentity {
+    pub fn unknown() -> Self {
+        Self {
+            id: None,
+            title: "unknown rig".to_string(),
+        }
+    }
+
+    /// First line of each CSV log. Read the logs with '#' as the comment character.
+    pub fn csv_comment(&self) -> String {
+        let id = match self.id {
+            Some(id) => id.to_string(),
+            None => String::new(),
+        };
+
+        format!(
+            "# rig_id: {}, rig_title: {}",
+            id,
+            self.title.replace(|c: char| c.is_control(), " ")
+        )
+    }
+}
+
+impl fmt::Display for RigIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.id {
+            Some(id) => write!(f, "Rig {} ({})", self.title, id),
+            None => write!(f, "Rig {}", self.title),
+        }
+    }
+}
+
+/// Choose the rig for this run. Call once from main() before anything is logged.
+pub fn select() -> &'static RigIdentity {
+    SELECTED_RIG.get_or_init(|| {
+        let rig = choose();
+        info!("Running on {}", rig);
+        rig
+    })
+}
+
+/// The rig chosen at startup
+pub fn selected() -> RigIdentity {
+    SELECTED_RIG
+        .get()
+        .cloned()
+        .unwrap_or_else(RigIdentity::unknown)
+}
+
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+fn choose() -> RigIdentity {
+    match env::var(ENV_RIG) {
+        Ok(title) => RigIdentity { id: None, title },
+        Err(_) => RigIdentity::unknown(),
+    }
+}
+
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn choose() -> RigIdentity {
+    use meme_machine_database as database;
+
+    let database_url = database::database_url(); // Also reads .env, which may set MEME_RIG
+    let configured = env::var(ENV_RIG).ok();
+    if database_url.is_none() {
+        return match configured {
+            Some(title) => RigIdentity { id: None, title },
+            None => RigIdentity::unknown(),
+        };
+    }
+    let connection = match database::try_establish_connection() {
+        Ok(connection) => connection,
+        Err(e) => {
+            warn!("Can not look up the rig: {}", e);
+            return match configured {
+                Some(title) => RigIdentity { id: None, title },
+                None => RigIdentity::unknown(),
+            };
+        }
+    };
+    let rigs = match database::list_rigs(&connection, false) {
+        Ok(rigs) => rigs,
+        Err(e) => {
+            warn!("Can not list rigs: {}", e);
+            Vec::new()
+        }
+    };
+    let rigs: Vec<RigIdentity> = rigs
+        .into_iter()
+        .map(|rig| RigIdentity {
+            id: Some(rig.id),
+            title: rig.title,
+        })
+        .collect();
+
+    match configured {
+        Some(configured) => match find(&rigs, &configured) {
+            Some(rig) => rig,
+            None => {
+                warn!(
+                    "{} '{}' is not an active rig in the database",
+                    ENV_RIG, configured
+                );
+                RigIdentity {
+                    id: None,
+                    title: configured,
+                }
+            }
+        },
+        None => match rigs.len() {
+            0 => RigIdentity::unknown(),
+            1 => rigs[0].clone(),
+            _ => choose_from(&rigs),
+        },
+    }
+}
+
+/// A rig by exact title, or by id if no rig has that title
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn find(rigs: &[RigIdentity], configured: &str) -> Option<RigIdentity> {
+    rigs.iter()
+        .find(|rig| rig.title == configured)
+        .or_else(|| match configured.parse::<i32>() {
+            Ok(id) => rigs.iter().find(|rig| rig.id == Some(id)),
+            Err(_) => None,
+        })
+        .cloned()
+}
+
+/// Ask at the console which of several active rigs this is
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+fn choose_from(rigs: &[RigIdentity]) -> RigIdentity {
+    use std::io::stdin;
+
+    println!(
+        "\nWhich rig is this? Set {} to skip this question.",
+        ENV_RIG
+    );
+    for (i, rig) in rigs.iter().enumerate() {
+        println!("  {}: {}", i + 1, rig);
+    }
+
+    loop {
+        let mut answer = String::new();
+        match stdin().read_line(&mut answer) {
+            Ok(0) | Err(_) => {
+                warn!("No rig chosen, there is no console to ask at");
+                return RigIdentity::unknown();
+            }
+            Ok(_) => (),
+        }
+        match answer.trim().parse::<usize>() {
+            Ok(n) if n >= 1 && n <= rigs.len() => return rigs[n - 1].clone(),
+            _ => println!("Please enter a number from 1 to {}", rigs.len()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rigs() -> Vec<RigIdentity> {
+        vec![
+            RigIdentity {
+                id: Some(3),
+                title: "Lab rig 1".to_string(),
+            },
+            RigIdentity {
+                id: Some(7),
+                title: "12".to_string(),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_find_by_title_then_id() {
+        assert_eq!(Some(3), find(&rigs(), "Lab rig 1").unwrap().id);
+        assert_eq!(Some(7), find(&rigs(), "12").unwrap().id);
+        assert_eq!("Lab rig 1", find(&rigs(), "3").unwrap().title);
+        assert_eq!(None, find(&rigs(), "lab rig 1"));
+    }
+
+    #[test]
+    fn test_csv_comment() {
+        let rig = RigIdentity {
+            id: Some(3),
+            title: "Lab\nrig 1".to_string(),
+        };
+
+        assert_eq!("# rig_id: 3, rig_title: Lab rig 1", rig.csv_comment());
+        assert_eq!(
+            "# rig_id: , rig_title: unknown rig",
+            RigIdentity::unknown().csv_comment()
+        );
+    }
+}
@@ -0,0 +1,291 @@
+/// Reconstruct the time each sample was taken. MindMonitor sends many samples between screen
+/// updates, and without a bundle timetag they would all share the time the app received them.
+use crate::muse_model::{MuseMessage, MuseMessageType};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::time::Duration;
+
+const EEG_RATE: f32 = 256.0; // Nominal samples per second for each stream
+const MOTION_RATE: f32 = 52.0;
+const ELEMENTS_RATE: f32 = 10.0;
+const GAP_PERIODS: f32 = 1.5; // Missing samples if the time between samples is this many periods
+const CLOCK_JUMP: Duration = Duration::from_secs(5); // Time moving by more than this is a clock change, not a gap
+const RATE_WINDOW: Duration = Duration::from_secs(5); // Effective sample rate is measured over this period
+
+/// Name of the stream a message belongs to, used to group samples taken at a regular rate
+pub fn stream_name(muse_message_type: &MuseMessageType) -> &'static str {
+    match muse_message_type {
+        MuseMessageType::Eeg { .. } => "eeg",
+        MuseMessageType::Accelerometer { .. } => "accelerometer",
+        MuseMessageType::Gyro { .. } => "gyro",
+        MuseMessageType::Alpha { .. } => "alpha",
+        MuseMessageType::Beta { .. } => "beta",
+        MuseMessageType::Gamma { .. } => "gamma",
+        MuseMessageType::Delta { .. } => "delta",
+        MuseMessageType::Theta { .. } => "theta",
+        MuseMessageType::Batt { .. } => "battery",
+        MuseMessageType::Horseshoe { .. } => "horseshoe",
+        MuseMessageType::TouchingForehead { .. } => "touching_forehead",
+        MuseMessageType::Blink { .. } => "blink",
+        MuseMessageType::JawClench { .. } => "jaw_clench",
+    }
+}
+
+/// Samples per second the Muse sends for a stream, or None for irregular events such as blinks
+pub fn nominal_rate(stream: &str) -> Option<f32> {
+    match stream {
+        "eeg" => Some(EEG_RATE),
+        "accelerometer" | "gyro" => Some(MOTION_RATE),
+        "alpha" | "beta" | "gamma" | "delta" | "theta" | "horseshoe" | "touching_forehead" => {
+            Some(ELEMENTS_RATE)
+        }
+        _ => None,
+    }
+}
+
+/// Sample timing of one stream, and problems seen in that timing
+pub struct StreamClock {
+    period: Option<Duration>,
+    last_time: Option<Duration>,
+    recent_arrivals: VecDeque<(Duration, usize)>, // Arrival time and number of samples
+    pub sample_count: u64,
+    pub gaps: u64,
+    pub duplicates: u64,
+    pub clock_jumps: u64,
+}
+
+impl StreamClock {
+    pub fn new(nominal_rate: Option<f32>) -> Self {
+        Self {
+            period: nominal_rate.map(|rate| Duration::from_secs_f32(1.0 / rate)),
+            last_time: None,
+            recent_arrivals: VecDeque::new(),
+            sample_count: 0,
+            gaps: 0,
+            duplicates: 0,
+            clock_jumps: 0,
+        }
+    }
+
+    /// Sample times for 'count' samples which all arrived stamped with 'time'. They are spread
+    /// back from that time at the nominal rate, but never before the previous sample.
+    pub fn stamp(&mut self, time: Duration, count: usize) -> Vec<Duration> {
+        let times = match (self.period, count) {
+            (Some(period), n) if n > 1 => {
+                let span = period * (n as u32 - 1);
+                let mut first = time.checked_sub(span).unwrap_or(time);
+                let mut spacing = period;
+
+                if let Some(last_time) = self.last_time {
+                    let earliest = last_time + period;
+                    if first < earliest && earliest <= time {
+                        // More samples than the nominal rate allows since the last ones, squeeze them in
+                        first = earliest;
+                        spacing = (time - earliest) / (n as u32 - 1);
+                    }
+                }
+
+                (0..n as u32).map(|i| first + spacing * i).collect()
+            }
+            (_, n) => vec![time; n],
+        };
+
+        self.record_arrival(time, count);
+        for t in &times {
+            self.observe(*t);
+        }
+
+        times
+    }
+
+    /// Check the time of the next sample against the previous one
+    fn observe(&mut self, time: Duration) {
+        if let Some(last_time) = self.last_time {
+            if time <= last_time {
+                if last_time - time > CLOCK_JUMP {
+                    self.clock_jumps = self.clock_jumps + 1;
+                } else {
+                    self.duplicates = self.duplicates + 1;
+                }
+            } else {
+                let step = time - last_time;
+                if step > CLOCK_JUMP {
+                    self.clock_jumps = self.clock_jumps + 1;
+                } else if let Some(period) = self.period {
+                    if step.as_secs_f32() > GAP_PERIODS * period.as_secs_f32() {
+                        self.gaps = self.gaps + 1;
+                    }
+                }
+            }
+        }
+
+        self.last_time = Some(time);
+        self.sample_count = self.sample_count + 1;
+    }
+
+    fn record_arrival(&mut self, time: Duration, count: usize) {
+        self.recent_arrivals.push_back((time, count));
+        while let Some((oldest, _)) = self.recent_arrivals.front() {
+            if time > *oldest + RATE_WINDOW || time < *oldest {
+                self.recent_arrivals.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    /// Samples per second actually received over the last few seconds
+    pub fn effective_rate(&self) -> Option<f32> {
+        match (self.recent_arrivals.front(), self.recent_arrivals.back()) {
+            (Some((first, _)), Some((last, _))) if last > first => {
+                let samples: usize = self.recent_arrivals.iter().skip(1).map(|(_, n)| n).sum();
+
+                Some(samples as f32 / (*last - *first).as_secs_f32())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Sample timing of every stream from the headset
+pub struct SampleTiming {
+    clocks: HashMap<&'static str, StreamClock>,
+}
+
+impl SampleTiming {
+    pub fn new() -> Self {
+        Self {
+            clocks: HashMap::new(),
+        }
+    }
+
+    /// Replace the shared arrival time of samples received together with the time each was taken
+    pub fn stamp_messages(&mut self, muse_messages: &mut [MuseMessage]) {
+        let mut groups: Vec<(&'static str, Duration, Vec<usize>)> = Vec::new();
+
+        for (i, muse_message) in muse_messages.iter().enumerate() {
+            let stream = stream_name(&muse_message.muse_message_type);
+            match groups
+                .iter_mut()
+                .find(|(s, time, _)| *s == stream && *time == muse_message.time)
+            {
+                Some((_, _, indexes)) => indexes.push(i),
+                None => groups.push((stream, muse_message.time, vec![i])),
+            }
+        }
+
+        for (stream, time, indexes) in groups {
+            let clock = self
+                .clocks
+                .entry(stream)
+                .or_insert_with(|| StreamClock::new(nominal_rate(stream)));
+            let times = clock.stamp(time, indexes.len());
+
+            for (i, t) in indexes.into_iter().zip(times) {
+                muse_messages[i].time = t;
+            }
+        }
+    }
+
+    /// Timing of a stream such as "eeg", if any samples have arrived
+    pub fn clock(&self, stream: &str) -> Option<&StreamClock> {
+        self.clocks.get(stream)
+    }
+}
+
+impl fmt::Display for StreamClock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let rate = match self.effective_rate() {
+            Some(rate) => format!("{:.0}Hz", rate),
+            None => "-".to_string(),
+        };
+
+        write!(
+            f,
+            "{}, {} gaps, {} duplicates, {} clock jumps",
+            rate, self.gaps, self.duplicates, self.clock_jumps
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn test_spread_back_from_arrival() {
+        let mut clock = StreamClock::new(Some(100.0));
+        let times = clock.stamp(ms(1000), 3);
+
+        assert_eq!(vec![ms(980), ms(990), ms(1000)], times);
+        assert_eq!(0, clock.gaps);
+        assert_eq!(0, clock.duplicates);
+    }
+
+    #[test]
+    fn test_never_before_previous_sample() {
+        let mut clock = StreamClock::new(Some(100.0));
+        clock.stamp(ms(1000), 1);
+        let times = clock.stamp(ms(1020), 5);
+
+        let us = Duration::from_micros;
+        assert_eq!(
+            vec![
+                us(1_010_000),
+                us(1_012_500),
+                us(1_015_000),
+                us(1_017_500),
+                us(1_020_000)
+            ],
+            times
+        );
+        assert_eq!(0, clock.duplicates);
+    }
+
+    #[test]
+    fn test_gap() {
+        let mut clock = StreamClock::new(Some(100.0));
+        clock.stamp(ms(1000), 1);
+        clock.stamp(ms(1010), 1);
+        clock.stamp(ms(1100), 1);
+
+        assert_eq!(1, clock.gaps);
+        assert_eq!(3, clock.sample_count);
+    }
+
+    #[test]
+    fn test_duplicate_and_clock_jump() {
+        let mut clock = StreamClock::new(Some(100.0));
+        clock.stamp(ms(10_000), 1);
+        clock.stamp(ms(10_000), 1);
+        assert_eq!(1, clock.duplicates);
+
+        clock.stamp(ms(1_000), 1);
+        clock.stamp(ms(60_000), 1);
+        assert_eq!(2, clock.clock_jumps);
+    }
+
+    #[test]
+    fn test_effective_rate() {
+        let mut clock = StreamClock::new(Some(256.0));
+        assert_eq!(None, clock.effective_rate());
+
+        for i in 0..=10 {
+            clock.stamp(ms(1000 + i * 100), 25);
+        }
+
+        assert_eq!(Some(250.0), clock.effective_rate());
+    }
+
+    #[test]
+    fn test_irregular_events_not_spread() {
+        let mut clock = StreamClock::new(nominal_rate("blink"));
+        let times = clock.stamp(ms(500), 2);
+
+        assert_eq!(vec![ms(500), ms(500)], times);
+    }
+}
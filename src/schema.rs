@@ -1,8 +1,132 @@
+table! {
+    band_powers (id) {
+        id -> Int8,
+        session_id -> Int4,
+        time -> Timestamp,
+        band -> Varchar,
+        tp9 -> Float4,
+        af7 -> Float4,
+        af8 -> Float4,
+        tp10 -> Float4,
+    }
+}
+
+table! {
+    eeg_samples (id) {
+        id -> Int8,
+        session_id -> Int4,
+        time -> Timestamp,
+        tp9 -> Float4,
+        af7 -> Float4,
+        af8 -> Float4,
+        tp10 -> Float4,
+        aux_right -> Nullable<Float4>,
+        aux_left -> Nullable<Float4>,
+    }
+}
+
+table! {
+    events (id) {
+        id -> Int4,
+        session_id -> Int4,
+        time -> Timestamp,
+        event -> Varchar,
+        detail -> Text,
+    }
+}
+
+table! {
+    participants (id) {
+        id -> Int4,
+        code -> Varchar,
+        created_at -> Timestamp,
+    }
+}
+
+table! {
+    responses (id) {
+        id -> Int4,
+        session_id -> Int4,
+        stimulus_id -> Nullable<Int4>,
+        familiar -> Nullable<Bool>,
+        liked -> Nullable<Bool>,
+        rating -> Nullable<Int2>,
+        reaction_time_ms -> Nullable<Int4>,
+        input -> Nullable<Varchar>,
+    }
+}
+
 table! {
     rigs (id) {
         id -> Int4,
         title -> Varchar,
         body -> Text,
         active -> Bool,
+        headset_serial -> Nullable<Varchar>,
+        screen -> Nullable<Varchar>,
+        input_devices -> Nullable<Varchar>,
+        location -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    sessions (id) {
+        id -> Int4,
+        rig_id -> Nullable<Int4>,
+        participant_id -> Nullable<Int4>,
+        started_at -> Timestamp,
+        ended_at -> Nullable<Timestamp>,
+        protocol_version -> Varchar,
+        valence_mean -> Nullable<Float4>,
+        valence_deviation -> Nullable<Float4>,
+        arousal_mean -> Nullable<Float4>,
+        arousal_deviation -> Nullable<Float4>,
+        synced_session_id -> Nullable<Int4>,
+        rejected_percent -> Nullable<Float4>,
+    }
+}
+
+table! {
+    stimuli (id) {
+        id -> Int4,
+        session_id -> Int4,
+        sequence -> Varchar,
+        position -> Int4,
+        image -> Varchar,
+        onset -> Timestamp,
+    }
+}
+
+table! {
+    valence_arousal (id) {
+        id -> Int8,
+        session_id -> Int4,
+        time -> Timestamp,
+        valence -> Nullable<Float4>,
+        arousal -> Nullable<Float4>,
+        normalized_valence -> Nullable<Float4>,
+        normalized_arousal -> Nullable<Float4>,
     }
 }
+
+joinable!(band_powers -> sessions (session_id));
+joinable!(eeg_samples -> sessions (session_id));
+joinable!(events -> sessions (session_id));
+joinable!(responses -> sessions (session_id));
+joinable!(responses -> stimuli (stimulus_id));
+joinable!(sessions -> participants (participant_id));
+joinable!(sessions -> rigs (rig_id));
+joinable!(stimuli -> sessions (session_id));
+joinable!(valence_arousal -> sessions (session_id));
+
+allow_tables_to_appear_in_same_query!(
+    band_powers,
+    eeg_samples,
+    events,
+    participants,
+    responses,
+    rigs,
+    sessions,
+    stimuli,
+    valence_arousal,
+);
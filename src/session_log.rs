@@ -0,0 +1,275 @@
+/// Every file written during a session goes in its own directory under MEME_LOG_ROOT, named by
+/// the start time and database session id, so one run never overwrites another. manifest.json
+/// there says which rig, app version, configuration and protocol produced the files.
+use crate::rig::RigIdentity;
+use crate::timeline::Protocol;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const ENV_LOG_ROOT: &str = "MEME_LOG_ROOT"; // Directory holding one subdirectory per session
+const DEFAULT_LOG_ROOT: &str = "log";
+pub const MANIFEST_FILE: &str = "manifest.json";
+const ENV_PREFIX: &str = "MEME_"; // Settings from the environment which go in the manifest
+const NOT_CONFIG: [&str; 3] = [crate::rig::ENV_RIG, "MEME_PARTICIPANT", ENV_LOG_ROOT]; // Which run this is, not how the app is configured
+
+pub const CSV_SCHEMA_VERSION: u32 = 3; // Layout of the CSV logs. 2: numeric epoch_us and session_s time columns, 3: other.csv split into one file per sensor
+
+static SESSION_DIR: OnceLock<PathBuf> = OnceLock::new();
+static SESSION_START: OnceLock<Duration> = OnceLock::new();
+
+/// Create the directory for this session. Call once, before any log file is created.
+pub fn open(started_at: SystemTime, session_id: Option<i32>) -> &'static Path {
+    SESSION_DIR.get_or_init(|| {
+        let root = env::var(ENV_LOG_ROOT).unwrap_or(DEFAULT_LOG_ROOT.to_string());
+
+        match create_dir(Path::new(&root), &dir_name(started_at, session_id)) {
+            Ok(dir) => {
+                info!("Logging this session to {:?}", dir);
+                dir
+            }
+            Err(e) => {
+                error!(
+                    "Can not create a session directory in {}, logging to the current directory: {}",
+                    root, e
+                );
+                PathBuf::from(".")
+            }
+        }
+    })
+}
+
+/// Where to write a log file of this session
+pub fn path(filename: &str) -> PathBuf {
+    match SESSION_DIR.get() {
+        Some(dir) => dir.join(filename),
+        None => PathBuf::from(filename),
+    }
+}
+
+/// The directory of this session, or the current directory before open()
+pub fn dir() -> PathBuf {
+    match SESSION_DIR.get() {
+        Some(dir) => dir.clone(),
+        None => PathBuf::from("."),
+    }
+}
+
+/// Set the time session_s counts from, on the clock of the EEG. Call once as the session starts.
+pub fn start_clock(time: Duration) {
+    let _result = SESSION_START.set(time);
+}
+
+/// Second line of each CSV log, after the rig
+pub fn schema_comment() -> String {
+    format!("# schema_version: {}", CSV_SCHEMA_VERSION)
+}
+
+/// The time of a row as microseconds since UNIX_EPOCH and seconds since the session started
+pub fn time_columns(time: Duration) -> [String; 2] {
+    let start = SESSION_START.get().cloned().unwrap_or_default();
+
+    session_time_columns(time, start)
+}
+
+/// Seconds from the start of the session, negative before it
+pub fn session_seconds(time: Duration) -> f64 {
+    seconds_since(time, SESSION_START.get().cloned().unwrap_or_default())
+}
+
+fn seconds_since(time: Duration, start: Duration) -> f64 {
+    if time >= start {
+        (time - start).as_secs_f64()
+    } else {
+        -(start - time).as_secs_f64()
+    }
+}
+
+fn session_time_columns(time: Duration, start: Duration) -> [String; 2] {
+    [
+        time.as_micros().to_string(),
+        format!("{:.6}", seconds_since(time, start)),
+    ]
+}
+
+/// For example "2020-05-02_10-00-00_session_17", UTC. Without a database session only the time.
+fn dir_name(started_at: SystemTime, session_id: Option<i32>) -> String {
+    let time = DateTime::<Utc>::from(started_at).format("%Y-%m-%d_%H-%M-%S");
+
+    match session_id {
+        Some(session_id) => format!("{}_session_{}", time, session_id),
+        None => time.to_string(),
+    }
+}
+
+/// A new directory, with a numbered suffix if two sessions start in the same second
+fn create_dir(root: &Path, name: &str) -> io::Result<PathBuf> {
+    fs::create_dir_all(root)?;
+    let mut dir = root.join(name);
+    let mut n = 1;
+
+    loop {
+        match fs::create_dir(&dir) {
+            Ok(()) => return Ok(dir),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                n = n + 1;
+                dir = root.join(format!("{}_{}", name, n));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// What produced the files in a session directory
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub session_id: Option<i32>, // Database session, None without a database
+    pub started_at: String,      // UTC
+    pub started_at_us: u64,      // Microseconds since UNIX_EPOCH, the clock of every log file
+    pub ended_at: Option<String>,
+    pub ended_at_us: Option<u64>,
+    pub rig_id: Option<i32>,
+    pub rig_title: String,
+    pub app_version: String,
+    pub csv_schema_version: u32,
+    pub config_hash: String, // Equal for sessions run with the same protocol and settings
+    pub config: BTreeMap<String, String>, // MEME_* settings from the environment
+    pub protocol_file: String,
+    pub protocol: Protocol,
+}
+
+impl Manifest {
+    pub fn new(
+        session_id: Option<i32>,
+        started_at: SystemTime,
+        rig: &RigIdentity,
+        protocol_file: &str,
+        protocol: &Protocol,
+    ) -> Self {
+        let config = config_from_env();
+
+        Self {
+            session_id,
+            started_at: utc(started_at),
+            started_at_us: epoch_us(started_at),
+            ended_at: None,
+            ended_at_us: None,
+            rig_id: rig.id,
+            rig_title: rig.title.clone(),
+            app_version: env!("CARGO_PKG_VERSION").to_string(),
+            csv_schema_version: CSV_SCHEMA_VERSION,
+            config_hash: config_hash(&config, protocol),
+            config,
+            protocol_file: protocol_file.to_string(),
+            protocol: protocol.clone(),
+        }
+    }
+
+    pub fn end(&mut self, ended_at: SystemTime) {
+        self.ended_at = Some(utc(ended_at));
+        self.ended_at_us = Some(epoch_us(ended_at));
+    }
+
+    /// Write manifest.json in the session directory, replacing any earlier version
+    pub fn save(&self) {
+        let path = path(MANIFEST_FILE);
+
+        if let Err(e) = self.write(&path) {
+            error!("Can not write {:?}: {}", path, e);
+        }
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", json)
+    }
+}
+
+fn utc(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).to_rfc3339()
+}
+
+fn epoch_us(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0))
+        .as_micros() as u64
+}
+
+fn config_from_env() -> BTreeMap<String, String> {
+    env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && !NOT_CONFIG.contains(&name.as_str()))
+        .collect()
+}
+
+/// 64 bit FNV-1a of the settings and protocol, stable across builds and platforms
+fn config_hash(config: &BTreeMap<String, String>, protocol: &Protocol) -> String {
+    let json = serde_json::to_string(&(config, protocol)).expect("Can not serialize the config");
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in json.bytes() {
+        hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+    }
+
+    format!("{:016x}", hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dir_name() {
+        let started_at = UNIX_EPOCH + Duration::from_millis(1_588_413_600_250);
+
+        assert_eq!(
+            "2020-05-02_10-00-00_session_17",
+            dir_name(started_at, Some(17))
+        );
+        assert_eq!("2020-05-02_10-00-00", dir_name(started_at, None));
+    }
+
+    #[test]
+    fn test_time_columns() {
+        let start = Duration::from_micros(1_588_413_600_250_000);
+
+        assert_eq!(
+            ["1588413601750001".to_string(), "1.500001".to_string()],
+            session_time_columns(start + Duration::from_micros(1_500_001), start)
+        );
+        assert_eq!(
+            ["1588413600000000".to_string(), "-0.250000".to_string()],
+            session_time_columns(start - Duration::from_millis(250), start)
+        );
+    }
+
+    #[test]
+    fn test_same_second_gets_new_dir() {
+        let root = env::temp_dir().join(format!("meme_session_log_test_{}", std::process::id()));
+        let first = create_dir(&root, "2020-05-02_10-00-00").unwrap();
+        let second = create_dir(&root, "2020-05-02_10-00-00").unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(root.join("2020-05-02_10-00-00"), first);
+        assert_eq!(root.join("2020-05-02_10-00-00_2"), second);
+    }
+
+    #[test]
+    fn test_config_hash() {
+        let mut config = BTreeMap::new();
+        config.insert("MEME_FILTER".to_string(), "60hz".to_string());
+        let protocol = Protocol::default();
+        let hash = config_hash(&config, &protocol);
+
+        assert_eq!(16, hash.len());
+        assert_eq!(hash, config_hash(&config.clone(), &protocol));
+        config.insert("MEME_FILTER".to_string(), "50hz".to_string());
+        assert_ne!(hash, config_hash(&config, &protocol));
+    }
+}
@@ -0,0 +1,418 @@
+/// The course of valence and arousal over a whole session, averaged per phase, and a shareable
+/// summary card drawn from it
+use crate::*;
+use svg::node::element::path::Data;
+use svg::node::element::{Group, Path, Rectangle, Text};
+use svg::Document;
+
+pub const SUMMARY_CARD_FILE: &str = "summary.svg";
+
+const CARD_SIZE: (f32, f32) = (1200.0, 675.0); // 16:9, the shape social media previews expect
+const CARD_MANDALA_CENTER: (f32, f32) = (300.0, 360.0);
+const CARD_MANDALA_SCALE: f32 = CARD_SIZE.1 / SCREEN_SIZE.1; // The mandala fills the same share of the card as of the screen
+const CARD_PLOT: (f32, f32, f32, f32) = (640.0, 120.0, 520.0, 240.0); // Left, top, width, height of the trajectory
+const CARD_AVERAGES_TOP: f32 = 430.0;
+const CARD_LINE_HEIGHT: f32 = 30.0;
+const PLOT_RANGE: f32 = 3.0; // Normalized values beyond this are drawn at the edge of the plot
+const MAX_PLOT_POINTS: usize = 400; // Longer trajectories are thinned to this many points
+const PETAL_DIR: &str = "static"; // The petal SVGs the app's mandalas are drawn from
+
+/// Normalized valence and arousal at one moment
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SummarySample {
+    pub seconds: f32, // Since the app started
+    pub valence: f32,
+    pub arousal: f32,
+}
+
+/// Running average of valence and arousal during one phase
+#[derive(Clone, Debug, PartialEq)]
+pub struct PhaseAverage {
+    pub label: String,
+    valence_sum: f32,
+    arousal_sum: f32,
+    pub count: usize,
+}
+
+impl PhaseAverage {
+    pub fn valence(&self) -> Option<f32> {
+        match self.count {
+            0 => None,
+            count => Some(self.valence_sum / count as f32),
+        }
+    }
+
+    pub fn arousal(&self) -> Option<f32> {
+        match self.count {
+            0 => None,
+            count => Some(self.arousal_sum / count as f32),
+        }
+    }
+}
+
+/// Collects normalized valence and arousal as the session runs
+pub struct SessionSummary {
+    pub samples: Vec<SummarySample>,
+    pub phases: Vec<PhaseAverage>,
+    valence: Option<f32>,
+    arousal: Option<f32>,
+}
+
+impl SessionSummary {
+    pub fn new() -> Self {
+        Self {
+            samples: Vec::new(),
+            phases: Vec::new(),
+            valence: None,
+            arousal: None,
+        }
+    }
+
+    /// Following samples count towards this phase
+    pub fn start_phase(&mut self, label: &str) {
+        self.phases.push(PhaseAverage {
+            label: label.to_string(),
+            valence_sum: 0.0,
+            arousal_sum: 0.0,
+            count: 0,
+        });
+    }
+
+    /// Add a sample if either value is new. Nothing is added until both are known.
+    pub fn add(&mut self, seconds: f32, valence: Option<f32>, arousal: Option<f32>) {
+        let valence = valence.filter(|v| v.is_finite());
+        let arousal = arousal.filter(|a| a.is_finite());
+        if valence.is_none() && arousal.is_none() {
+            return;
+        }
+        self.valence = valence.or(self.valence);
+        self.arousal = arousal.or(self.arousal);
+
+        if let (Some(valence), Some(arousal)) = (self.valence, self.arousal) {
+            self.samples.push(SummarySample {
+                seconds,
+                valence,
+                arousal,
+            });
+            if let Some(phase) = self.phases.last_mut() {
+                phase.valence_sum = phase.valence_sum + valence;
+                phase.arousal_sum = phase.arousal_sum + arousal;
+                phase.count = phase.count + 1;
+            }
+        }
+    }
+
+    /// Phases which had valence and arousal, in order
+    pub fn phase_averages(&self) -> Vec<&PhaseAverage> {
+        self.phases.iter().filter(|phase| phase.count > 0).collect()
+    }
+
+    /// Every sample of the session, thinned to at most MAX_PLOT_POINTS
+    pub fn plot_samples(&self) -> Vec<SummarySample> {
+        let step = (self.samples.len() + MAX_PLOT_POINTS - 1) / MAX_PLOT_POINTS;
+
+        self.samples.iter().step_by(step.max(1)).cloned().collect()
+    }
+
+    /// One line of text per phase average
+    pub fn phase_average_lines(&self) -> Vec<String> {
+        self.phase_averages()
+            .iter()
+            .map(|phase| {
+                format!(
+                    "{}: valence {:+.2}, arousal {:+.2}",
+                    phase.label,
+                    phase.valence().unwrap_or(0.0),
+                    phase.arousal().unwrap_or(0.0)
+                )
+            })
+            .collect()
+    }
+
+    /// The summary card with the final mandala, the valence and arousal trajectory and per-phase averages
+    pub fn to_svg(&self) -> Document {
+        // The last values given to the mandalas, or where they settle at startup
+        let (final_valence, final_arousal) = match self.samples.last() {
+            Some(sample) => (sample.valence, sample.arousal),
+            None => (0.0, 1.0),
+        };
+
+        let mut document = Document::new()
+            .set("viewBox", (0, 0, CARD_SIZE.0 as i32, CARD_SIZE.1 as i32))
+            .set("width", CARD_SIZE.0)
+            .set("height", CARD_SIZE.1)
+            .add(
+                Rectangle::new()
+                    .set("width", CARD_SIZE.0)
+                    .set("height", CARD_SIZE.1)
+                    .set("fill", svg_color(COLOR_BACKGROUND)),
+            )
+            .add(svg_text(
+                CARD_SIZE.0 / 2.0,
+                60.0,
+                44.0,
+                COLOR_TITLE,
+                &format!("{} - Thank You", STR_TITLE),
+            ));
+
+        // FINAL MANDALA
+        let valence_pose = PetalPose::between(
+            &POSE_VALENCE_MANDALA_CLOSED,
+            &POSE_VALENCE_MANDALA_OPEN,
+            final_valence,
+        );
+        if let Some(petal) = load_petal_path(MANDALA_VALENCE_PETAL_SVG_NAME) {
+            document = document.add(svg_mandala(&petal, MANDALA_VALENCE_PETALS, &valence_pose));
+        }
+        let arousal_pose = PetalPose::between(
+            &POSE_AROUSAL_MANDALA_CLOSED,
+            &POSE_AROUSAL_MANDALA_OPEN,
+            final_arousal,
+        );
+        if let Some(petal) = load_petal_path(MANDALA_AROUSAL_PETAL_SVG_NAME) {
+            document = document.add(svg_mandala(&petal, MANDALA_AROUSAL_PETALS, &arousal_pose));
+        }
+
+        // TRAJECTORY
+        let (left, top, width, height) = CARD_PLOT;
+        document = document.add(
+            Rectangle::new()
+                .set("x", left)
+                .set("y", top)
+                .set("width", width)
+                .set("height", height)
+                .set("fill", "none")
+                .set("stroke", svg_color(COLOR_TEXT)),
+        );
+        let samples = self.plot_samples();
+        if samples.len() > 1 {
+            document = document
+                .add(svg_trajectory(
+                    &samples,
+                    |s| s.valence,
+                    COLOR_VALENCE_MANDALA_OPEN,
+                ))
+                .add(svg_trajectory(
+                    &samples,
+                    |s| s.arousal,
+                    COLOR_AROUSAL_MANDALA_OPEN,
+                ));
+        }
+        document = document
+            .add(svg_text(
+                left + width / 4.0,
+                top + height + 30.0,
+                22.0,
+                COLOR_VALENCE_MANDALA_OPEN,
+                "valence",
+            ))
+            .add(svg_text(
+                left + 3.0 * width / 4.0,
+                top + height + 30.0,
+                22.0,
+                COLOR_AROUSAL_MANDALA_OPEN,
+                "arousal",
+            ));
+
+        // PER-PHASE AVERAGES
+        for (i, line) in self.phase_average_lines().iter().enumerate() {
+            document = document.add(svg_text(
+                left + width / 2.0,
+                CARD_AVERAGES_TOP + i as f32 * CARD_LINE_HEIGHT,
+                20.0,
+                COLOR_TEXT,
+                line,
+            ));
+        }
+
+        document
+    }
+
+    pub fn save_svg(&self, path: &std::path::Path) -> std::io::Result<()> {
+        svg::save(path, &self.to_svg())
+    }
+}
+
+/// Position of a value on the vertical axis of a plot from 'top', 'height' pixels high
+pub fn plot_y(value: f32, top: f32, height: f32) -> f32 {
+    let bounded = value.max(-PLOT_RANGE).min(PLOT_RANGE);
+
+    top + height / 2.0 - bounded / PLOT_RANGE * height / 2.0
+}
+
+/// Position of a sample on the horizontal axis of a plot from 'left', 'width' pixels wide
+pub fn plot_x(samples: &[SummarySample], i: usize, left: f32, width: f32) -> f32 {
+    let first = samples[0].seconds;
+    let duration = (samples[samples.len() - 1].seconds - first).max(1.0);
+
+    left + (samples[i].seconds - first) / duration * width
+}
+
+fn svg_color(color: Color) -> String {
+    format!(
+        "rgb({},{},{})",
+        (color.r * 255.0) as u8,
+        (color.g * 255.0) as u8,
+        (color.b * 255.0) as u8
+    )
+}
+
+fn svg_text(x: f32, y: f32, size: f32, color: Color, text: &str) -> Text {
+    Text::new()
+        .set("x", x)
+        .set("y", y)
+        .set("font-family", "Muli, sans-serif")
+        .set("font-size", size)
+        .set("text-anchor", "middle")
+        .set("fill", svg_color(color))
+        .add(svg::node::Text::new(text))
+}
+
+fn svg_trajectory(
+    samples: &[SummarySample],
+    value: fn(&SummarySample) -> f32,
+    color: Color,
+) -> Path {
+    let (left, top, width, height) = CARD_PLOT;
+    let mut data = Data::new().move_to((
+        plot_x(samples, 0, left, width),
+        plot_y(value(&samples[0]), top, height),
+    ));
+    for i in 1..samples.len() {
+        data = data.line_to((
+            plot_x(samples, i, left, width),
+            plot_y(value(&samples[i]), top, height),
+        ));
+    }
+
+    Path::new()
+        .set("fill", "none")
+        .set("stroke", svg_color(color))
+        .set("stroke-width", 3)
+        .set("d", data)
+}
+
+/// The petal path of the mandala's SVG file, None if it can not be read
+fn load_petal_path(file: &str) -> Option<String> {
+    let path = std::path::Path::new(PETAL_DIR).join(file);
+    let petal = match std::fs::read_to_string(&path) {
+        Ok(svg) => petal_path_data(&svg).map(|data| data.to_string()),
+        Err(e) => {
+            warn!("Can not read {:?} for the summary card: {}", path, e);
+            return None;
+        }
+    };
+    if petal.is_none() {
+        warn!(
+            "No petal path in {:?}, the summary card has no mandala",
+            path
+        );
+    }
+
+    petal
+}
+
+/// The 'd' attribute of the first path in an SVG file
+fn petal_path_data(svg: &str) -> Option<&str> {
+    let path = &svg[svg.find("<path")?..];
+    let start = path
+        .match_indices("d=\"")
+        .map(|(i, _)| i)
+        .find(|&i| path[..i].ends_with(char::is_whitespace))?
+        + "d=\"".len();
+    let end = start + path[start..].find('"')?;
+
+    Some(&path[start..end])
+}
+
+/// The mandala as the app draws it: the same petal path, petal count, scale and pose
+fn svg_mandala(petal_path: &str, petals: usize, pose: &PetalPose) -> Group {
+    let (x, y) = CARD_MANDALA_CENTER;
+
+    let mut group = Group::new()
+        .set("fill", svg_color(pose.color))
+        .set("fill-opacity", pose.color.a.max(0.0).min(1.0))
+        .set(
+            "transform",
+            format!(
+                "translate({} {}) scale({} {})",
+                x,
+                y,
+                MANDALA_SCALE.0 * CARD_MANDALA_SCALE,
+                MANDALA_SCALE.1 * CARD_MANDALA_SCALE
+            ),
+        );
+    for petal in 0..petals {
+        let angle = petal as f32 * 360.0 / petals as f32;
+        group = group.add(Path::new().set("d", petal_path).set(
+            "transform",
+            format!("rotate({}) {}", angle, pose.svg_transform()),
+        ));
+    }
+
+    group
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_averages() {
+        let mut summary = SessionSummary::new();
+        summary.start_phase("Calibration");
+        summary.add(1.0, Some(1.0), None);
+        assert!(summary.samples.is_empty());
+        summary.add(2.0, None, Some(-1.0));
+        summary.add(3.0, Some(3.0), None);
+        summary.start_phase("Breathing");
+        summary.start_phase("FreeRide");
+        summary.add(4.0, Some(std::f32::NAN), Some(0.0));
+
+        let averages = summary.phase_averages();
+        assert_eq!(2, averages.len());
+        assert_eq!(Some(2.0), averages[0].valence());
+        assert_eq!(Some(-1.0), averages[0].arousal());
+        assert_eq!("FreeRide", averages[1].label);
+        assert_eq!(Some(3.0), averages[1].valence());
+    }
+
+    #[test]
+    fn test_plot_is_thinned() {
+        let mut summary = SessionSummary::new();
+        for i in 0..1000 {
+            summary.add(i as f32, Some(0.0), Some(0.0));
+        }
+
+        assert!(summary.plot_samples().len() <= MAX_PLOT_POINTS);
+        assert_eq!(10.0, plot_y(-10.0, 0.0, 10.0));
+        assert_eq!(5.0, plot_y(0.0, 0.0, 10.0));
+    }
+
+    #[test]
+    fn test_petal_path_data() {
+        let svg = r#"<svg><path id="p1" style="fill:#a0a0a0" d="m 0,0 c 1,2 3,4 5,6 z" /></svg>"#;
+        assert_eq!(Some("m 0,0 c 1,2 3,4 5,6 z"), petal_path_data(svg));
+        assert_eq!(None, petal_path_data("<svg></svg>"));
+
+        for file in &[
+            MANDALA_VALENCE_PETAL_SVG_NAME,
+            MANDALA_AROUSAL_PETAL_SVG_NAME,
+        ] {
+            let petal = load_petal_path(file).unwrap();
+            assert!(petal.starts_with("m 0"), "{}: {}", file, petal);
+        }
+    }
+
+    #[test]
+    fn test_final_mandala_is_not_clamped() {
+        let pose = PetalPose::between(
+            &POSE_VALENCE_MANDALA_CLOSED,
+            &POSE_VALENCE_MANDALA_OPEN,
+            2.0,
+        );
+
+        assert_eq!(180.0, pose.rotate);
+        assert_eq!((100.0, 0.0), pose.translate);
+    }
+}
@@ -0,0 +1,217 @@
+/// Present a sequence of images: each is shown for a fixed time, separated by a blank screen of
+/// random length so the participant can not anticipate the next onset
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::fmt;
+use std::fs;
+
+/// How long images and the blanks between them are on screen
+#[derive(Clone, Debug)]
+pub struct StimulusSettings {
+    pub image_seconds: f32,
+    pub min_blank_seconds: f32, // Inter-stimulus blank, chosen at random between min and max
+    pub max_blank_seconds: f32,
+}
+
+impl Default for StimulusSettings {
+    fn default() -> Self {
+        Self {
+            image_seconds: 5.0,
+            min_blank_seconds: 1.0,
+            max_blank_seconds: 2.5,
+        }
+    }
+}
+
+/// Read the images listed in a manifest, one per line, relative to the static directory.
+/// Empty lines and lines starting with '#' are skipped.
+pub fn read_manifest(filename: &str) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(filename)
+        .map_err(|e| format!("Can not read image manifest {}: {}", filename, e))?;
+    let images: Vec<String> = text
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| line.to_string())
+        .collect();
+
+    if images.is_empty() {
+        return Err(format!("Image manifest {} lists no images", filename));
+    }
+
+    Ok(images)
+}
+
+/// A stimulus appearing or disappearing
+#[derive(Clone, Debug, PartialEq)]
+pub enum StimulusEvent {
+    Onset { index: usize, image: String },
+    Offset { index: usize, image: String },
+}
+
+impl StimulusEvent {
+    /// Name in the event log
+    pub fn name(&self) -> &'static str {
+        match self {
+            StimulusEvent::Onset { .. } => "stimulus_onset",
+            StimulusEvent::Offset { .. } => "stimulus_offset",
+        }
+    }
+}
+
+impl fmt::Display for StimulusEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StimulusEvent::Onset { index, image } | StimulusEvent::Offset { index, image } => {
+                write!(f, "{} {}", index, image)
+            }
+        }
+    }
+}
+
+/// The images in presentation order, and when each appears and disappears
+pub struct StimulusSequence {
+    pub images: Vec<String>,   // Presentation order, after any shuffle
+    pub seed: u64,             // Recorded so a shuffled order and blank timing can be repeated
+    schedule: Vec<(f32, f32)>, // Onset and offset of each image, seconds since the sequence started
+    start: Option<f32>,
+    next_event: usize, // Onset of image i is event 2i, offset is 2i + 1
+}
+
+impl StimulusSequence {
+    pub fn new(images: Vec<String>, settings: &StimulusSettings, shuffle: bool, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut images = images;
+        if shuffle {
+            images.shuffle(&mut rng);
+        }
+
+        let mut schedule = Vec::with_capacity(images.len());
+        let mut time = 0.0;
+        for _ in 0..images.len() {
+            time = time
+                + if settings.max_blank_seconds > settings.min_blank_seconds {
+                    rng.gen_range(settings.min_blank_seconds, settings.max_blank_seconds)
+                } else {
+                    settings.min_blank_seconds
+                };
+            schedule.push((time, time + settings.image_seconds));
+            time = time + settings.image_seconds;
+        }
+
+        Self {
+            images,
+            seed,
+            schedule,
+            start: None,
+            next_event: 0,
+        }
+    }
+
+    /// Events which are due by 'now', in order. The sequence starts on the first call.
+    pub fn update(&mut self, now: f32) -> Vec<StimulusEvent> {
+        let start = *self.start.get_or_insert(now);
+        let elapsed = now - start;
+        let mut events = Vec::new();
+
+        while !self.is_finished() {
+            let index = self.next_event / 2;
+            let (onset, offset) = self.schedule[index];
+            let image = self.images[index].clone();
+            let event = if self.next_event % 2 == 0 {
+                if elapsed < onset {
+                    break;
+                }
+                StimulusEvent::Onset { index, image }
+            } else {
+                if elapsed < offset {
+                    break;
+                }
+                StimulusEvent::Offset { index, image }
+            };
+            events.push(event);
+            self.next_event = self.next_event + 1;
+        }
+
+        events
+    }
+
+    /// Index of the image on screen, None during a blank
+    pub fn current(&self) -> Option<usize> {
+        if self.next_event % 2 == 1 {
+            Some(self.next_event / 2)
+        } else {
+            None
+        }
+    }
+
+    /// The last image has been shown and removed
+    pub fn is_finished(&self) -> bool {
+        self.next_event >= 2 * self.images.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn images() -> Vec<String> {
+        (0..10).map(|i| format!("n{}.png", i)).collect()
+    }
+
+    #[test]
+    fn test_same_seed_same_order() {
+        let settings = StimulusSettings::default();
+        let a = StimulusSequence::new(images(), &settings, true, 42);
+        let b = StimulusSequence::new(images(), &settings, true, 42);
+        let unshuffled = StimulusSequence::new(images(), &settings, false, 42);
+
+        assert_eq!(a.images, b.images);
+        assert_eq!(a.schedule, b.schedule);
+        assert_ne!(a.images, unshuffled.images);
+        assert_eq!(images(), unshuffled.images);
+    }
+
+    #[test]
+    fn test_blank_between_images() {
+        let settings = StimulusSettings::default();
+        let sequence = StimulusSequence::new(images(), &settings, false, 7);
+        let mut previous_offset = 0.0;
+
+        for (onset, offset) in &sequence.schedule {
+            let blank = onset - previous_offset;
+            assert!(blank >= 1.0 && blank <= 2.5);
+            assert!((offset - onset - 5.0).abs() < 0.001);
+            previous_offset = *offset;
+        }
+    }
+
+    #[test]
+    fn test_onset_and_offset_events() {
+        let settings = StimulusSettings {
+            image_seconds: 5.0,
+            min_blank_seconds: 1.0,
+            max_blank_seconds: 1.0,
+        };
+        let mut sequence = StimulusSequence::new(images()[0..2].to_vec(), &settings, false, 0);
+
+        assert!(sequence.update(100.0).is_empty());
+        assert_eq!(None, sequence.current());
+        assert_eq!(
+            vec![StimulusEvent::Onset {
+                index: 0,
+                image: "n0.png".to_string()
+            }],
+            sequence.update(101.0)
+        );
+        assert_eq!(Some(0), sequence.current());
+
+        let events = sequence.update(107.5);
+        assert_eq!(2, events.len());
+        assert_eq!("stimulus_offset", events[0].name());
+        assert_eq!("stimulus_onset", events[1].name());
+        assert_eq!(Some(1), sequence.current());
+
+        assert_eq!(1, sequence.update(200.0).len());
+        assert!(sequence.is_finished());
+    }
+}
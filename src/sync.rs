@@ -0,0 +1,542 @@
+/// Copy sessions recorded in a local database, usually the SQLite file on a laptop at an event,
+/// to the central PostgreSQL database. Rigs are matched by title and participants by code,
+/// anything missing centrally is created. Each session is copied in one transaction and then
+/// marked in the local database with its central id, so it is only copied once. A session already
+/// copied centrally, but not marked because sync stopped in between, is found by its start time
+/// and rig and only marked.
+use super::models::{
+    BandPowerRow, EegRow, Event, NewEvent, NewResponse, NewRig, NewStimulus, Participant, Response,
+    Rig, SampleBatch, Session, Stimulus, ValenceArousalRow,
+};
+use super::schema::{
+    band_powers, eeg_samples, events, participants, responses, sessions, stimuli, valence_arousal,
+};
+use super::*;
+use std::collections::HashMap;
+
+const SAMPLES_PER_PAGE: i64 = 10_000; // Rows read from the local database at a time
+const SAME_START_MS: i64 = 1; // A central session starting this close on the same rig is the same session, PostgreSQL keeps only microseconds
+
+/// One session copied to the central database
+#[derive(Debug)]
+pub struct SyncedSession {
+    pub local_id: i32,
+    pub central_id: i32,
+    pub rows: usize, // Stimuli, events, responses and samples, 0 if it had already been copied
+}
+
+/// Sessions not copied yet, oldest first. A session which has not ended may still be running,
+/// so it is left for a later sync unless 'include_unended'.
+pub fn sessions_to_sync(local: &DbConnection, include_unended: bool) -> QueryResult<Vec<Session>> {
+    let not_synced = sessions::table
+        .filter(sessions::synced_session_id.is_null())
+        .order(sessions::id);
+
+    if include_unended {
+        with_connection!(local, |c| not_synced.load::<Session>(c))
+    } else {
+        with_connection!(local, |c| not_synced
+            .filter(sessions::ended_at.is_not_null())
+            .load::<Session>(c))
+    }
+}
+
+/// Copy one session with everything recorded in it
+pub fn sync_session(
+    local: &DbConnection,
+    central: &DbConnection,
+    session: &Session,
+) -> QueryResult<SyncedSession> {
+    let (central_id, rows) = central.transaction(|| copy_session(local, central, session))?;
+
+    with_connection!(local, |c| diesel::update(sessions::table.find(session.id))
+        .set(sessions::synced_session_id.eq(central_id))
+        .execute(c))?;
+
+    Ok(SyncedSession {
+        local_id: session.id,
+        central_id,
+        rows,
+    })
+}
+
+fn copy_session(
+    local: &DbConnection,
+    central: &DbConnection,
+    session: &Session,
+) -> QueryResult<(i32, usize)> {
+    let rig = match session.rig_id {
+        Some(rig_id) => match find_rig_by_id(local, rig_id)? {
+            Some(rig) => Some(central_rig(central, &rig)?),
+            None => None,
+        },
+        None => None,
+    };
+    let participant = match session.participant_id {
+        Some(participant_id) => {
+            let participant = with_connection!(local, |c| participants::table
+                .find(participant_id)
+                .first::<Participant>(c))?;
+            Some(find_or_create_participant(central, &participant.code)?)
+        }
+        None => None,
+    };
+    if let Some(copied) = find_copied_session(central, rig.as_ref(), session)? {
+        return Ok((copied.id, 0));
+    }
+
+    let central_id = create_session(
+        central,
+        rig.as_ref(),
+        participant.as_ref(),
+        &session.protocol_version,
+        system_time(session.started_at),
+    )?
+    .id;
+    if let Some(ended_at) = session.ended_at {
+        let calibration = CalibrationStats {
+            valence_mean: session.valence_mean,
+            valence_deviation: session.valence_deviation,
+            arousal_mean: session.arousal_mean,
+            arousal_deviation: session.arousal_deviation,
+            rejected_percent: session.rejected_percent,
+        };
+        end_session(central, central_id, &calibration, system_time(ended_at))?;
+    }
+
+    let stimulus_ids = copy_stimuli(local, central, session.id, central_id)?;
+    let mut rows = stimulus_ids.len();
+    rows = rows + copy_events(local, central, session.id, central_id)?;
+    rows = rows + copy_responses(local, central, session.id, central_id, &stimulus_ids)?;
+    rows = rows + copy_eeg(local, central, session.id, central_id)?;
+    rows = rows + copy_band_powers(local, central, session.id, central_id)?;
+    rows = rows + copy_valence_arousal(local, central, session.id, central_id)?;
+
+    Ok((central_id, rows))
+}
+
+/// A central session with the same start on the same rig, copied by an earlier sync
+fn find_copied_session(
+    central: &DbConnection,
+    rig: Option<&Rig>,
+    session: &Session,
+) -> QueryResult<Option<Session>> {
+    let window = chrono::Duration::milliseconds(SAME_START_MS);
+    let started =
+        sessions::started_at.between(session.started_at - window, session.started_at + window);
+
+    with_connection!(central, |c| {
+        let query = sessions::table.filter(started).into_boxed();
+        let query = match rig {
+            Some(rig) => query.filter(sessions::rig_id.eq(rig.id)),
+            None => query.filter(sessions::rig_id.is_null()),
+        };
+        query.order(sessions::id).first::<Session>(c).optional()
+    })
+}
+
+/// The central rig with the same title, created with the same hardware if there is none
+fn central_rig(central: &DbConnection, rig: &Rig) -> QueryResult<Rig> {
+    match find_rigs_by_title(central, &rig.title)?.into_iter().next() {
+        Some(central_rig) => Ok(central_rig),
+        None => create_rig(
+            central,
+            &NewRig {
+                title: &rig.title,
+                body: &rig.body,
+                headset_serial: rig.headset_serial.as_deref(),
+                screen: rig.screen.as_deref(),
+                input_devices: rig.input_devices.as_deref(),
+                location: rig.location.as_deref(),
+            },
+        ),
+    }
+}
+
+/// Returns the central id of each local stimulus, for the responses to refer to
+fn copy_stimuli(
+    local: &DbConnection,
+    central: &DbConnection,
+    local_id: i32,
+    central_id: i32,
+) -> QueryResult<HashMap<i32, i32>> {
+    let local_stimuli = with_connection!(local, |c| stimuli::table
+        .filter(stimuli::session_id.eq(local_id))
+        .order(stimuli::id)
+        .load::<Stimulus>(c))?;
+    let mut stimulus_ids = HashMap::new();
+
+    for stimulus in local_stimuli {
+        let new_stimulus = NewStimulus {
+            session_id: central_id,
+            sequence: stimulus.sequence,
+            position: stimulus.position,
+            image: stimulus.image,
+            onset: stimulus.onset,
+        };
+        let copied: Stimulus = insert_returning!(central, stimuli::table, &new_stimulus)?;
+        stimulus_ids.insert(stimulus.id, copied.id);
+    }
+
+    Ok(stimulus_ids)
+}
+
+fn copy_events(
+    local: &DbConnection,
+    central: &DbConnection,
+    local_id: i32,
+    central_id: i32,
+) -> QueryResult<usize> {
+    let new_events: Vec<NewEvent> = with_connection!(local, |c| events::table
+        .filter(events::session_id.eq(local_id))
+        .order(events::id)
+        .load::<Event>(c))?
+    .into_iter()
+    .map(|event| NewEvent {
+        session_id: central_id,
+        time: event.time,
+        event: event.event,
+        detail: event.detail,
+    })
+    .collect();
+
+    let mut count = 0;
+    for chunk in new_events.chunks(ROWS_PER_INSERT) {
+        count = count
+            + with_connection!(central, |c| diesel::insert_into(events::table)
+                .values(chunk)
+                .execute(c))?;
+    }
+
+    Ok(count)
+}
+
+fn copy_responses(
+    local: &DbConnection,
+    central: &DbConnection,
+    local_id: i32,
+    central_id: i32,
+    stimulus_ids: &HashMap<i32, i32>,
+) -> QueryResult<usize> {
+    let new_responses: Vec<NewResponse> = with_connection!(local, |c| responses::table
+        .filter(responses::session_id.eq(local_id))
+        .order(responses::id)
+        .load::<Response>(c))?
+    .into_iter()
+    .map(|response| NewResponse {
+        session_id: central_id,
+        stimulus_id: response
+            .stimulus_id
+            .and_then(|stimulus_id| stimulus_ids.get(&stimulus_id).cloned()),
+        familiar: response.familiar,
+        liked: response.liked,
+        rating: response.rating,
+        reaction_time_ms: response.reaction_time_ms,
+        input: response.input,
+    })
+    .collect();
+
+    let mut count = 0;
+    for chunk in new_responses.chunks(ROWS_PER_INSERT) {
+        count = count
+            + with_connection!(central, |c| diesel::insert_into(responses::table)
+                .values(chunk)
+                .execute(c))?;
+    }
+
+    Ok(count)
+}
+
+/// SQLite gives back a NaN channel as NULL
+fn channel(value: Option<f32>) -> f32 {
+    value.unwrap_or(std::f32::NAN)
+}
+
+type ChannelColumns = (Option<f32>, Option<f32>, Option<f32>, Option<f32>);
+
+fn channels(columns: ChannelColumns) -> [f32; 4] {
+    [
+        channel(columns.0),
+        channel(columns.1),
+        channel(columns.2),
+        channel(columns.3),
+    ]
+}
+
+fn copy_eeg(
+    local: &DbConnection,
+    central: &DbConnection,
+    local_id: i32,
+    central_id: i32,
+) -> QueryResult<usize> {
+    use self::eeg_samples::dsl::*;
+
+    let mut last_id = 0;
+    let mut count = 0;
+    loop {
+        let page = with_connection!(local, |c| eeg_samples
+            .select((
+                id,
+                time,
+                (
+                    tp9.nullable(),
+                    af7.nullable(),
+                    af8.nullable(),
+                    tp10.nullable()
+                ),
+                aux_right,
+                aux_left,
+            ))
+            .filter(session_id.eq(local_id))
+            .filter(id.gt(last_id))
+            .order(id)
+            .limit(SAMPLES_PER_PAGE)
+            .load::<(i64, NaiveDateTime, ChannelColumns, Option<f32>, Option<f32>)>(c))?;
+        let row_id = match page.last() {
+            Some(row) => row.0,
+            None => return Ok(count),
+        };
+        let batch = SampleBatch {
+            eeg: page
+                .into_iter()
+                .map(|row| EegRow {
+                    time: system_time(row.1),
+                    channels: channels(row.2),
+                    aux: [row.3, row.4],
+                })
+                .collect(),
+            ..SampleBatch::default()
+        };
+
+        count = count + insert_samples(central, central_id, &batch)?;
+        last_id = row_id;
+    }
+}
+
+fn copy_band_powers(
+    local: &DbConnection,
+    central: &DbConnection,
+    local_id: i32,
+    central_id: i32,
+) -> QueryResult<usize> {
+    use self::band_powers::dsl::*;
+
+    let mut last_id = 0;
+    let mut count = 0;
+    loop {
+        let page = with_connection!(local, |c| band_powers
+            .select((
+                id,
+                time,
+                band,
+                (
+                    tp9.nullable(),
+                    af7.nullable(),
+                    af8.nullable(),
+                    tp10.nullable()
+                ),
+            ))
+            .filter(session_id.eq(local_id))
+            .filter(id.gt(last_id))
+            .order(id)
+            .limit(SAMPLES_PER_PAGE)
+            .load::<(i64, NaiveDateTime, String, ChannelColumns)>(c))?;
+        let row_id = match page.last() {
+            Some(row) => row.0,
+            None => return Ok(count),
+        };
+        let batch = SampleBatch {
+            band_powers: page
+                .into_iter()
+                .map(|row| BandPowerRow {
+                    time: system_time(row.1),
+                    band: row.2,
+                    channels: channels(row.3),
+                })
+                .collect(),
+            ..SampleBatch::default()
+        };
+
+        count = count + insert_samples(central, central_id, &batch)?;
+        last_id = row_id;
+    }
+}
+
+fn copy_valence_arousal(
+    local: &DbConnection,
+    central: &DbConnection,
+    local_id: i32,
+    central_id: i32,
+) -> QueryResult<usize> {
+    use self::valence_arousal::dsl::*;
+
+    let mut last_id = 0;
+    let mut count = 0;
+    loop {
+        let page = with_connection!(local, |c| valence_arousal
+            .filter(session_id.eq(local_id))
+            .filter(id.gt(last_id))
+            .order(id)
+            .limit(SAMPLES_PER_PAGE)
+            .load::<models::ValenceArousal>(c))?;
+        let row_id = match page.last() {
+            Some(row) => row.id,
+            None => return Ok(count),
+        };
+        let batch = SampleBatch {
+            valence_arousal: page
+                .into_iter()
+                .map(|row| ValenceArousalRow {
+                    time: system_time(row.time),
+                    valence: row.valence,
+                    arousal: row.arousal,
+                    normalized_valence: row.normalized_valence,
+                    normalized_arousal: row.normalized_arousal,
+                })
+                .collect(),
+            ..SampleBatch::default()
+        };
+
+        count = count + insert_samples(central, central_id, &batch)?;
+        last_id = row_id;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// A local database with one ended session on 'Lab rig 1'
+    fn local() -> DbConnection {
+        let local = establish("sqlite::memory:").unwrap();
+        let rig = create_rig(
+            &local,
+            &NewRig {
+                title: "Lab rig 1",
+                body: "",
+                headset_serial: Some("MUSE-2016-4B2C"),
+                ..NewRig::default()
+            },
+        )
+        .unwrap();
+        let participant = find_or_create_participant(&local, "P017").unwrap();
+        let started_at = UNIX_EPOCH + Duration::from_millis(1_583_020_800_123);
+        let session =
+            create_session(&local, Some(&rig), Some(&participant), "1.0", started_at).unwrap();
+
+        let stimulus: Stimulus = insert_returning!(
+            &local,
+            stimuli::table,
+            &NewStimulus {
+                session_id: session.id,
+                sequence: "negative".to_string(),
+                position: 0,
+                image: "negative-images/1.jpg".to_string(),
+                onset: naive_utc(started_at),
+            }
+        )
+        .unwrap();
+        with_connection!(&local, |c| diesel::insert_into(responses::table)
+            .values(&NewResponse {
+                session_id: session.id,
+                stimulus_id: Some(stimulus.id),
+                familiar: None,
+                liked: Some(true),
+                rating: Some(4),
+                reaction_time_ms: Some(800),
+                input: Some("keyboard".to_string()),
+            })
+            .execute(c))
+        .unwrap();
+
+        let mut batch = SampleBatch::default();
+        for i in 0..3 {
+            batch.eeg.push(EegRow {
+                time: started_at + Duration::from_millis(4 * i),
+                channels: [1.0, std::f32::NAN, 3.0, 4.0],
+                aux: [None, Some(0.5)],
+            });
+        }
+        batch.valence_arousal.push(ValenceArousalRow {
+            time: started_at,
+            valence: Some(1.5),
+            arousal: None,
+            normalized_valence: None,
+            normalized_arousal: None,
+        });
+        insert_samples(&local, session.id, &batch).unwrap();
+        end_session(
+            &local,
+            session.id,
+            &CalibrationStats::default(),
+            started_at + Duration::from_secs(600),
+        )
+        .unwrap();
+
+        local
+    }
+
+    #[test]
+    fn test_sync_session() {
+        let local = local();
+        let central = establish("sqlite::memory:").unwrap();
+        // An unrelated rig, so central ids differ from local ones
+        create_rig(
+            &central,
+            &NewRig {
+                title: "Lab rig 2",
+                body: "",
+                ..NewRig::default()
+            },
+        )
+        .unwrap();
+
+        let to_sync = sessions_to_sync(&local, false).unwrap();
+        assert_eq!(1, to_sync.len());
+        let synced = sync_session(&local, &central, &to_sync[0]).unwrap();
+        assert_eq!(1 + 1 + 3 + 1, synced.rows);
+        assert!(sessions_to_sync(&local, true).unwrap().is_empty());
+
+        let session = with_connection!(&central, |c| sessions::table
+            .find(synced.central_id)
+            .first::<Session>(c))
+        .unwrap();
+        assert_eq!(to_sync[0].started_at, session.started_at);
+        assert_eq!(to_sync[0].ended_at, session.ended_at);
+        let rig = find_rig_by_id(&central, session.rig_id.unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!("Lab rig 1", rig.title);
+        assert_eq!(Some("MUSE-2016-4B2C".to_string()), rig.headset_serial);
+
+        let response =
+            with_connection!(&central, |c| responses::table.first::<Response>(c)).unwrap();
+        let stimulus = with_connection!(&central, |c| stimuli::table.first::<Stimulus>(c)).unwrap();
+        assert_eq!(Some(stimulus.id), response.stimulus_id);
+        assert_eq!(synced.central_id, stimulus.session_id);
+    }
+
+    #[test]
+    fn test_sync_again_after_mark_lost() {
+        let local = local();
+        let central = establish("sqlite::memory:").unwrap();
+        let to_sync = sessions_to_sync(&local, false).unwrap();
+        let synced = sync_session(&local, &central, &to_sync[0]).unwrap();
+
+        // As if sync stopped after the central copy, before the local session was marked
+        with_connection!(&local, |c| diesel::update(sessions::table)
+            .set(sessions::synced_session_id.eq(None::<i32>))
+            .execute(c))
+        .unwrap();
+        let to_sync = sessions_to_sync(&local, false).unwrap();
+        let again = sync_session(&local, &central, &to_sync[0]).unwrap();
+
+        assert_eq!(synced.central_id, again.central_id);
+        assert_eq!(0, again.rows);
+        let sessions =
+            with_connection!(&central, |c| sessions::table.count().get_result::<i64>(c)).unwrap();
+        assert_eq!(1, sessions);
+        assert!(sessions_to_sync(&local, true).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+/// The order and length of each part of a session, loaded from a JSON protocol file
+use crate::breathing::BreathSettings;
+use crate::free_ride::FreeRideSettings;
+use serde::{Deserialize, Serialize};
+use std::{env, fs};
+
+const ENV_PROTOCOL: &str = "MEME_PROTOCOL"; // Path to a protocol file, instead of the default
+pub const DEFAULT_PROTOCOL_FILE: &str = "static/protocol.json";
+
+/// What is on screen during a phase
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PhaseKind {
+    Title,
+    Instructions,
+    FitCheck,
+    Calibration,
+    ImageSequence,
+    Breathing,
+    FreeRide,
+    Exit,
+}
+
+/// Something other than time running out which ends a phase
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndCondition {
+    Button,       // Return, Space or either response button
+    FitCheck,     // Every electrode has had good contact long enough
+    Calibrated,   // Valence and arousal history is full
+    SequenceDone, // The last image of an image sequence has been shown
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Phase {
+    pub kind: PhaseKind,
+    #[serde(default)]
+    pub name: Option<String>, // Label in logs, for example to tell two image sequences apart
+    #[serde(default)]
+    pub seconds: Option<f32>, // The phase ends after this long, if nothing else ends it first
+    #[serde(default)]
+    pub until: Vec<EndCondition>,
+    #[serde(default)]
+    pub manifest: Option<String>, // Image sequence only, file listing the images to show
+    #[serde(default)]
+    pub shuffle: bool, // Image sequence only, show the images in random order
+    #[serde(default)]
+    pub seed: Option<u64>, // Image sequence only, repeat a previous random order and timing
+    #[serde(default)]
+    pub breathing: Option<BreathSettings>, // Breathing only, the breath cycle if not the default
+    #[serde(default)]
+    pub free_ride: Option<FreeRideSettings>, // Free ride only, the target and difficulty if not the default
+}
+
+impl Phase {
+    fn new(kind: PhaseKind, seconds: Option<f32>, until: Vec<EndCondition>) -> Self {
+        Self {
+            kind,
+            name: None,
+            seconds,
+            until,
+            manifest: None,
+            shuffle: false,
+            seed: None,
+            breathing: None,
+            free_ride: None,
+        }
+    }
+
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{:?} {}", self.kind, name),
+            None => format!("{:?}", self.kind),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Protocol {
+    #[serde(default = "unversioned")]
+    pub version: String, // Stored with each database session so results can be grouped by protocol
+    pub phases: Vec<Phase>,
+}
+
+fn unversioned() -> String {
+    "unversioned".to_string()
+}
+
+impl Protocol {
+    pub fn parse(json: &str) -> Result<Protocol, String> {
+        let protocol: Protocol =
+            serde_json::from_str(json).map_err(|e| format!("Protocol is not valid: {}", e))?;
+        protocol.check()?;
+
+        Ok(protocol)
+    }
+
+    /// Read a protocol file. If it does not exist the default protocol is used.
+    pub fn load(filename: &str) -> Result<Protocol, String> {
+        match fs::read_to_string(filename) {
+            Ok(json) => Protocol::parse(&json),
+            Err(e) => {
+                warn!(
+                    "Can not read protocol {} ({}), using the default",
+                    filename, e
+                );
+                Ok(Protocol::default())
+            }
+        }
+    }
+
+    /// Load the protocol named by MEME_PROTOCOL, or the default protocol file
+    pub fn from_env() -> Protocol {
+        Protocol::load(&Protocol::file_from_env()).expect("Can not load the session protocol")
+    }
+
+    /// MEME_PROTOCOL, or the default protocol file
+    pub fn file_from_env() -> String {
+        env::var(ENV_PROTOCOL).unwrap_or(DEFAULT_PROTOCOL_FILE.to_string())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        if self.phases.is_empty() {
+            return Err("Protocol has no phases".to_string());
+        }
+        for phase in &self.phases {
+            if phase.seconds.is_none() && phase.until.is_empty() {
+                return Err(format!(
+                    "Phase {} needs 'seconds' or 'until' or it will never end",
+                    phase.label()
+                ));
+            }
+            if phase.kind == PhaseKind::ImageSequence && phase.manifest.is_none() {
+                return Err(format!("Phase {} needs a 'manifest'", phase.label()));
+            }
+            if let Some(breathing) = &phase.breathing {
+                breathing.check()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Protocol {
+    /// Title, instructions, fit check, one minute of calibration then the mandala until Escape
+    fn default() -> Self {
+        Self {
+            version: "built-in".to_string(),
+            phases: vec![
+                Phase::new(PhaseKind::Title, Some(4.0), vec![]),
+                Phase::new(
+                    PhaseKind::Instructions,
+                    Some(30.0),
+                    vec![EndCondition::Button],
+                ),
+                Phase::new(
+                    PhaseKind::FitCheck,
+                    None,
+                    vec![EndCondition::FitCheck, EndCondition::Button],
+                ),
+                Phase::new(PhaseKind::Calibration, Some(60.0), vec![]),
+                Phase::new(PhaseKind::FreeRide, Some(12000.0), vec![]),
+                Phase::new(PhaseKind::Exit, Some(10.0), vec![]),
+            ],
+        }
+    }
+}
+
+/// What has happened since the last update which may end the current phase
+#[derive(Clone, Debug, Default)]
+pub struct PhaseSignals {
+    pub button: bool,
+    pub fit_check: bool,
+    pub calibrated: bool,
+    pub sequence_done: bool,
+}
+
+/// Steps through the phases of a protocol
+pub struct Timeline {
+    phases: Vec<Phase>,
+    current: usize,
+    phase_start: f32, // Seconds since the app started
+    finished: bool,
+}
+
+impl Timeline {
+    pub fn new(protocol: Protocol, now: f32) -> Self {
+        Self {
+            phases: protocol.phases,
+            current: 0,
+            phase_start: now,
+            finished: false,
+        }
+    }
+
+    pub fn phase(&self) -> &Phase {
+        &self.phases[self.current]
+    }
+
+    /// Seconds since the current phase started
+    pub fn phase_elapsed(&self, now: f32) -> f32 {
+        now - self.phase_start
+    }
+
+    /// The last phase has ended
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Move to the next phase if this one has ended. Returns true if the phase changed.
+    pub fn update(&mut self, now: f32, signals: &PhaseSignals) -> bool {
+        if self.finished {
+            return false;
+        }
+
+        let phase = self.phase();
+        let timed_out = match phase.seconds {
+            Some(seconds) => self.phase_elapsed(now) >= seconds,
+            None => false,
+        };
+        let condition_met = phase.until.iter().any(|condition| match condition {
+            EndCondition::Button => signals.button,
+            EndCondition::FitCheck => signals.fit_check,
+            EndCondition::Calibrated => signals.calibrated,
+            EndCondition::SequenceDone => signals.sequence_done,
+        });
+
+        if !timed_out && !condition_met {
+            return false;
+        }
+
+        if self.current + 1 < self.phases.len() {
+            self.current = self.current + 1;
+            self.phase_start = now;
+            info!("Phase {}: {}", self.current, self.phase().label());
+
+            true
+        } else {
+            self.finished = true;
+            info!("Protocol finished");
+
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JSON: &str = r#"{
+        "phases": [
+            { "kind": "title", "seconds": 2 },
+            { "kind": "fit_check", "until": ["fit_check", "button"] },
+            { "kind": "image_sequence", "name": "negative", "manifest": "negative.txt", "seconds": 120 }
+        ]
+    }"#;
+
+    #[test]
+    fn test_parse() {
+        let protocol = Protocol::parse(JSON).unwrap();
+
+        assert_eq!(3, protocol.phases.len());
+        assert_eq!(PhaseKind::FitCheck, protocol.phases[1].kind);
+        assert_eq!(
+            vec![EndCondition::FitCheck, EndCondition::Button],
+            protocol.phases[1].until
+        );
+        assert_eq!("ImageSequence negative", protocol.phases[2].label());
+        assert_eq!(
+            Some("negative.txt".to_string()),
+            protocol.phases[2].manifest
+        );
+        assert!(!protocol.phases[2].shuffle);
+        assert_eq!("unversioned", protocol.version);
+    }
+
+    #[test]
+    fn test_phase_that_never_ends() {
+        let result = Protocol::parse(r#"{ "phases": [ { "kind": "free_ride" } ] }"#);
+
+        assert!(result.is_err());
+        let result =
+            Protocol::parse(r#"{ "phases": [ { "kind": "image_sequence", "seconds": 1 } ] }"#);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_breathing_settings() {
+        let protocol = Protocol::parse(
+            r#"{ "phases": [ { "kind": "breathing", "seconds": 60, "breathing": { "breathe_out": 6 } } ] }"#,
+        )
+        .unwrap();
+        let breathing = protocol.phases[0].breathing.as_ref().unwrap();
+
+        assert_eq!(6.0, breathing.breathe_out);
+        assert_eq!(BreathSettings::default().breathe_in, breathing.breathe_in);
+    }
+
+    #[test]
+    fn test_default_protocol_is_valid() {
+        assert_eq!(Ok(()), Protocol::default().check());
+    }
+
+    #[test]
+    fn test_shipped_protocol_is_valid() {
+        let json = fs::read_to_string(DEFAULT_PROTOCOL_FILE).unwrap();
+
+        Protocol::parse(&json).unwrap();
+    }
+
+    #[test]
+    fn test_advance_by_time_and_condition() {
+        let mut timeline = Timeline::new(Protocol::parse(JSON).unwrap(), 10.0);
+        let no_signals = PhaseSignals::default();
+
+        assert!(!timeline.update(11.0, &no_signals));
+        assert!(timeline.update(12.0, &no_signals));
+        assert_eq!(PhaseKind::FitCheck, timeline.phase().kind);
+
+        assert!(!timeline.update(1000.0, &no_signals));
+        let fit = PhaseSignals {
+            fit_check: true,
+            ..PhaseSignals::default()
+        };
+        assert!(timeline.update(1001.0, &fit));
+        assert_eq!(0.0, timeline.phase_elapsed(1001.0));
+
+        assert!(!timeline.update(1121.0, &no_signals));
+        assert!(timeline.is_finished());
+    }
+}